@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// A range of client addresses, written like `192.0.2.0/24` or `2001:db8::/32`.
+/// A bare address is a range of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, client: IpAddr) -> bool {
+        //Clients on an IPv6 socket show up with IPv4-mapped addresses
+        match (self.addr, client.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(client)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(client) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(client)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(client) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid address in network {:?}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len.map(str::parse::<u8>) {
+            None => max,
+            Some(Ok(len)) if len <= max => len,
+            Some(_) => return Err(format!("Invalid prefix length in network {:?}", s).into()),
+        };
+
+        Ok(Network { addr, len })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Every client there is
+pub fn anyone() -> Vec<Network> {
+    vec![
+        Network {
+            addr: Ipv4Addr::UNSPECIFIED.into(),
+            len: 0,
+        },
+        Network {
+            addr: Ipv6Addr::UNSPECIFIED.into(),
+            len: 0,
+        },
+    ]
+}
+
+/// Clients on this machine only
+pub fn localhost() -> Vec<Network> {
+    vec![
+        Network {
+            addr: Ipv4Addr::new(127, 0, 0, 0).into(),
+            len: 8,
+        },
+        Network {
+            addr: Ipv6Addr::LOCALHOST.into(),
+            len: 128,
+        },
+    ]
+}
+
+/// Whether `client` is in any of the networks
+pub fn admits(networks: &[Network], client: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network: Network = "192.0.2.0/24".parse().unwrap();
+        assert!(network.contains(ip("192.0.2.200")));
+        assert!(!network.contains(ip("192.0.3.1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+
+        let network: Network = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains(ip("2001:db8:ffff::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+
+        let single: Network = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn mapped_addresses_count_as_ipv4() {
+        assert!(admits(&localhost(), ip("::ffff:127.0.0.1")));
+        assert!(admits(&localhost(), ip("::1")));
+        assert!(!admits(&localhost(), ip("::ffff:192.0.2.1")));
+    }
+
+    #[test]
+    fn anyone_is_everyone() {
+        assert!(admits(&anyone(), ip("192.0.2.1")));
+        assert!(admits(&anyone(), ip("2001:db8::1")));
+        assert!(!admits(&[], ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        for s in ["", "192.0.2.0/33", "2001:db8::/129", "192.0.2/24", "192.0.2.0/", "localhost"] {
+            assert!(s.parse::<Network>().is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::authority::zone::{is_newer, Zone};
+use crate::authority::zonefile::{self, write_file};
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+
+//How many changes a journal remembers. Secondaries further behind than that
+//get the whole zone.
+const MAX_DIFFS: usize = 100;
+
+/// The changes that took a zone from one version to the next
+#[derive(Clone, Debug)]
+pub struct Diff {
+    //The SOA records of the two versions
+    pub from: DnsRecord,
+    pub to: DnsRecord,
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+impl Diff {
+    /// What changed between two versions of a zone, None if either has no SOA
+    pub fn between(old: &Zone, new: &Zone) -> Option<Diff> {
+        let contents = |zone: &Zone| -> BTreeSet<DnsRecord> {
+            zone.records()
+                .into_iter()
+                .filter(|rec| rec.qtype() != QueryType::Soa)
+                .collect()
+        };
+        let (old_records, new_records) = (contents(old), contents(new));
+
+        Some(Diff {
+            from: old.soa()?.clone(),
+            to: new.soa()?.clone(),
+            removed: old_records.difference(&new_records).cloned().collect(),
+            added: new_records.difference(&old_records).cloned().collect(),
+        })
+    }
+
+    /// The records the way an IXFR sends them (RFC 1995 section 4): the old SOA
+    /// and what was removed, then the new SOA and what was added
+    pub fn records(&self) -> Vec<DnsRecord> {
+        let mut records = vec![self.from.clone()];
+        records.extend(self.removed.iter().cloned());
+        records.push(self.to.clone());
+        records.extend(self.added.iter().cloned());
+        records
+    }
+
+    pub fn old_serial(&self) -> Option<u32> {
+        serial(&self.from)
+    }
+
+    pub fn new_serial(&self) -> Option<u32> {
+        serial(&self.to)
+    }
+}
+
+fn serial(soa: &DnsRecord) -> Option<u32> {
+    match soa {
+        DnsRecord::Soa { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// The recent history of a zone, so secondaries can catch up with an IXFR
+/// instead of transferring the whole zone. Given a directory, the journal is
+/// kept in `<zone>.journal` there, next to a copy of the zone's latest version
+/// in `<zone>.zone` to work out the changes to the zone file across restarts.
+/// Both are in master file format.
+pub struct Journal {
+    origin: DomainName,
+    dir: Option<PathBuf>,
+    diffs: VecDeque<Diff>,
+}
+
+impl Journal {
+    /// A journal kept in memory only
+    pub fn new(origin: DomainName) -> Journal {
+        Journal {
+            origin,
+            dir: None,
+            diffs: VecDeque::new(),
+        }
+    }
+
+    /// Reads the journal of the zone at `origin` from `dir`, along with the
+    /// copy of the zone it was last written with. Both are missing at first.
+    pub fn open(origin: DomainName, dir: &Path) -> Result<(Journal, Option<Zone>), Box<dyn Error>> {
+        let mut journal = Journal {
+            origin,
+            dir: Some(dir.to_path_buf()),
+            diffs: VecDeque::new(),
+        };
+
+        let path = journal.path("journal");
+        if path.exists() {
+            let text = fs::read_to_string(&path).map_err(|e| format!("Can't read journal {}: {}", path.display(), e))?;
+            journal.diffs = parse_diffs(&journal.origin, &text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+
+        let path = journal.path("zone");
+        let last = if path.exists() {
+            Some(zonefile::load(&journal.origin, &path)?)
+        } else {
+            None
+        };
+
+        Ok((journal, last))
+    }
+
+    fn path(&self, extension: &str) -> PathBuf {
+        let name = if self.origin.is_root() { "root" } else { self.origin.as_str() };
+        self.dir
+            .as_deref()
+            .unwrap_or(Path::new("."))
+            .join(format!("{}.{}", name, extension))
+    }
+
+    fn last_serial(&self) -> Option<u32> {
+        self.diffs.back().and_then(Diff::new_serial)
+    }
+
+    /// Takes note of the zone going from `old` to `new`, with `old` being None
+    /// when we had no copy of it. The history only holds while every version
+    /// has a newer serial than the one before, a zone changed any other way
+    /// starts over with an empty journal.
+    pub fn update(&mut self, old: Option<&Zone>, new: &Zone) -> Result<(), Box<dyn Error>> {
+        let diff = old.and_then(|old| Diff::between(old, new));
+        let newer = match diff {
+            Some(ref diff) => matches!((diff.new_serial(), diff.old_serial()), (Some(to), Some(from)) if is_newer(to, from)),
+            None => false,
+        };
+
+        match diff {
+            Some(diff) if newer => {
+                self.diffs.push_back(diff);
+                while self.diffs.len() > MAX_DIFFS {
+                    self.diffs.pop_front();
+                }
+            }
+            Some(diff) if diff.removed.is_empty() && diff.added.is_empty() && diff.old_serial() == diff.new_serial() => {}
+            None if self.last_serial().is_some() && self.last_serial() == new.serial() => {}
+            _ => {
+                if !self.diffs.is_empty() {
+                    println!("Zone {} changed without a newer serial, starting its journal over", self.origin);
+                }
+                self.diffs.clear();
+            }
+        }
+
+        self.save(new)
+    }
+
+    //Records of types we don't know come without their data, so there's no way
+    //to write them down. A journal leaving them out would send secondaries an
+    //incomplete zone after a restart, so a zone with any of them gets no journal
+    //at all and its secondaries transfer the whole zone.
+    fn save(&mut self, zone: &Zone) -> Result<(), Box<dyn Error>> {
+        if self.dir.is_none() {
+            return Ok(());
+        }
+
+        let mut journal = format!("; Changes to zone {}, oldest first\n", self.origin);
+        let mut copy = format!("; Zone {} as last served\n", self.origin);
+        let written = self
+            .diffs
+            .iter()
+            .try_for_each(|diff| append_records(&mut journal, diff.records()))
+            .and_then(|_| append_records(&mut copy, zone.records()));
+
+        if let Err(e) = written {
+            println!("Not keeping a journal of zone {}: {}", self.origin, e);
+            self.diffs.clear();
+            for extension in ["journal", "zone"] {
+                match fs::remove_file(self.path(extension)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(format!("Can't remove {}: {}", self.path(extension).display(), e).into())
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(());
+        }
+
+        write_file(&self.path("journal"), &journal)?;
+        write_file(&self.path("zone"), &copy)
+    }
+
+    /// All changes since the version with serial `since`, condensed into one the way
+    /// RFC 1995 (section 5) allows: records added and then removed again, or
+    /// the other way round, don't appear at all. None if the journal doesn't
+    /// go back that far.
+    pub fn changes_since(&self, since: u32) -> Option<Diff> {
+        let start = self.diffs.iter().position(|diff| diff.old_serial() == Some(since))?;
+
+        let mut removed = BTreeSet::new();
+        let mut added = BTreeSet::new();
+        let mut to = self.diffs[start].from.clone();
+        for diff in self.diffs.iter().skip(start) {
+            if diff.old_serial() != serial(&to) {
+                return None;
+            }
+
+            for rec in &diff.removed {
+                if !added.remove(rec) {
+                    removed.insert(rec.clone());
+                }
+            }
+            for rec in &diff.added {
+                if !removed.remove(rec) {
+                    added.insert(rec.clone());
+                }
+            }
+            to = diff.to.clone();
+        }
+
+        Some(Diff {
+            from: self.diffs[start].from.clone(),
+            to,
+            removed: removed.into_iter().collect(),
+            added: added.into_iter().collect(),
+        })
+    }
+}
+
+//Splits the records of a journal into its diffs. Every SOA starts a new group
+//of records, and the groups pair up into the two halves of a diff.
+fn parse_diffs(origin: &DomainName, text: &str) -> Result<VecDeque<Diff>, Box<dyn Error>> {
+    let mut groups: Vec<(DnsRecord, Vec<DnsRecord>)> = Vec::new();
+    for (line_no, rec) in zonefile::parse_records(origin, text)? {
+        if rec.qtype() == QueryType::Soa {
+            groups.push((rec, Vec::new()));
+        } else {
+            groups
+                .last_mut()
+                .ok_or_else(|| format!("line {}: Changes have to start with an SOA", line_no))?
+                .1
+                .push(rec);
+        }
+    }
+
+    if !groups.len().is_multiple_of(2) {
+        return Err("The last change is incomplete".into());
+    }
+
+    let mut diffs = VecDeque::new();
+    let mut groups = groups.into_iter();
+    while let (Some((from, removed)), Some((to, added))) = (groups.next(), groups.next()) {
+        diffs.push_back(Diff { from, to, removed, added });
+    }
+    Ok(diffs)
+}
+
+//Adds the records to a master file, failing on the first one there's no way
+//to write down
+fn append_records(text: &mut String, records: Vec<DnsRecord>) -> Result<(), Box<dyn Error>> {
+    for rec in records {
+        text.push_str(&zonefile::format(&rec)?);
+        text.push('\n');
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::process;
+
+    fn origin() -> DomainName {
+        DomainName::new("example.org")
+    }
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::Soa {
+            domain: origin(),
+            mname: DomainName::new("ns.example.org"),
+            rname: DomainName::new("hostmaster.example.org"),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a(name: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: DomainName::new(name),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 3600,
+        }
+    }
+
+    fn zone(serial: u32, records: &[DnsRecord]) -> Zone {
+        let mut zone = Zone::new(origin());
+        zone.add(soa(serial)).unwrap();
+        zone.add(DnsRecord::NS {
+            domain: origin(),
+            host: DomainName::new("ns.example.com"),
+            ttl: 3600,
+        })
+        .unwrap();
+        for rec in records {
+            zone.add(rec.clone()).unwrap();
+        }
+        zone
+    }
+
+    //A directory of its own for every test, empty to start with
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("journal-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn changes_are_condensed_since_any_serial() {
+        let v1 = zone(1, &[a("www.example.org", 1)]);
+        let v2 = zone(2, &[a("www.example.org", 2)]);
+        let v3 = zone(3, &[a("www.example.org", 1), a("mail.example.org", 3)]);
+
+        let mut journal = Journal::new(origin());
+        journal.update(None, &v1).unwrap();
+        journal.update(Some(&v1), &v2).unwrap();
+        journal.update(Some(&v2), &v3).unwrap();
+
+        let diff = journal.changes_since(2).unwrap();
+        assert_eq!(diff.removed, [a("www.example.org", 2)]);
+        assert_eq!(diff.added.len(), 2);
+
+        //www went back to what it was, which leaves only mail
+        let diff = journal.changes_since(1).unwrap();
+        assert_eq!((diff.old_serial(), diff.new_serial()), (Some(1), Some(3)));
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.added, [a("mail.example.org", 3)]);
+
+        assert!(journal.changes_since(3).is_none());
+        assert!(journal.changes_since(0).is_none());
+    }
+
+    #[test]
+    fn changes_without_a_newer_serial_start_over() {
+        let v1 = zone(1, &[a("www.example.org", 1)]);
+        let v2 = zone(2, &[a("www.example.org", 2)]);
+        let also_v2 = zone(2, &[a("www.example.org", 3)]);
+
+        let mut journal = Journal::new(origin());
+        journal.update(Some(&v1), &v2).unwrap();
+        assert!(journal.changes_since(1).is_some());
+
+        journal.update(Some(&v2), &also_v2).unwrap();
+        assert!(journal.changes_since(1).is_none());
+    }
+
+    #[test]
+    fn journals_last_across_restarts() {
+        let dir = dir("restart");
+        let nsec = DnsRecord::Nsec {
+            domain: origin(),
+            next: DomainName::new("www.example.org"),
+            types: vec![QueryType::NS.to_num(), QueryType::Soa.to_num(), 46, QueryType::Nsec.to_num()],
+            ttl: 300,
+        };
+        let v1 = zone(1, &[a("www.example.org", 1)]);
+        let v2 = zone(2, &[a("www.example.org", 2), nsec.clone()]);
+
+        let (mut journal, last) = Journal::open(origin(), &dir).unwrap();
+        assert!(last.is_none());
+        journal.update(None, &v1).unwrap();
+        journal.update(Some(&v1), &v2).unwrap();
+
+        let (journal, last) = Journal::open(origin(), &dir).unwrap();
+        let last = last.unwrap();
+        assert_eq!(last.serial(), Some(2));
+        assert_eq!(last.rrset(&origin(), QueryType::Nsec), std::slice::from_ref(&nsec));
+        let diff = journal.changes_since(1).unwrap();
+        assert_eq!(diff.removed, [a("www.example.org", 1)]);
+        assert!(diff.added.contains(&nsec));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_without_data_drop_the_journal() {
+        let dir = dir("unknown");
+        let unknown = DnsRecord::Unknown {
+            domain: DomainName::new("www.example.org"),
+            qtype: 99,
+            data_len: 4,
+            ttl: 3600,
+        };
+        let v1 = zone(1, &[a("www.example.org", 1)]);
+        let v2 = zone(2, &[a("www.example.org", 2)]);
+        let v3 = zone(3, &[a("www.example.org", 2), unknown]);
+
+        let (mut journal, _) = Journal::open(origin(), &dir).unwrap();
+        journal.update(Some(&v1), &v2).unwrap();
+        assert!(dir.join("example.org.journal").exists());
+
+        //Secondaries get the whole zone from now on, before and after a restart
+        journal.update(Some(&v2), &v3).unwrap();
+        assert!(journal.changes_since(1).is_none());
+        assert!(journal.changes_since(2).is_none());
+        assert!(!dir.join("example.org.journal").exists());
+        assert!(!dir.join("example.org.zone").exists());
+
+        let (journal, last) = Journal::open(origin(), &dir).unwrap();
+        assert!(last.is_none());
+        assert!(journal.changes_since(1).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+pub mod journal;
+pub mod notify;
+pub mod reload;
+pub mod secondary;
+pub mod transfer;
+pub mod update;
+pub mod zone;
+pub mod zonefile;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::tsig::{Key, Keyring};
+use journal::{Diff, Journal};
+use secondary::Secondary;
+use zone::Zone;
+
+/// An entry of a zone's allow list: a client address, or a TSIG key that
+/// anyone signing with it is let in by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Allow {
+    Address(IpAddr),
+    Key(DomainName),
+}
+
+impl Allow {
+    fn admits(&self, client: IpAddr, key: Option<&DomainName>) -> bool {
+        match self {
+            Allow::Address(address) => *address == client,
+            Allow::Key(name) => key == Some(name),
+        }
+    }
+}
+
+impl FromStr for Allow {
+    type Err = Box<dyn Error>;
+
+    /// Parses an address, or `key:<name>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("key:") {
+            Some(name) => Ok(Allow::Key(DomainName::new(name))),
+            None => s
+                .parse()
+                .map(Allow::Address)
+                .map_err(|_| format!("Invalid address {:?}", s).into()),
+        }
+    }
+}
+
+/// The zones we serve authoritatively. Questions for names inside one of them
+/// are answered from it, and never passed on to the resolver.
+#[derive(Default)]
+pub struct Authority {
+    //Secondary zones get swapped for fresh copies while we're running, so the
+    //zones are shared behind a lock and handed out one Arc at a time
+    zones: RwLock<HashMap<DomainName, Arc<Zone>>>,
+    //Clients allowed to transfer each zone, nobody for zones not in here
+    transfer_acls: HashMap<DomainName, Vec<Allow>>,
+    //Clients allowed to send dynamic updates for each zone, likewise
+    update_acls: HashMap<DomainName, Vec<Allow>>,
+    //The TSIG keys that sign transfers, NOTIFY and updates
+    keyring: Keyring,
+    //Held while an update works out the next version of a zone, so that
+    //updates apply one after the other
+    update_lock: Mutex<()>,
+    secondaries: HashMap<DomainName, Arc<Secondary>>,
+    //The recent changes of every zone, for answering IXFR
+    journals: Mutex<HashMap<DomainName, Journal>>,
+    //Secondaries to tell when a zone changes
+    notify_targets: HashMap<DomainName, Vec<SocketAddr>>,
+    //The files our primary zones come from, to reload them when they change
+    zone_files: HashMap<DomainName, PathBuf>,
+}
+
+impl Authority {
+    pub fn new() -> Authority {
+        Authority::default()
+    }
+
+    pub fn add_zone(&mut self, zone: Zone) {
+        self.zones.get_mut().unwrap().insert(zone.origin.clone(), Arc::new(zone));
+    }
+
+    /// Loads the zone at `origin` from a master file, which gets reloaded
+    /// whenever it changes
+    pub fn load_zone(&mut self, origin: DomainName, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        self.add_zone(zonefile::load(&origin, &path)?);
+        self.zone_files.insert(origin, path);
+        Ok(())
+    }
+
+    pub fn zone_files(&self) -> impl Iterator<Item = (&DomainName, &PathBuf)> {
+        self.zone_files.iter()
+    }
+
+    /// The file the zone at `origin` was loaded from, if it's a primary zone
+    /// with a file
+    pub fn zone_file(&self, origin: &DomainName) -> Option<&Path> {
+        self.zone_files.get(origin).map(PathBuf::as_path)
+    }
+
+    /// Makes us a secondary for `origin`, with a copy of the zone transferred
+    /// from `primary`. Until the first transfer succeeds the zone has no data.
+    pub fn add_secondary(&mut self, origin: DomainName, primary: SocketAddr) {
+        self.secondaries
+            .insert(origin.clone(), Arc::new(Secondary::new(origin, primary)));
+    }
+
+    pub fn secondaries(&self) -> impl Iterator<Item = &Arc<Secondary>> {
+        self.secondaries.values()
+    }
+
+    pub fn secondary(&self, origin: &DomainName) -> Option<&Arc<Secondary>> {
+        self.secondaries.get(origin)
+    }
+
+    pub fn add_key(&mut self, key: Key) {
+        self.keyring.add(key);
+    }
+
+    /// Signs the messages to and from `server` with the key called `name`
+    pub fn use_key(&mut self, server: IpAddr, name: &DomainName) -> Result<(), Box<dyn Error>> {
+        self.keyring.use_for(server, name)
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    //Checks that the keys an allow list names are known
+    fn check_keys(&self, clients: &[Allow]) -> Result<(), Box<dyn Error>> {
+        for client in clients {
+            if let Allow::Key(name) = client {
+                if !self.keyring.contains(name) {
+                    return Err(format!("Unknown TSIG key {}", name).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets `clients` transfer the zone at `origin`, which has to be one of ours
+    pub fn allow_transfer(&mut self, origin: &DomainName, clients: Vec<Allow>) -> Result<(), Box<dyn Error>> {
+        if !self.zones.get_mut().unwrap().contains_key(origin) && !self.secondaries.contains_key(origin) {
+            return Err(format!("Can't allow transfers of {}, it's not one of our zones", origin).into());
+        }
+        self.check_keys(&clients)?;
+
+        self.transfer_acls.entry(origin.clone()).or_default().extend(clients);
+        Ok(())
+    }
+
+    /// Lets `clients` send dynamic updates for the zone at `origin`, which has
+    /// to be one of our primary zones
+    pub fn allow_update(&mut self, origin: &DomainName, clients: Vec<Allow>) -> Result<(), Box<dyn Error>> {
+        if !self.zones.get_mut().unwrap().contains_key(origin) || self.secondaries.contains_key(origin) {
+            return Err(format!("Can't allow updates of {}, it's not one of our primary zones", origin).into());
+        }
+        self.check_keys(&clients)?;
+
+        self.update_acls.entry(origin.clone()).or_default().extend(clients);
+        Ok(())
+    }
+
+    /// Has `secondaries` told whenever the zone at `origin` changes
+    pub fn also_notify(&mut self, origin: &DomainName, secondaries: Vec<SocketAddr>) -> Result<(), Box<dyn Error>> {
+        if !self.zones.get_mut().unwrap().contains_key(origin) && !self.secondaries.contains_key(origin) {
+            return Err(format!("Can't notify about changes to {}, it's not one of our zones", origin).into());
+        }
+
+        self.notify_targets.entry(origin.clone()).or_default().extend(secondaries);
+        Ok(())
+    }
+
+    /// Tells the secondaries of the zone at `origin` about its current version
+    pub fn notify_secondaries(&self, origin: &DomainName) {
+        let secondaries = match self.notify_targets.get(origin) {
+            Some(secondaries) => secondaries,
+            None => return,
+        };
+        if let Some(soa) = self.zone(origin).and_then(|zone| zone.soa().cloned()) {
+            notify::send(&soa, secondaries, &self.keyring);
+        }
+    }
+
+    /// Tells the secondaries of every zone about its current version, for when
+    /// we've just started and they can't know if anything changed while we
+    /// were down
+    pub fn notify_all(&self) {
+        for origin in self.notify_targets.keys() {
+            self.notify_secondaries(origin);
+        }
+    }
+
+    /// Whether `client` may transfer the zone at `origin`, having signed its
+    /// request with `key` if any
+    pub fn may_transfer(&self, origin: &DomainName, client: IpAddr, key: Option<&DomainName>) -> bool {
+        self.transfer_acls
+            .get(origin)
+            .is_some_and(|clients| clients.iter().any(|allow| allow.admits(client, key)))
+    }
+
+    pub fn may_update(&self, origin: &DomainName, client: IpAddr, key: Option<&DomainName>) -> bool {
+        self.update_acls
+            .get(origin)
+            .is_some_and(|clients| clients.iter().any(|allow| allow.admits(client, key)))
+    }
+
+    /// Keeps other updates out until the guard is dropped
+    pub fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.update_lock.lock().unwrap()
+    }
+
+    /// How many zones we serve, including secondaries still waiting for their data
+    pub fn zone_count(&self) -> usize {
+        let zones = self.zones.read().unwrap();
+        zones.len() + self.secondaries.keys().filter(|origin| !zones.contains_key(origin)).count()
+    }
+
+    /// The zone whose apex is `origin`
+    pub fn zone(&self, origin: &DomainName) -> Option<Arc<Zone>> {
+        self.zones.read().unwrap().get(origin).cloned()
+    }
+
+    /// Puts a new copy of a zone in place of the old one, notes the changes in
+    /// its journal and, if it has a new serial, tells its secondaries
+    pub fn replace_zone(&self, zone: Zone) {
+        let zone = Arc::new(zone);
+        //The journals stay locked until the changes are noted, so that nobody else
+        //gets to replace the zone in between and the changes go in in order. The
+        //zones themselves are only locked for the swap, queries don't wait for
+        //the journal to be written.
+        let mut journals = self.journals.lock().unwrap();
+        let old = self.zones.write().unwrap().insert(zone.origin.clone(), Arc::clone(&zone));
+
+        let journal = journals
+            .entry(zone.origin.clone())
+            .or_insert_with(|| Journal::new(zone.origin.clone()));
+        if let Err(e) = journal.update(old.as_deref(), &zone) {
+            eprintln!("Can't update the journal of zone {}: {}", zone.origin, e);
+        }
+        drop(journals);
+
+        if old.and_then(|old| old.serial()) != zone.serial() {
+            self.notify_secondaries(&zone.origin);
+        }
+    }
+
+    /// Keeps the journals of all our zones in `dir`, so they last across
+    /// restarts. Zones whose file has changed since the last run get those
+    /// changes added to their journal.
+    pub fn open_journals(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let zones = self.zones.get_mut().unwrap();
+        let journals = self.journals.get_mut().unwrap();
+
+        for origin in zones.keys().chain(self.secondaries.keys()) {
+            let (mut journal, last) = Journal::open(origin.clone(), dir)?;
+            if let Some(zone) = zones.get(origin) {
+                journal.update(last.as_ref(), zone)?;
+            }
+            journals.insert(origin.clone(), journal);
+        }
+        Ok(())
+    }
+
+    /// The changes to the zone at `origin` since the version with serial
+    /// `since`, if its journal goes back that far
+    pub fn changes_since(&self, origin: &DomainName, since: u32) -> Option<Diff> {
+        self.journals.lock().unwrap().get(origin)?.changes_since(since)
+    }
+
+    /// Stops serving the data of a zone, which for a secondary means it expired
+    pub fn remove_zone(&self, origin: &DomainName) {
+        self.zones.write().unwrap().remove(origin);
+    }
+
+    /// The deepest of our zones `qname` belongs to
+    pub fn find_zone(&self, qname: &DomainName) -> Option<Arc<Zone>> {
+        let zones = self.zones.read().unwrap();
+        qname.ancestors().find_map(|name| zones.get(&name).cloned())
+    }
+
+    /// Answers from our own zones, or None if the name isn't in any of them
+    pub fn answer(&self, qname: &DomainName, qtype: QueryType) -> Option<DnsPacket> {
+        let zones = self.zones.read().unwrap();
+        for name in qname.ancestors() {
+            if let Some(zone) = zones.get(&name) {
+                return Some(zone.lookup(qname, qtype));
+            }
+
+            //A secondary zone without data is still ours, whether it was never
+            //transferred or has expired. Nobody else is asked about it.
+            if self.secondaries.contains_key(&name) {
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResultCode::ServFail;
+                return Some(packet);
+            }
+        }
+        None
+    }
+
+    /// The A and AAAA records of `host`, if it's in one of our zones
+    pub fn addresses(&self, host: &DomainName) -> Vec<DnsRecord> {
+        match self.find_zone(host) {
+            Some(zone) => zone.addresses(host),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn zone(serial: u32, last: u8) -> Zone {
+        let origin = DomainName::new("example.org");
+        zonefile::parse(
+            &origin,
+            &format!(
+                "$TTL 3600\n@ SOA ns hostmaster {} 3600 600 86400 300\n@ NS ns\nns A 192.0.2.{}\n",
+                serial, last
+            ),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn answers_come_from_the_deepest_zone() {
+        let mut authority = Authority::new();
+        authority.add_zone(zone(1, 1));
+        let sub = DomainName::new("sub.example.org");
+        authority.add_zone(
+            zonefile::parse(&sub, "$TTL 3600\n@ SOA ns hostmaster 1 3600 600 86400 300\n@ NS ns\nns A 192.0.2.9\n").unwrap(),
+        );
+
+        let packet = authority.answer(&DomainName::new("ns.sub.example.org"), QueryType::A).unwrap();
+        assert!(matches!(packet.answers[..], [DnsRecord::A { addr, .. }] if addr == Ipv4Addr::new(192, 0, 2, 9)));
+        let packet = authority.answer(&DomainName::new("ns.example.org"), QueryType::A).unwrap();
+        assert!(matches!(packet.answers[..], [DnsRecord::A { addr, .. }] if addr == Ipv4Addr::new(192, 0, 2, 1)));
+
+        //Names that don't exist in our zones are ours to deny, others aren't ours at all
+        let packet = authority.answer(&DomainName::new("nope.example.org"), QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert!(authority.answer(&DomainName::new("example.com"), QueryType::A).is_none());
+        assert!(authority.answer(&DomainName::new("org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn replaced_zones_are_journaled() {
+        let origin = DomainName::new("example.org");
+        let mut authority = Authority::new();
+        authority.add_zone(zone(1, 1));
+
+        authority.replace_zone(zone(2, 2));
+        authority.replace_zone(zone(3, 3));
+        assert_eq!(authority.zone(&origin).unwrap().serial(), Some(3));
+
+        let diff = authority.changes_since(&origin, 1).unwrap();
+        let ns = |last| DnsRecord::A {
+            domain: DomainName::new("ns.example.org"),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 3600,
+        };
+        assert_eq!(diff.removed, [ns(1)]);
+        assert_eq!(diff.added, [ns(3)]);
+        assert_eq!(diff.new_serial(), Some(3));
+    }
+}
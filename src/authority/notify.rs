@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::tsig::{Key, Keyring, Signer};
+use crate::udp;
+
+//How long we wait for the first acknowledgement, every retry waits twice as
+//long as the one before, up to the maximum
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+//Tries before we give up on a secondary. It still finds out about the change
+//once its refresh timer runs out.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Tells the secondaries of the zone the SOA belongs to that it has changed
+/// (RFC 1996), so they don't wait for their refresh timer to find out. Each
+/// one is sent its NOTIFY from a thread of its own, and sent it again until it
+/// acknowledges it. Secondaries we share a TSIG key with get a signed NOTIFY.
+pub fn send(soa: &DnsRecord, secondaries: &[SocketAddr], keyring: &Keyring) {
+    for &secondary in secondaries {
+        let soa = soa.clone();
+        let key = keyring.for_server(secondary.ip()).cloned();
+        thread::spawn(move || notify(&soa, secondary, key.as_ref(), INITIAL_TIMEOUT));
+    }
+}
+
+//Sends the NOTIFY until the secondary answers, waiting `timeout` for the first
+//answer. Returns the answer, or None if there never was one.
+fn notify(soa: &DnsRecord, secondary: SocketAddr, key: Option<&Key>, mut timeout: Duration) -> Option<ResultCode> {
+    let zone = soa.domain();
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match send_once(soa, secondary, key, timeout) {
+            Ok(ResultCode::NoError) => {
+                println!("{} acknowledged the change of zone {}", secondary, zone);
+                return Some(ResultCode::NoError);
+            }
+            Ok(rescode) => {
+                eprintln!("{} answered the NOTIFY for zone {} with {:?}", secondary, zone, rescode);
+                return Some(rescode);
+            }
+            Err(e) => eprintln!("NOTIFY for zone {} to {} failed (attempt {}): {}", zone, secondary, attempt, e),
+        }
+        timeout = next_timeout(timeout);
+    }
+
+    eprintln!("Giving up on telling {} about the change of zone {}", secondary, zone);
+    None
+}
+
+fn next_timeout(timeout: Duration) -> Duration {
+    (timeout * 2).min(MAX_TIMEOUT)
+}
+
+//Sends the NOTIFY once and waits for the answer, which is all a secondary
+//does to acknowledge it
+fn send_once(soa: &DnsRecord, secondary: SocketAddr, key: Option<&Key>, timeout: Duration) -> Result<ResultCode, Box<dyn Error>> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = udp::random_id();
+    packet.header.opcode = Opcode::Notify;
+    packet.header.authoritative_answer = true;
+    packet.questions.push(DnsQuestion::new(soa.domain().clone(), QueryType::Soa));
+    //The new SOA, a hint the secondary may use to skip asking us for the serial
+    packet.answers.push(soa.clone());
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    let mut signer = key.map(|key| Signer::new(key.clone()));
+    if let Some(signer) = signer.as_mut() {
+        signer.sign(&mut req_buffer)?;
+    }
+
+    let (response, mut res_buffer) = udp::exchange(&packet, &req_buffer, secondary, timeout)?;
+    if let Some(signer) = signer {
+        signer.verifier().check(&mut res_buffer)?;
+    }
+
+    if response.header.opcode != Opcode::Notify {
+        return Err("The answer doesn't match our NOTIFY".into());
+    }
+    Ok(response.header.rescode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::domainname::DomainName;
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    fn soa() -> DnsRecord {
+        DnsRecord::Soa {
+            domain: DomainName::new("example.org"),
+            mname: DomainName::new("ns.example.org"),
+            rname: DomainName::new("hostmaster.example.org"),
+            serial: 2,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    //A secondary that ignores the first `ignored` NOTIFYs and answers the next
+    //one with `rescode`. Returns its address and when each NOTIFY came in.
+    fn secondary(ignored: usize, rescode: ResultCode) -> (SocketAddr, thread::JoinHandle<Vec<Instant>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut arrivals = Vec::new();
+            //Anything after the answer would be one NOTIFY too many
+            socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            loop {
+                let mut buffer = BytePacketBuffer::new();
+                let (_, client) = match socket.recv_from(&mut buffer.buf) {
+                    Ok(received) => received,
+                    Err(_) => return arrivals,
+                };
+                arrivals.push(Instant::now());
+                if arrivals.len() <= ignored {
+                    continue;
+                }
+
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+                assert_eq!(request.header.opcode, Opcode::Notify);
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.opcode = Opcode::Notify;
+                response.header.response = true;
+                response.header.rescode = rescode;
+                response.questions = request.questions;
+                let mut buffer = BytePacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+            }
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn timeouts_double_up_to_the_maximum() {
+        let mut timeout = INITIAL_TIMEOUT;
+        let mut timeouts = Vec::new();
+        for _ in 0..7 {
+            timeouts.push(timeout.as_secs());
+            timeout = next_timeout(timeout);
+        }
+        assert_eq!(timeouts, [2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn notify_is_sent_again_until_it_is_answered() {
+        let (addr, secondary) = secondary(2, ResultCode::NoError);
+        assert_eq!(notify(&soa(), addr, None, Duration::from_millis(50)), Some(ResultCode::NoError));
+
+        let arrivals = secondary.join().unwrap();
+        assert_eq!(arrivals.len(), 3);
+        //The second retry waited longer than the first
+        let first = arrivals[1] - arrivals[0];
+        let second = arrivals[2] - arrivals[1];
+        assert!(first >= Duration::from_millis(50), "{:?}", first);
+        assert!(second >= Duration::from_millis(100), "{:?}", second);
+    }
+
+    #[test]
+    fn any_answer_ends_the_retries() {
+        let (addr, secondary) = secondary(0, ResultCode::NotAuth);
+        assert_eq!(notify(&soa(), addr, None, Duration::from_millis(50)), Some(ResultCode::NotAuth));
+        assert_eq!(secondary.join().unwrap().len(), 1);
+    }
+}
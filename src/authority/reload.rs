@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::authority::zone::{is_newer, Zone};
+use crate::authority::zonefile;
+use crate::authority::Authority;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+
+//How often zone files are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watches the file a zone was loaded from, and loads it again whenever it has
+/// been modified. The new version only takes over with a newer serial, since
+/// secondaries would never pick up a change without one. A file that doesn't
+/// parse leaves the old version in place.
+pub fn run(authority: &Authority, origin: &DomainName, path: &Path) {
+    let mut last_modified = modified(path);
+
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        let now = modified(path);
+        if now != last_modified {
+            last_modified = now;
+            reload(authority, origin, path);
+        }
+    }
+}
+
+fn reload(authority: &Authority, origin: &DomainName, path: &Path) {
+    //Dynamic updates write the file as well, one at a time with us
+    let _guard = authority.lock_updates();
+
+    let zone = match zonefile::load(origin, path) {
+        Ok(zone) => zone,
+        Err(e) => {
+            eprintln!("Not reloading zone {}: {}", origin, e);
+            return;
+        }
+    };
+
+    let current = authority.zone(origin);
+    let records = |zone: &Zone| -> BTreeSet<DnsRecord> { zone.records().into_iter().collect() };
+    //Which is the case after every update
+    if current.as_ref().is_some_and(|current| records(current) == records(&zone)) {
+        return;
+    }
+
+    match (zone.serial(), current.and_then(|zone| zone.serial())) {
+        (Some(serial), Some(current)) if !is_newer(serial, current) => {
+            eprintln!("Not reloading zone {}: serial {} isn't newer than {}", origin, serial, current);
+        }
+        (serial, _) => {
+            println!("Reloaded zone {} from {}, now at serial {:?}", origin, path.display(), serial);
+            authority.replace_zone(zone);
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::querytype::QueryType;
+    use std::process;
+
+    fn zone_file(serial: u32, www: u8) -> String {
+        format!(
+            "$TTL 3600\n@ SOA ns hostmaster {} 3600 600 86400 300\n@ NS ns\nns A 192.0.2.1\nwww A 192.0.2.{}\n",
+            serial, www
+        )
+    }
+
+    #[test]
+    fn reloads_need_a_newer_serial_and_keep_what_was_written_back() {
+        let origin = DomainName::new("example.org");
+        let dir = std::env::temp_dir().join(format!("reload-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.org.zone");
+        fs::write(&path, zone_file(1, 2)).unwrap();
+
+        let mut authority = Authority::new();
+        authority.load_zone(origin.clone(), path.clone()).unwrap();
+        let www = || authority.zone(&origin).unwrap().rrset(&DomainName::new("www.example.org"), QueryType::A);
+        let serial = || authority.zone(&origin).unwrap().serial();
+
+        //The way an update leaves things: a new version, in memory and in the file
+        let updated = zonefile::parse(&origin, &zone_file(2, 3)).unwrap();
+        zonefile::save(&updated, &path).unwrap();
+        authority.replace_zone(updated);
+        reload(&authority, &origin, &path);
+        assert_eq!(serial(), Some(2));
+        assert_eq!(authority.changes_since(&origin, 1).map(|diff| diff.added.len()), Some(1));
+
+        fs::write(&path, zone_file(2, 4)).unwrap();
+        reload(&authority, &origin, &path);
+        assert_eq!(serial(), Some(2));
+
+        fs::write(&path, zone_file(3, 4)).unwrap();
+        reload(&authority, &origin, &path);
+        assert_eq!(serial(), Some(3));
+        assert_eq!(www().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
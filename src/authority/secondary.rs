@@ -0,0 +1,601 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::authority::zone::{is_newer, Zone};
+use crate::authority::Authority;
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::tcp;
+use crate::tsig::{Key, Signer};
+use crate::udp;
+
+//How long we wait on the primary, for an answer to the SOA query as well as
+//for each message of a transfer
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(10);
+
+//How often we try while we have no copy of the zone, and no SOA to tell us
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+//Timers in the SOA shorter than this are raised to it, a zone with a refresh of
+//zero shouldn't have us hammering the primary
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A zone we serve a copy of, transferred from its primary server
+pub struct Secondary {
+    pub origin: DomainName,
+    pub primary: SocketAddr,
+    //Set when the primary has told us the zone changed, until we've checked
+    notified: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl Secondary {
+    pub fn new(origin: DomainName, primary: SocketAddr) -> Secondary {
+        Secondary {
+            origin,
+            primary,
+            notified: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Has the zone checked against the primary right away
+    pub fn notify(&self) {
+        *self.notified.lock().unwrap() = true;
+        self.wakeup.notify_one();
+    }
+
+    //Sleeps for `timeout`, or until a NOTIFY comes in
+    fn wait(&self, timeout: Duration) {
+        let notified = self.notified.lock().unwrap();
+        let (mut notified, _) = self
+            .wakeup
+            .wait_timeout_while(notified, timeout, |notified| !*notified)
+            .unwrap();
+        *notified = false;
+    }
+}
+
+//The timers of a zone's SOA, which say how a secondary keeps its copy current
+struct Timers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+impl Timers {
+    fn of(zone: &Zone) -> Option<Timers> {
+        let secs = |secs: u32| Duration::from_secs(secs as u64).max(MIN_INTERVAL);
+        match zone.soa()? {
+            DnsRecord::Soa { refresh, retry, expire, .. } => Some(Timers {
+                refresh: secs(*refresh),
+                retry: secs(*retry),
+                expire: secs(*expire),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps the copy of a secondary zone current for as long as the server runs,
+/// as described in RFC 1034 (section 4.3.5). Every refresh interval of the SOA
+/// we ask the primary for its serial, and transfer the zone when it's newer than
+/// ours. Failures are tried again every retry interval, and once the primary has
+/// been out of reach for the expire interval, the zone stops being served. A
+/// NOTIFY from the primary cuts any wait short.
+pub fn run(authority: &Authority, secondary: &Secondary) {
+    //When the copy we serve was last known to be current
+    let mut last_refresh = Instant::now();
+
+    loop {
+        let result = refresh(authority, secondary);
+        let timers = authority.zone(&secondary.origin).and_then(|zone| Timers::of(&zone));
+
+        let wait = match (result, timers) {
+            (Ok(()), timers) => {
+                last_refresh = Instant::now();
+                timers.map_or(INITIAL_RETRY, |timers| timers.refresh)
+            }
+            (Err(e), Some(timers)) => {
+                eprintln!("Refreshing zone {} from {} failed: {}", secondary.origin, secondary.primary, e);
+                if last_refresh.elapsed() >= timers.expire {
+                    eprintln!("Zone {} has expired, no longer serving it", secondary.origin);
+                    authority.remove_zone(&secondary.origin);
+                    INITIAL_RETRY
+                } else {
+                    timers.retry
+                }
+            }
+            (Err(e), None) => {
+                eprintln!("Transferring zone {} from {} failed: {}", secondary.origin, secondary.primary, e);
+                INITIAL_RETRY
+            }
+        };
+
+        secondary.wait(wait);
+    }
+}
+
+//Brings the zone up to date, if the primary has a newer version than ours
+fn refresh(authority: &Authority, secondary: &Secondary) -> Result<(), Box<dyn Error>> {
+    let current = authority.zone(&secondary.origin);
+    let key = authority.keyring().for_server(secondary.primary.ip());
+
+    if let Some(serial) = current.as_ref().and_then(|zone| zone.serial()) {
+        let primary_serial = query_serial(secondary, key)?;
+        if !is_newer(primary_serial, serial) {
+            return Ok(());
+        }
+    }
+
+    if let Some(zone) = transfer(secondary, current.as_deref(), key)? {
+        println!(
+            "Transferred zone {} from {}, now at serial {:?}",
+            secondary.origin,
+            secondary.primary,
+            zone.serial()
+        );
+        authority.replace_zone(zone);
+    }
+    Ok(())
+}
+
+fn query(origin: &DomainName, qtype: QueryType) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = udp::random_id();
+    packet.questions.push(DnsQuestion::new(origin.clone(), qtype));
+    packet
+}
+
+//Signs the request in the buffer when we share a key with the primary
+fn sign(req_buffer: &mut BytePacketBuffer, key: Option<&Key>) -> Result<Option<Signer>, Box<dyn Error>> {
+    let mut signer = key.map(|key| Signer::new(key.clone()));
+    if let Some(signer) = signer.as_mut() {
+        signer.sign(req_buffer)?;
+    }
+    Ok(signer)
+}
+
+//The serial of the zone as the primary has it
+fn query_serial(secondary: &Secondary, key: Option<&Key>) -> Result<u32, Box<dyn Error>> {
+    let mut request = query(&secondary.origin, QueryType::Soa);
+    let mut req_buffer = BytePacketBuffer::new();
+    request.write(&mut req_buffer)?;
+    let signer = sign(&mut req_buffer, key)?;
+
+    let (response, mut res_buffer) = udp::exchange(&request, &req_buffer, secondary.primary, PRIMARY_TIMEOUT)?;
+    if let Some(signer) = signer {
+        signer.verifier().check(&mut res_buffer)?;
+    }
+    check_response(&request, &response)?;
+
+    response
+        .answers
+        .iter()
+        .find_map(|rec| match rec {
+            DnsRecord::Soa { domain, serial, .. } if *domain == secondary.origin => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| "The primary's answer has no SOA".into())
+}
+
+fn check_response(request: &DnsPacket, response: &DnsPacket) -> Result<(), Box<dyn Error>> {
+    if response.header.id != request.header.id {
+        return Err("The primary's answer doesn't match our query".into());
+    }
+    if response.header.rescode != ResultCode::NoError {
+        return Err(format!("The primary answered {:?}", response.header.rescode).into());
+    }
+    Ok(())
+}
+
+//Transfers the zone from the primary over TCP, incrementally (RFC 1995) when
+//there's a copy to start from. None if it turns out nothing has changed.
+fn transfer(secondary: &Secondary, current: Option<&Zone>, key: Option<&Key>) -> Result<Option<Zone>, Box<dyn Error>> {
+    let incremental = current.is_some();
+    let mut request = query(
+        &secondary.origin,
+        if incremental { QueryType::Ixfr } else { QueryType::Axfr },
+    );
+    //The SOA of our copy tells the primary which version to send the changes from
+    request.authorities.extend(current.and_then(|zone| zone.soa()).cloned());
+
+    let mut stream = TcpStream::connect_timeout(&secondary.primary, PRIMARY_TIMEOUT)?;
+    stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+
+    let mut req_buffer = BytePacketBuffer::new();
+    request.write(&mut req_buffer)?;
+    let mut verifier = sign(&mut req_buffer, key)?.map(|signer| signer.verifier());
+    tcp::write_message(&mut stream, &req_buffer)?;
+
+    let mut records = Vec::new();
+    while !is_complete(&records, incremental)? {
+        let mut res_buffer = tcp::read_message(&mut stream)?
+            .ok_or("The primary stopped sending before the transfer was complete")?;
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if let Some(verifier) = verifier.as_mut() {
+            verifier.check(&mut res_buffer)?;
+        }
+        check_response(&request, &response)?;
+
+        if response.answers.is_empty() {
+            return Err("The primary sent a message without records in the middle of the transfer".into());
+        }
+        records.extend(response.answers);
+    }
+    if verifier.is_some_and(|verifier| !verifier.is_complete()) {
+        return Err("The last message of the transfer isn't signed".into());
+    }
+
+    //Records we can't send on would only break our answers
+    let count = records.len();
+    records.retain(|rec| !matches!(rec, DnsRecord::Unknown { .. }));
+    if records.len() < count {
+        println!("Dropped {} records of unsupported types from zone {}", count - records.len(), secondary.origin);
+    }
+
+    build_zone(&secondary.origin, current, &records)
+}
+
+//Whether the records so far make up a whole transfer. A full one ends with the
+//SOA it started with. An incremental one, told apart by an SOA in second place,
+//has the new SOA three times: at the start, before the last batch of additions
+//and at the end. A lone SOA is how the primary says our copy is current.
+fn is_complete(records: &[DnsRecord], incremental: bool) -> Result<bool, Box<dyn Error>> {
+    let serial = match records.first() {
+        None => return Ok(false),
+        Some(DnsRecord::Soa { serial, .. }) => *serial,
+        Some(_) => return Err("The transfer doesn't start with an SOA".into()),
+    };
+    if records.len() == 1 {
+        return Ok(incremental);
+    }
+
+    let closing = records
+        .iter()
+        .filter(|rec| matches!(rec, DnsRecord::Soa { serial: other, .. } if *other == serial))
+        .count();
+    let diffs = matches!(records[1], DnsRecord::Soa { .. });
+    Ok(closing == if diffs { 3 } else { 2 })
+}
+
+fn build_zone(origin: &DomainName, current: Option<&Zone>, records: &[DnsRecord]) -> Result<Option<Zone>, Box<dyn Error>> {
+    let (soa, rest) = records.split_first().ok_or("Empty transfer")?;
+    let body = match rest.split_last() {
+        Some((_, body)) => body,
+        None => return Ok(None),
+    };
+
+    let zone = match (current, body.first()) {
+        (Some(current), Some(DnsRecord::Soa { .. })) => {
+            let mut zone = current.clone();
+            apply_diffs(&mut zone, body)?;
+            zone
+        }
+        _ => {
+            let mut zone = Zone::new(origin.clone());
+            zone.add(soa.clone())?;
+            for rec in body {
+                zone.add(rec.clone())?;
+            }
+            zone
+        }
+    };
+
+    zone.validate()?;
+    Ok(Some(zone))
+}
+
+//Applies the differences of an incremental transfer. Each one is the SOA of the
+//old version with the records deleted from it, then the SOA of the new version
+//with the records added to it.
+fn apply_diffs(zone: &mut Zone, diffs: &[DnsRecord]) -> Result<(), Box<dyn Error>> {
+    let mut adding = true;
+
+    for rec in diffs {
+        match rec {
+            DnsRecord::Soa { serial, .. } if adding => {
+                if zone.serial() != Some(*serial) {
+                    return Err(format!("The changes start from serial {}, our copy is at {:?}", serial, zone.serial()).into());
+                }
+                adding = false;
+            }
+            DnsRecord::Soa { .. } => {
+                if let Some(old) = zone.soa().cloned() {
+                    zone.remove(&old);
+                }
+                zone.add(rec.clone())?;
+                adding = true;
+            }
+            _ if adding => zone.add(rec.clone())?,
+            _ => zone.remove(rec),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers a NOTIFY, which the primary of one of our secondary zones sends when
+/// the zone has changed (RFC 1996). Only the primary itself is listened to,
+/// and when we share a TSIG key with it, only if it signed the NOTIFY with it.
+pub fn notify(authority: &Authority, request: &DnsPacket, client: IpAddr, key: Option<&DomainName>) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = request.header.id;
+    response.header.opcode = Opcode::Notify;
+    response.header.response = true;
+    response.header.authoritative_answer = true;
+    response.questions = request.questions.clone();
+
+    let question = match request.questions.first() {
+        Some(question) => question,
+        None => {
+            response.header.rescode = ResultCode::FormErr;
+            return response;
+        }
+    };
+
+    let signed = |secondary: &Secondary| match authority.keyring().for_server(secondary.primary.ip()) {
+        Some(expected) => key == Some(&expected.name),
+        None => true,
+    };
+
+    match authority.secondary(&question.name) {
+        Some(secondary) if secondary.primary.ip() == client && signed(secondary) => {
+            println!("Zone {} has changed, notified by {}", question.name, client);
+            secondary.notify();
+        }
+        Some(_) => {
+            println!("Ignoring NOTIFY for zone {} from {}, which isn't its primary or didn't sign it", question.name, client);
+            response.header.rescode = ResultCode::Refused;
+        }
+        None => response.header.rescode = ResultCode::Refused,
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn origin() -> DomainName {
+        DomainName::new("example.org")
+    }
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::Soa {
+            domain: origin(),
+            mname: DomainName::new("ns.example.org"),
+            rname: DomainName::new("hostmaster.example.org"),
+            serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a(name: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: DomainName::new(name),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 3600,
+        }
+    }
+
+    fn ns() -> DnsRecord {
+        DnsRecord::NS {
+            domain: origin(),
+            host: DomainName::new("ns.example.org"),
+            ttl: 3600,
+        }
+    }
+
+    //Version 1 of the zone, as a full transfer
+    fn axfr() -> Vec<DnsRecord> {
+        vec![soa(1), ns(), a("ns.example.org", 1), a("www.example.org", 2), soa(1)]
+    }
+
+    //From version 1 to 2, www moves and mail shows up
+    fn ixfr() -> Vec<DnsRecord> {
+        vec![
+            soa(2),
+            soa(1),
+            a("www.example.org", 2),
+            soa(2),
+            a("www.example.org", 3),
+            a("mail.example.org", 4),
+            soa(2),
+        ]
+    }
+
+    fn addrs(zone: &Zone, name: &str) -> Vec<DnsRecord> {
+        zone.rrset(&DomainName::new(name), QueryType::A)
+    }
+
+    //A primary on a port of its own, answering SOA queries with `serial` and
+    //each transfer with the next of `transfers`, a list of messages. The types
+    //of the transfers asked for come out of the receiver.
+    fn primary(serial: u32, transfers: Vec<Vec<Vec<DnsRecord>>>) -> (SocketAddr, Receiver<QueryType>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).unwrap();
+
+        let answer = |request: &DnsPacket, records: Vec<DnsRecord>| {
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.authoritative_answer = true;
+            response.questions = request.questions.clone();
+            response.answers = records;
+            let mut buffer = BytePacketBuffer::with_size(tcp::MAX_MESSAGE_SIZE);
+            response.write(&mut buffer).unwrap();
+            buffer
+        };
+
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, client) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let response = answer(&request, vec![soa(serial)]);
+            socket.send_to(response.get_range(0, response.pos()).unwrap(), client).unwrap();
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for messages in transfers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = tcp::read_message(&mut stream).unwrap().unwrap();
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+                sender.send(request.questions[0].qtype).unwrap();
+                for records in messages {
+                    tcp::write_message(&mut stream, &answer(&request, records)).unwrap();
+                }
+            }
+        });
+
+        (addr, receiver)
+    }
+
+    #[test]
+    fn transfers_span_several_messages() {
+        let (addr, transfers) = primary(
+            2,
+            vec![
+                //Every message but the last ends in a name, the last one in the SOA
+                vec![vec![soa(1), ns()], vec![a("ns.example.org", 1), a("www.example.org", 2)], vec![soa(1)]],
+                vec![ixfr()[..4].to_vec(), ixfr()[4..].to_vec()],
+            ],
+        );
+        let mut authority = Authority::new();
+        authority.add_secondary(origin(), addr);
+        let secondary = Arc::clone(authority.secondary(&origin()).unwrap());
+
+        //Without a copy, the whole zone gets transferred
+        refresh(&authority, &secondary).unwrap();
+        assert_eq!(transfers.recv().unwrap(), QueryType::Axfr);
+        let zone = authority.zone(&origin()).unwrap();
+        assert_eq!(zone.serial(), Some(1));
+        assert_eq!(addrs(&zone, "www.example.org"), [a("www.example.org", 2)]);
+
+        //The primary is at serial 2 now, so only the changes are asked for
+        refresh(&authority, &secondary).unwrap();
+        assert_eq!(transfers.recv().unwrap(), QueryType::Ixfr);
+        let zone = authority.zone(&origin()).unwrap();
+        assert_eq!(zone.serial(), Some(2));
+        assert_eq!(addrs(&zone, "www.example.org"), [a("www.example.org", 3)]);
+        assert_eq!(addrs(&zone, "mail.example.org"), [a("mail.example.org", 4)]);
+    }
+
+    #[test]
+    fn current_copies_arent_transferred() {
+        let (addr, transfers) = primary(1, vec![]);
+        let mut authority = Authority::new();
+        authority.add_secondary(origin(), addr);
+        authority.add_zone(build_zone(&origin(), None, &axfr()).unwrap().unwrap());
+        let secondary = Arc::clone(authority.secondary(&origin()).unwrap());
+
+        refresh(&authority, &secondary).unwrap();
+        assert!(transfers.try_recv().is_err());
+        assert_eq!(authority.zone(&origin()).unwrap().serial(), Some(1));
+    }
+
+    #[test]
+    fn transfers_are_complete_once_the_soa_comes_around() {
+        let full = axfr();
+        assert!(!is_complete(&[], false).unwrap());
+        assert!(!is_complete(&full[..4], false).unwrap());
+        assert!(is_complete(&full, false).unwrap());
+        assert!(is_complete(&full, true).unwrap());
+
+        let diffs = ixfr();
+        assert!(!is_complete(&diffs[..4], true).unwrap());
+        assert!(!is_complete(&diffs[..6], true).unwrap());
+        assert!(is_complete(&diffs, true).unwrap());
+
+        //A lone SOA says an incremental transfer has nothing to send
+        assert!(is_complete(&full[..1], true).unwrap());
+        assert!(!is_complete(&full[..1], false).unwrap());
+
+        assert!(is_complete(&full[1..], false).is_err());
+    }
+
+    #[test]
+    fn diffs_apply_to_the_version_they_start_from() {
+        let v1 = build_zone(&origin(), None, &axfr()).unwrap().unwrap();
+        assert_eq!(v1.serial(), Some(1));
+
+        let v2 = build_zone(&origin(), Some(&v1), &ixfr()).unwrap().unwrap();
+        assert_eq!(v2.serial(), Some(2));
+        assert_eq!(addrs(&v2, "www.example.org"), [a("www.example.org", 3)]);
+        assert_eq!(v2.rrset(&origin(), QueryType::Soa), [soa(2)]);
+
+        //Changes from a version we don't have can't be applied
+        assert!(build_zone(&origin(), Some(&v2), &ixfr()).is_err());
+
+        //Nothing to send means nothing to build
+        assert!(build_zone(&origin(), Some(&v1), &[soa(1)]).unwrap().is_none());
+
+        //A full transfer in answer to IXFR replaces the copy
+        let v3 = vec![soa(3), ns(), a("ns.example.org", 1), soa(3)];
+        let zone = build_zone(&origin(), Some(&v2), &v3).unwrap().unwrap();
+        assert_eq!(zone.serial(), Some(3));
+        assert!(addrs(&zone, "www.example.org").is_empty());
+    }
+
+    #[test]
+    fn notify_is_only_taken_from_the_primary() {
+        let primary: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let mut authority = Authority::new();
+        authority.add_secondary(origin(), primary);
+        let secondary = Arc::clone(authority.secondary(&origin()).unwrap());
+
+        let mut request = query(&origin(), QueryType::Soa);
+        request.header.opcode = Opcode::Notify;
+
+        let response = notify(&authority, &request, "192.0.2.2".parse().unwrap(), None);
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(!*secondary.notified.lock().unwrap());
+
+        let response = notify(&authority, &request, primary.ip(), None);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.header.id, request.header.id);
+        assert!(response.header.response);
+        assert!(*secondary.notified.lock().unwrap());
+
+        //NOTIFY for zones we aren't a secondary for, or without a question
+        let other = query(&DomainName::new("example.com"), QueryType::Soa);
+        assert_eq!(notify(&authority, &other, primary.ip(), None).header.rescode, ResultCode::Refused);
+        assert_eq!(notify(&authority, &DnsPacket::new(), primary.ip(), None).header.rescode, ResultCode::FormErr);
+    }
+
+    #[test]
+    fn notify_has_to_be_signed_when_we_share_a_key() {
+        let primary: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let key = Key::new("transfer-key", "hmac-sha256", "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0").unwrap();
+        let mut authority = Authority::new();
+        authority.add_secondary(origin(), primary);
+        authority.add_key(key.clone());
+        authority.use_key(primary.ip(), &key.name).unwrap();
+
+        let request = query(&origin(), QueryType::Soa);
+        let response = notify(&authority, &request, primary.ip(), None);
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+
+        let response = notify(&authority, &request, primary.ip(), Some(&key.name));
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+    }
+}
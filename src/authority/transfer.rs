@@ -0,0 +1,248 @@
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::authority::zone::{is_newer, Zone};
+use crate::authority::Authority;
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::tcp;
+use crate::tsig;
+
+//Every message starts with a header of this many bytes
+const HEADER_SIZE: usize = 12;
+
+/// Answers an AXFR question (RFC 5936) with the whole zone, spread over as many
+/// messages as it takes. The SOA comes first and last, which is how the client
+/// knows the transfer is complete.
+///
+/// IXFR questions (RFC 1995) get the changes since the version the client has
+/// from the zone's journal, all rolled into one. When the journal doesn't go
+/// back that far, they get the whole zone as well.
+///
+/// Only clients on the zone's allow list get anything, everyone else is refused.
+/// `key` is the TSIG key the request was signed with, if any.
+pub fn answer(authority: &Authority, request: &DnsPacket, client: IpAddr, key: Option<&DomainName>) -> Result<Vec<DnsPacket>, Box<dyn Error>> {
+    let question = request.questions.first().ok_or("Zone transfer request without a question")?;
+    let mut first = reply_to(request, Some(question));
+
+    let zone = match authority.zone(&question.name) {
+        Some(zone) if authority.may_transfer(&zone.origin, client, key) => zone,
+        Some(_) => {
+            println!("Refused transfer of {} to {}", question.name, client);
+            first.header.rescode = ResultCode::Refused;
+            return Ok(vec![first]);
+        }
+        None => {
+            first.header.rescode = ResultCode::NotAuth;
+            return Ok(vec![first]);
+        }
+    };
+
+    let incremental = match question.qtype {
+        QueryType::Ixfr => changes(authority, &zone, request),
+        _ => None,
+    };
+    let kind = if incremental.is_some() { "incrementally" } else { "in full" };
+
+    let records = incremental.unwrap_or_else(|| {
+        let mut records = zone.records();
+        records.extend(zone.soa().cloned());
+        records
+    });
+
+    //Records are written without name compression, so their sizes simply add up
+    //and a message is full once the next record would take it past the limit.
+    //The question is only repeated in the first one, and signed messages need
+    //room for their signature.
+    let limit = tcp::MAX_MESSAGE_SIZE - if key.is_some() { tsig::MAX_OVERHEAD } else { 0 };
+    let mut scratch = BytePacketBuffer::with_size(tcp::MAX_MESSAGE_SIZE);
+    first.write(&mut scratch)?;
+    let mut size = scratch.pos();
+
+    let mut messages = vec![first];
+    for rec in records {
+        scratch.seek(0)?;
+        let len = rec
+            .write(&mut scratch)
+            .map_err(|e| format!("Can't encode {:?} for the transfer of {}: {}", rec, zone.origin, e))?;
+
+        if size + len > limit {
+            messages.push(reply_to(request, None));
+            size = HEADER_SIZE;
+        }
+        size += len;
+        messages.last_mut().ok_or("No message to add records to")?.answers.push(rec);
+    }
+
+    println!("Transferring {} to {} {} in {} message(s)", zone.origin, client, kind, messages.len());
+    Ok(messages)
+}
+
+//The records of an incremental transfer to a client that has the version of
+//the zone whose SOA is in the request. A client that's up to date only gets the
+//current SOA. None if the journal can't tell what changed since.
+fn changes(authority: &Authority, zone: &Zone, request: &DnsPacket) -> Option<Vec<DnsRecord>> {
+    let since = request.authorities.iter().find_map(|rec| match rec {
+        DnsRecord::Soa { serial, .. } => Some(*serial),
+        _ => None,
+    })?;
+    let soa = zone.soa()?;
+    let serial = zone.serial()?;
+
+    if !is_newer(serial, since) {
+        return Some(vec![soa.clone()]);
+    }
+
+    let diff = authority.changes_since(&zone.origin, since)?;
+    if diff.new_serial() != Some(serial) {
+        return None;
+    }
+
+    let mut records = vec![soa.clone()];
+    records.extend(diff.records());
+    records.push(soa.clone());
+    Some(records)
+}
+
+/// Answers a transfer question that came in over UDP. AXFR isn't defined over
+/// UDP (RFC 5936, section 4.2) and gets NOTIMP. IXFR gets the zone's SOA alone,
+/// which tells the client our version and to come back over TCP for the
+/// changes (RFC 1995, section 2).
+pub fn answer_udp(authority: &Authority, request: &DnsPacket, client: IpAddr, key: Option<&DomainName>) -> DnsPacket {
+    let question = request.questions.first();
+    let mut reply = reply_to(request, question);
+    let question = match question {
+        Some(question) if question.qtype == QueryType::Ixfr => question,
+        _ => {
+            reply.header.authoritative_answer = false;
+            reply.header.rescode = ResultCode::NotImp;
+            return reply;
+        }
+    };
+
+    match authority.zone(&question.name) {
+        Some(zone) if authority.may_transfer(&zone.origin, client, key) => reply.answers.extend(zone.soa().cloned()),
+        Some(_) => reply.header.rescode = ResultCode::Refused,
+        None => reply.header.rescode = ResultCode::NotAuth,
+    }
+    reply
+}
+
+fn reply_to(request: &DnsPacket, question: Option<&DnsQuestion>) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.response = true;
+    packet.header.authoritative_answer = true;
+    packet.questions.extend(question.cloned());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::{zonefile, Allow};
+
+    //example.org with `extra` records added, transferable to 192.0.2.1
+    fn authority_with(extra: &str) -> Authority {
+        let origin = DomainName::new("example.org");
+        let text = format!("$TTL 3600\n@ SOA ns hostmaster 7 3600 600 86400 300\n@ NS ns\nns A 192.0.2.53\n{}", extra);
+        let mut authority = Authority::new();
+        authority.add_zone(zonefile::parse(&origin, &text).unwrap());
+        authority.allow_transfer(&origin, vec![Allow::Address(ip("192.0.2.1"))]).unwrap();
+        authority
+    }
+
+    fn authority() -> Authority {
+        authority_with("")
+    }
+
+    fn is_soa(rec: &DnsRecord) -> bool {
+        rec.qtype() == QueryType::Soa
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn request(zone: &str, qtype: QueryType) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request.header.id = 9;
+        request.questions.push(DnsQuestion::new(DomainName::new(zone), qtype));
+        request
+    }
+
+    #[test]
+    fn axfr_over_udp_is_not_implemented() {
+        let reply = answer_udp(&authority(), &request("example.org", QueryType::Axfr), ip("192.0.2.1"), None);
+        assert_eq!(reply.header.rescode, ResultCode::NotImp);
+        assert!(!reply.header.truncated_message);
+        assert!(reply.answers.is_empty());
+    }
+
+    #[test]
+    fn ixfr_over_udp_gets_the_soa_alone() {
+        let authority = authority();
+        let reply = answer_udp(&authority, &request("example.org", QueryType::Ixfr), ip("192.0.2.1"), None);
+        assert_eq!(reply.header.rescode, ResultCode::NoError);
+        assert_eq!(reply.header.id, 9);
+        assert!(reply.header.authoritative_answer);
+        assert!(matches!(reply.answers[..], [DnsRecord::Soa { serial: 7, .. }]));
+
+        let reply = answer_udp(&authority, &request("example.org", QueryType::Ixfr), ip("192.0.2.2"), None);
+        assert_eq!(reply.header.rescode, ResultCode::Refused);
+        assert!(reply.answers.is_empty());
+
+        let reply = answer_udp(&authority, &request("example.com", QueryType::Ixfr), ip("192.0.2.1"), None);
+        assert_eq!(reply.header.rescode, ResultCode::NotAuth);
+    }
+
+    #[test]
+    fn axfr_starts_and_ends_with_the_soa() {
+        let messages = answer(&authority(), &request("example.org", QueryType::Axfr), ip("192.0.2.1"), None).unwrap();
+        assert_eq!(messages.len(), 1);
+        let records = &messages[0].answers;
+        assert_eq!(records.len(), 4);
+        assert!(is_soa(&records[0]) && is_soa(&records[3]));
+        assert!(!records[1..3].iter().any(is_soa));
+        assert_eq!(messages[0].questions.len(), 1);
+        assert_eq!(messages[0].header.id, 9);
+    }
+
+    #[test]
+    fn large_zones_are_split_across_messages() {
+        let text: String = (0..1000).map(|i| format!("txt{} TXT \"{}\"\n", i, "x".repeat(60))).collect();
+        let messages = answer(&authority_with(&text), &request("example.org", QueryType::Axfr), ip("192.0.2.1"), None).unwrap();
+        assert!(messages.len() > 1);
+
+        //Only the first message repeats the question, and every one of them fits
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(message.questions.len(), usize::from(i == 0));
+            assert_eq!(message.header.id, 9);
+            let mut message = message.clone();
+            let mut buffer = BytePacketBuffer::with_size(tcp::MAX_MESSAGE_SIZE);
+            message.write(&mut buffer).unwrap();
+        }
+
+        let records: Vec<&DnsRecord> = messages.iter().flat_map(|message| &message.answers).collect();
+        assert_eq!(records.len(), 1000 + 4);
+        assert!(is_soa(records[0]) && is_soa(records[records.len() - 1]));
+        assert_eq!(records.iter().filter(|rec| is_soa(rec)).count(), 2);
+    }
+
+    #[test]
+    fn transfers_only_for_allowed_clients() {
+        let authority = authority();
+        let messages = answer(&authority, &request("example.org", QueryType::Axfr), ip("192.0.2.2"), None).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].header.rescode, ResultCode::Refused);
+        assert!(messages[0].answers.is_empty());
+
+        let messages = answer(&authority, &request("example.com", QueryType::Axfr), ip("192.0.2.1"), None).unwrap();
+        assert_eq!(messages[0].header.rescode, ResultCode::NotAuth);
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::authority::zone::{is_newer, Zone};
+use crate::authority::zonefile;
+use crate::authority::Authority;
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnsheader::DnsHeader;
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::opcode::Opcode;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+
+//The classes of prerequisite and update records (RFC 2136 section 2.4 and 2.5).
+//IN is the class of our zones, the other two say what to do with the record.
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+//An UPDATE message. It reuses the sections of a query: the question section
+//holds the zone, the answer section the prerequisites and the authority
+//section the updates.
+struct Update {
+    zone: Vec<DnsQuestion>,
+    prerequisites: Vec<(DnsRecord, u16)>,
+    updates: Vec<(DnsRecord, u16)>,
+}
+
+impl Update {
+    //DnsPacket drops the class of every record, which is what UPDATE messages
+    //are all about, so they get read a second time with the classes kept
+    fn read(header: &DnsHeader, buffer: &mut BytePacketBuffer) -> Result<Update, Box<dyn Error>> {
+        let mut zone = Vec::new();
+        for _ in 0..header.questions {
+            let mut question = DnsQuestion::new(DomainName::root(), QueryType::Unknown(0));
+            question.read(buffer)?;
+            zone.push(question);
+        }
+
+        let mut prerequisites = Vec::new();
+        for _ in 0..header.answers {
+            prerequisites.push(DnsRecord::read_with_class(buffer)?);
+        }
+        let mut updates = Vec::new();
+        for _ in 0..header.authoritative_entries {
+            updates.push(DnsRecord::read_with_class(buffer)?);
+        }
+
+        Ok(Update { zone, prerequisites, updates })
+    }
+}
+
+/// Answers an UPDATE message (RFC 2136), which changes one of our primary
+/// zones if all its prerequisites hold. The changes make a new version of the
+/// zone with a higher serial all at once, or aren't made at all. Only clients
+/// on the zone's allow list may update it, everyone else is refused. `key` is
+/// the TSIG key the update was signed with, if any. Zones loaded from a file
+/// get the new version written back to it, so updates last across restarts
+/// and reloads; edits to the file have to start from that version.
+pub fn answer(authority: &Authority, buffer: &mut BytePacketBuffer, client: IpAddr, key: Option<&DomainName>) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.opcode = Opcode::Update;
+    response.header.response = true;
+
+    let mut header = DnsHeader::new();
+    let update = buffer
+        .seek(0)
+        .and_then(|_| header.read(buffer))
+        .and_then(|_| Update::read(&header, buffer));
+    response.header.id = header.id;
+
+    let update = match update {
+        Ok(update) => update,
+        Err(e) => {
+            println!("Malformed update from {}: {}", client, e);
+            response.header.rescode = ResultCode::FormErr;
+            return response;
+        }
+    };
+    response.questions = update.zone.clone();
+
+    if let Err(rescode) = apply(authority, &update, client, key) {
+        response.header.rescode = rescode;
+    }
+    response
+}
+
+fn apply(authority: &Authority, update: &Update, client: IpAddr, key: Option<&DomainName>) -> Result<(), ResultCode> {
+    let origin = match update.zone.as_slice() {
+        [zone] if zone.qtype == QueryType::Soa => &zone.name,
+        _ => return Err(ResultCode::FormErr),
+    };
+
+    //Secondaries would pass the update on to the primary, which we don't do
+    if authority.secondary(origin).is_some() {
+        println!("Refused update of {} from {}, we're a secondary for it", origin, client);
+        return Err(ResultCode::Refused);
+    }
+
+    let _guard = authority.lock_updates();
+    let current = authority.zone(origin).ok_or(ResultCode::NotAuth)?;
+    if !authority.may_update(origin, client, key) {
+        println!("Refused update of {} from {}", origin, client);
+        return Err(ResultCode::Refused);
+    }
+
+    check_prerequisites(&current, &update.prerequisites)?;
+    prescan(&current, &update.updates)?;
+
+    let mut zone = (*current).clone();
+    for (rec, class) in &update.updates {
+        apply_update(&mut zone, rec, *class);
+    }
+
+    let contents = |zone: &Zone| -> BTreeSet<DnsRecord> { zone.records().into_iter().collect() };
+    if contents(&zone) == contents(&current) {
+        println!("Update of {} from {} changed nothing", origin, client);
+        return Ok(());
+    }
+
+    //A new version needs a new serial, unless the update brought one itself
+    let bumped = matches!((zone.serial(), current.serial()), (Some(new), Some(old)) if is_newer(new, old));
+    if !bumped {
+        bump_serial(&mut zone);
+    }
+    zone.validate().map_err(|e| {
+        eprintln!("Update of {} from {} would break the zone: {}", origin, client, e);
+        ResultCode::ServFail
+    })?;
+
+    //An update we can't keep isn't made at all
+    if let Some(path) = authority.zone_file(origin) {
+        zonefile::save(&zone, path).map_err(|e| {
+            eprintln!("Update of {} from {} can't be saved: {}", origin, client, e);
+            ResultCode::ServFail
+        })?;
+    }
+
+    println!("Updated zone {} from {}, now at serial {:?}", origin, client, zone.serial());
+    authority.replace_zone(zone);
+    Ok(())
+}
+
+//Whether a prerequisite or update record has no data, which makes it about a
+//whole RRset or name instead of a single record
+fn is_empty(rec: &DnsRecord) -> bool {
+    matches!(rec, DnsRecord::Unknown { data_len: 0, .. })
+}
+
+//Checks the prerequisites against the zone (RFC 2136 section 3.2). Records of
+//class IN together require an RRset to be exactly the way they are.
+fn check_prerequisites(zone: &Zone, prerequisites: &[(DnsRecord, u16)]) -> Result<(), ResultCode> {
+    let mut rrsets: BTreeSet<(DomainName, u16)> = BTreeSet::new();
+
+    for (rec, class) in prerequisites {
+        if rec.ttl() != 0 {
+            return Err(ResultCode::FormErr);
+        }
+        if !rec.domain().is_subdomain_of(&zone.origin) {
+            return Err(ResultCode::NotZone);
+        }
+
+        let (name, qtype) = (rec.domain(), rec.qtype());
+        match *class {
+            CLASS_ANY | CLASS_NONE if !is_empty(rec) => return Err(ResultCode::FormErr),
+            //The name is in use, or has an RRset of the type
+            CLASS_ANY if qtype == QueryType::Any => {
+                if zone.records_at(name).is_empty() {
+                    return Err(ResultCode::NXDomain);
+                }
+            }
+            CLASS_ANY => {
+                if zone.rrset(name, qtype).is_empty() {
+                    return Err(ResultCode::NXRRSet);
+                }
+            }
+            //The name isn't in use, or has no RRset of the type
+            CLASS_NONE if qtype == QueryType::Any => {
+                if !zone.records_at(name).is_empty() {
+                    return Err(ResultCode::YXDomain);
+                }
+            }
+            CLASS_NONE => {
+                if !zone.rrset(name, qtype).is_empty() {
+                    return Err(ResultCode::YXRRSet);
+                }
+            }
+            CLASS_IN if qtype != QueryType::Any && !is_empty(rec) => {
+                rrsets.insert((name.clone(), qtype.to_num()));
+            }
+            _ => return Err(ResultCode::FormErr),
+        }
+    }
+
+    for (name, qtype) in rrsets {
+        let qtype = QueryType::from_num(qtype);
+        let expected: BTreeSet<DnsRecord> = prerequisites
+            .iter()
+            .filter(|(rec, class)| *class == CLASS_IN && *rec.domain() == name && rec.qtype() == qtype)
+            .map(|(rec, _)| rec.clone())
+            .collect();
+        let actual: BTreeSet<DnsRecord> = zone
+            .rrset(&name, qtype)
+            .into_iter()
+            .map(|mut rec| {
+                rec.set_ttl(0);
+                rec
+            })
+            .collect();
+        if expected != actual {
+            return Err(ResultCode::NXRRSet);
+        }
+    }
+
+    Ok(())
+}
+
+//Checks the updates before any of them is made (RFC 2136 section 3.4.1)
+fn prescan(zone: &Zone, updates: &[(DnsRecord, u16)]) -> Result<(), ResultCode> {
+    for (rec, class) in updates {
+        if !rec.domain().is_subdomain_of(&zone.origin) {
+            return Err(ResultCode::NotZone);
+        }
+
+        let meta = matches!(rec.qtype(), QueryType::Any | QueryType::Axfr | QueryType::Ixfr | QueryType::Opt | QueryType::Tsig);
+        match *class {
+            CLASS_IN if meta || is_empty(rec) => return Err(ResultCode::FormErr),
+            //We only keep the data of the types we know, there'd be nothing to serve
+            CLASS_IN if matches!(rec, DnsRecord::Unknown { .. }) => return Err(ResultCode::NotImp),
+            CLASS_IN => {}
+            CLASS_ANY if rec.ttl() != 0 || !is_empty(rec) || (meta && rec.qtype() != QueryType::Any) => {
+                return Err(ResultCode::FormErr)
+            }
+            CLASS_ANY => {}
+            CLASS_NONE if rec.ttl() != 0 || meta => return Err(ResultCode::FormErr),
+            CLASS_NONE => {}
+            _ => return Err(ResultCode::FormErr),
+        }
+    }
+    Ok(())
+}
+
+//Makes a single change (RFC 2136 section 3.4.2). Changes that would leave the
+//zone without its SOA or NS records, or a CNAME next to other data, are skipped.
+fn apply_update(zone: &mut Zone, rec: &DnsRecord, class: u16) {
+    let name = rec.domain();
+    let qtype = rec.qtype();
+    let at_apex = *name == zone.origin;
+
+    match class {
+        CLASS_IN => {
+            let existing = zone.records_at(name);
+            match qtype {
+                QueryType::Soa => {
+                    let newer = match (rec, zone.serial()) {
+                        (DnsRecord::Soa { serial, .. }, Some(current)) => is_newer(*serial, current),
+                        _ => false,
+                    };
+                    if !at_apex || !newer {
+                        return;
+                    }
+                }
+                QueryType::Cname if existing.iter().any(|other| other.qtype() != QueryType::Cname) => return,
+                _ if existing.iter().any(|other| other.qtype() == QueryType::Cname) && qtype != QueryType::Cname => return,
+                _ => {}
+            }
+
+            //An SOA or CNAME takes the place of the one there, any other record
+            //replaces only its exact duplicate, which may have a different ttl
+            if matches!(qtype, QueryType::Soa | QueryType::Cname) {
+                for other in zone.rrset(name, qtype) {
+                    zone.remove(&other);
+                }
+            } else {
+                zone.remove(rec);
+            }
+            //Names and types were checked in the prescan
+            let _ = zone.add(rec.clone());
+        }
+        CLASS_ANY => {
+            let doomed = if qtype == QueryType::Any {
+                zone.records_at(name)
+            } else {
+                zone.rrset(name, qtype)
+            };
+            for other in doomed {
+                if at_apex && matches!(other.qtype(), QueryType::Soa | QueryType::NS) {
+                    continue;
+                }
+                zone.remove(&other);
+            }
+        }
+        CLASS_NONE => {
+            if qtype == QueryType::Soa {
+                return;
+            }
+            if at_apex && qtype == QueryType::NS {
+                let last = zone.rrset(name, qtype).iter().all(|other| {
+                    let mut other = other.clone();
+                    other.set_ttl(rec.ttl());
+                    other == *rec
+                });
+                if last {
+                    return;
+                }
+            }
+            zone.remove(rec);
+        }
+        _ => {}
+    }
+}
+
+//Moves the zone's serial one ahead
+fn bump_serial(zone: &mut Zone) {
+    let mut soa = match zone.soa() {
+        Some(soa) => soa.clone(),
+        None => return,
+    };
+    zone.remove(&soa);
+    if let DnsRecord::Soa { serial, .. } = &mut soa {
+        *serial = serial.wrapping_add(1);
+    }
+    //The SOA was just taken out of this very zone
+    let _ = zone.add(soa);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::Allow;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+    use std::process;
+
+    const ZONE: &str = "$TTL 3600
+@        SOA   ns hostmaster 1 3600 600 86400 300
+@        NS    ns
+ns       A     192.0.2.1
+www      A     192.0.2.2
+www      A     192.0.2.3
+alias    CNAME www
+";
+
+    fn origin() -> DomainName {
+        DomainName::new("example.org")
+    }
+
+    fn client() -> IpAddr {
+        "192.0.2.100".parse().unwrap()
+    }
+
+    fn authority() -> Authority {
+        let mut authority = Authority::new();
+        authority.add_zone(zonefile::parse(&origin(), ZONE).unwrap());
+        authority.allow_update(&origin(), vec![Allow::Address(client())]).unwrap();
+        authority
+    }
+
+    fn a(name: &str, last: u8, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: DomainName::new(name),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl,
+        }
+    }
+
+    //A prerequisite or update about a whole name or RRset
+    fn empty(name: &str, qtype: QueryType) -> DnsRecord {
+        DnsRecord::Unknown {
+            domain: DomainName::new(name),
+            qtype: qtype.to_num(),
+            data_len: 0,
+            ttl: 0,
+        }
+    }
+
+    fn update(prerequisites: Vec<(DnsRecord, u16)>, updates: Vec<(DnsRecord, u16)>) -> Update {
+        Update {
+            zone: vec![DnsQuestion::new(origin(), QueryType::Soa)],
+            prerequisites,
+            updates,
+        }
+    }
+
+    fn check(authority: &Authority, prerequisites: Vec<(DnsRecord, u16)>) -> Result<(), ResultCode> {
+        let add = vec![(a("new.example.org", 9, 300), CLASS_IN)];
+        apply(authority, &update(prerequisites, add), client(), None)
+    }
+
+    fn addrs(authority: &Authority, name: &str) -> Vec<DnsRecord> {
+        authority.zone(&origin()).unwrap().rrset(&DomainName::new(name), QueryType::A)
+    }
+
+    #[test]
+    fn prerequisites_on_names_and_rrsets() {
+        let authority = authority();
+        //Name is in use
+        assert_eq!(check(&authority, vec![(empty("www.example.org", QueryType::Any), CLASS_ANY)]), Ok(()));
+        assert_eq!(
+            check(&authority, vec![(empty("nope.example.org", QueryType::Any), CLASS_ANY)]),
+            Err(ResultCode::NXDomain)
+        );
+        //RRset exists
+        assert_eq!(check(&authority, vec![(empty("www.example.org", QueryType::A), CLASS_ANY)]), Ok(()));
+        assert_eq!(
+            check(&authority, vec![(empty("www.example.org", QueryType::Aaaa), CLASS_ANY)]),
+            Err(ResultCode::NXRRSet)
+        );
+        //Name is not in use
+        assert_eq!(check(&authority, vec![(empty("nope.example.org", QueryType::Any), CLASS_NONE)]), Ok(()));
+        assert_eq!(
+            check(&authority, vec![(empty("www.example.org", QueryType::Any), CLASS_NONE)]),
+            Err(ResultCode::YXDomain)
+        );
+        //RRset does not exist
+        assert_eq!(check(&authority, vec![(empty("www.example.org", QueryType::Aaaa), CLASS_NONE)]), Ok(()));
+        assert_eq!(
+            check(&authority, vec![(empty("www.example.org", QueryType::A), CLASS_NONE)]),
+            Err(ResultCode::YXRRSet)
+        );
+    }
+
+    #[test]
+    fn prerequisites_on_whole_rrsets() {
+        let authority = authority();
+        let www = |last| (a("www.example.org", last, 0), CLASS_IN);
+        assert_eq!(check(&authority, vec![www(2), www(3)]), Ok(()));
+        //Part of the RRset isn't enough, and neither is more than it has
+        assert_eq!(check(&authority, vec![www(2)]), Err(ResultCode::NXRRSet));
+        assert_eq!(check(&authority, vec![www(2), www(3), www(4)]), Err(ResultCode::NXRRSet));
+    }
+
+    #[test]
+    fn malformed_prerequisites() {
+        let authority = authority();
+        assert_eq!(
+            check(&authority, vec![(a("www.example.org", 2, 300), CLASS_IN)]),
+            Err(ResultCode::FormErr)
+        );
+        assert_eq!(
+            check(&authority, vec![(a("www.example.org", 2, 0), CLASS_ANY)]),
+            Err(ResultCode::FormErr)
+        );
+        assert_eq!(
+            check(&authority, vec![(empty("www.example.com", QueryType::Any), CLASS_ANY)]),
+            Err(ResultCode::NotZone)
+        );
+        //Nothing was added by any of the failed updates
+        assert!(addrs(&authority, "new.example.org").is_empty());
+    }
+
+    #[test]
+    fn updates_add_and_delete_and_bump_the_serial() {
+        let authority = authority();
+        let updates = vec![
+            (a("www.example.org", 2, 0), CLASS_NONE),
+            (a("www.example.org", 4, 300), CLASS_IN),
+            (empty("alias.example.org", QueryType::Any), CLASS_ANY),
+            //A CNAME can't go next to other data, this one is skipped
+            (
+                DnsRecord::Cname {
+                    domain: DomainName::new("www.example.org"),
+                    host: origin(),
+                    ttl: 300,
+                },
+                CLASS_IN,
+            ),
+            //and the apex keeps its NS records
+            (empty("example.org", QueryType::NS), CLASS_ANY),
+        ];
+        assert_eq!(apply(&authority, &update(vec![], updates), client(), None), Ok(()));
+
+        let zone = authority.zone(&origin()).unwrap();
+        assert_eq!(zone.serial(), Some(2));
+        assert_eq!(addrs(&authority, "www.example.org"), [a("www.example.org", 3, 3600), a("www.example.org", 4, 300)]);
+        assert!(zone.records_at(&DomainName::new("alias.example.org")).is_empty());
+        assert_eq!(zone.rrset(&origin(), QueryType::NS).len(), 1);
+
+        //Changing nothing keeps the serial
+        let updates = vec![(a("www.example.org", 9, 0), CLASS_NONE)];
+        assert_eq!(apply(&authority, &update(vec![], updates), client(), None), Ok(()));
+        assert_eq!(authority.zone(&origin()).unwrap().serial(), Some(2));
+    }
+
+    #[test]
+    fn update_rcodes() {
+        let authority = authority();
+        let add = || vec![(a("new.example.org", 9, 300), CLASS_IN)];
+
+        let stranger = "192.0.2.200".parse().unwrap();
+        assert_eq!(apply(&authority, &update(vec![], add()), stranger, None), Err(ResultCode::Refused));
+
+        let mut other = update(vec![], add());
+        other.zone = vec![DnsQuestion::new(DomainName::new("example.com"), QueryType::Soa)];
+        assert_eq!(apply(&authority, &other, client(), None), Err(ResultCode::NotAuth));
+
+        let mut not_soa = update(vec![], add());
+        not_soa.zone[0].qtype = QueryType::A;
+        assert_eq!(apply(&authority, &not_soa, client(), None), Err(ResultCode::FormErr));
+
+        let outside = vec![(a("www.example.com", 9, 300), CLASS_IN)];
+        assert_eq!(apply(&authority, &update(vec![], outside), client(), None), Err(ResultCode::NotZone));
+
+        let unknown = DnsRecord::Unknown {
+            domain: DomainName::new("new.example.org"),
+            qtype: 99,
+            data_len: 4,
+            ttl: 300,
+        };
+        let unknown = vec![(unknown, CLASS_IN)];
+        assert_eq!(apply(&authority, &update(vec![], unknown), client(), None), Err(ResultCode::NotImp));
+
+        assert!(addrs(&authority, "new.example.org").is_empty());
+    }
+
+    #[test]
+    fn updates_arrive_over_the_wire() {
+        let authority = authority();
+        let mut request = DnsPacket::new();
+        request.header.id = 4711;
+        request.header.opcode = Opcode::Update;
+        request.questions.push(DnsQuestion::new(origin(), QueryType::Soa));
+        request.authorities.push(a("new.example.org", 9, 300));
+        let mut buffer = BytePacketBuffer::new();
+        request.write(&mut buffer).unwrap();
+
+        let response = answer(&authority, &mut buffer, client(), None);
+        assert_eq!(response.header.id, 4711);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(addrs(&authority, "new.example.org"), [a("new.example.org", 9, 300)]);
+    }
+
+    #[test]
+    fn updates_are_written_to_the_zone_file() {
+        let dir = std::env::temp_dir().join(format!("update-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path: PathBuf = dir.join("example.org.zone");
+        fs::write(&path, ZONE).unwrap();
+
+        let mut authority = Authority::new();
+        authority.load_zone(origin(), path.clone()).unwrap();
+        authority.allow_update(&origin(), vec![Allow::Address(client())]).unwrap();
+        assert_eq!(check(&authority, vec![]), Ok(()));
+
+        let saved = zonefile::load(&origin(), &path).unwrap();
+        assert_eq!(saved.serial(), Some(2));
+        assert_eq!(saved.rrset(&DomainName::new("new.example.org"), QueryType::A), [a("new.example.org", 9, 300)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
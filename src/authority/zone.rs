@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+
+//How many CNAME and DNAME hops inside the zone we follow for a single answer
+const MAX_CNAME_CHAIN: usize = 8;
+
+//Longest a domain name may get in wire format, DNAME substitutions that would
+//go past it fail with YXDOMAIN
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Serial number arithmetic (RFC 1982): serials wrap around, and `a` is newer
+/// than `b` when it's less than half of the number space ahead of it
+pub fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+//A node in the zone's name tree, one per label. Nodes without records are
+//empty non-terminals, which exist because there is something below them.
+#[derive(Clone, Default)]
+struct Node {
+    records: Vec<DnsRecord>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn rrset(&self, qtype: QueryType) -> Vec<DnsRecord> {
+        self.records.iter().filter(|rec| rec.qtype() == qtype).cloned().collect()
+    }
+
+    fn has(&self, qtype: QueryType) -> bool {
+        self.records.iter().any(|rec| rec.qtype() == qtype)
+    }
+
+    //Removes `rec` from the node `labels` further down, along with the nodes
+    //that are left with neither records nor children
+    fn remove(&mut self, labels: &[String], rec: &DnsRecord) {
+        match labels.split_first() {
+            None => self.records.retain(|other| !same_data(other, rec)),
+            Some((label, rest)) => {
+                if let Some(child) = self.children.get_mut(label) {
+                    child.remove(rest, rec);
+                    if child.records.is_empty() && child.children.is_empty() {
+                        self.children.remove(label);
+                    }
+                }
+            }
+        }
+    }
+}
+
+//Records are the same if only their ttl differs
+fn same_data(a: &DnsRecord, b: &DnsRecord) -> bool {
+    let mut a = a.clone();
+    a.set_ttl(b.ttl());
+    a == *b
+}
+
+//Where a walk down the tree towards a name ended
+enum Walk<'a> {
+    //The name exists, possibly as an empty non-terminal
+    Found(&'a Node),
+    //The name is at or below a delegation to a child zone
+    Cut(DomainName),
+    //The name is below a DNAME, which redirects it elsewhere
+    Dname(&'a DnsRecord),
+    //The name doesn't exist. Its closest encloser is the deepest node that does.
+    Missing(&'a Node),
+}
+
+/// A zone we're authoritative for, held in memory as a tree of names rooted at
+/// the zone's apex
+#[derive(Clone)]
+pub struct Zone {
+    pub origin: DomainName,
+    apex: Node,
+}
+
+impl Zone {
+    pub fn new(origin: DomainName) -> Zone {
+        Zone {
+            origin,
+            apex: Node::default(),
+        }
+    }
+
+    //The labels of `name` below the apex, from the top down. The tree is keyed
+    //by lowercase labels, as names match whatever their case.
+    fn relative_labels<'a>(&self, name: &'a DomainName) -> impl Iterator<Item = String> + 'a {
+        name.labels().rev().skip(self.origin.label_count()).map(str::to_ascii_lowercase)
+    }
+
+    /// Adds a record to the zone. Records outside of it are refused, and adding
+    /// a record that's already there does nothing.
+    pub fn add(&mut self, rec: DnsRecord) -> Result<(), Box<dyn Error>> {
+        if !rec.domain().is_subdomain_of(&self.origin) {
+            return Err(format!("{} is outside of zone {}", rec.domain(), self.origin).into());
+        }
+
+        let labels: Vec<String> = self.relative_labels(rec.domain()).collect();
+        let mut node = &mut self.apex;
+        for label in labels {
+            node = node.children.entry(label).or_default();
+        }
+
+        if !node.records.contains(&rec) {
+            node.records.push(rec);
+        }
+        Ok(())
+    }
+
+    /// Removes a record from the zone, no matter its ttl. Removing a record
+    /// that isn't there does nothing.
+    pub fn remove(&mut self, rec: &DnsRecord) {
+        let labels: Vec<String> = self.relative_labels(rec.domain()).collect();
+        self.apex.remove(&labels, rec);
+    }
+
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.apex.records.iter().find(|rec| rec.qtype() == QueryType::Soa)
+    }
+
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa() {
+            Some(DnsRecord::Soa { serial, .. }) => Some(*serial),
+            _ => None,
+        }
+    }
+
+    /// Every record in the zone with the SOA first, including the delegations
+    /// to child zones and their glue
+    pub fn records(&self) -> Vec<DnsRecord> {
+        let mut records: Vec<DnsRecord> = self.soa().into_iter().cloned().collect();
+        let mut nodes = vec![&self.apex];
+        while let Some(node) = nodes.pop() {
+            records.extend(node.records.iter().filter(|rec| rec.qtype() != QueryType::Soa).cloned());
+            nodes.extend(node.children.values().rev());
+        }
+        records
+    }
+
+    /// Checks that the zone can be served: it needs an SOA and NS records at
+    /// its apex
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.soa().is_none() {
+            return Err(format!("Zone {} has no SOA record at its apex", self.origin).into());
+        }
+        if !self.apex.has(QueryType::NS) {
+            return Err(format!("Zone {} has no NS records at its apex", self.origin).into());
+        }
+        Ok(())
+    }
+
+    fn node(&self, name: &DomainName) -> Option<&Node> {
+        if !name.is_subdomain_of(&self.origin) {
+            return None;
+        }
+
+        let mut node = &self.apex;
+        for label in self.relative_labels(name) {
+            node = node.children.get(&label)?;
+        }
+        Some(node)
+    }
+
+    /// The records of `name` with type `qtype`, without any wildcard or alias
+    /// processing
+    pub fn rrset(&self, name: &DomainName, qtype: QueryType) -> Vec<DnsRecord> {
+        self.node(name).map(|node| node.rrset(qtype)).unwrap_or_default()
+    }
+
+    /// All records of `name`, none for empty non-terminals
+    pub fn records_at(&self, name: &DomainName) -> Vec<DnsRecord> {
+        self.node(name).map(|node| node.records.clone()).unwrap_or_default()
+    }
+
+    /// The A and AAAA records of `host`, taken as they are without any wildcard
+    /// or alias processing
+    pub fn addresses(&self, host: &DomainName) -> Vec<DnsRecord> {
+        let mut addrs = self.rrset(host, QueryType::A);
+        addrs.extend(self.rrset(host, QueryType::Aaaa));
+        addrs
+    }
+
+    //Walks from the apex down towards `name`, stopping early at zone cuts and
+    //DNAMEs, since the tree below them isn't ours to answer from
+    fn walk(&self, name: &DomainName) -> Walk<'_> {
+        let mut node = &self.apex;
+        let mut current = self.origin.clone();
+
+        for label in self.relative_labels(name) {
+            if current != self.origin && node.has(QueryType::NS) {
+                return Walk::Cut(current);
+            }
+            if let Some(dname) = node.records.iter().find(|rec| rec.qtype() == QueryType::Dname) {
+                return Walk::Dname(dname);
+            }
+
+            node = match node.children.get(&label) {
+                Some(child) => child,
+                None => return Walk::Missing(node),
+            };
+            current = DomainName::new(&format!("{}.{}", label, current.as_str()));
+        }
+
+        if current != self.origin && node.has(QueryType::NS) {
+            return Walk::Cut(current);
+        }
+        Walk::Found(node)
+    }
+
+    /// Answers a question for a name in this zone, following the algorithm of
+    /// RFC 1034 (section 4.3.2) with the wildcard rules of RFC 4592 and DNAME
+    /// substitution from RFC 6672:
+    ///
+    /// - names at or below a delegation get a referral to the child zone's
+    ///   nameservers along with their glue
+    /// - names below a DNAME get the DNAME and a CNAME made up from it
+    /// - names that don't exist are answered from a wildcard at their closest
+    ///   encloser if there is one, and with NXDOMAIN otherwise
+    /// - names without the type asked for, including empty non-terminals, get an
+    ///   empty answer
+    ///
+    /// Negative answers carry the SOA in the authority section.
+    pub fn lookup(&self, qname: &DomainName, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let mut name = qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let mut wildcard = false;
+            let node = match self.walk(&name) {
+                Walk::Found(node) => node,
+
+                //Below a zone cut the data belongs to the child zone, all we can do
+                //is point to its servers. We're no authority for that.
+                Walk::Cut(cut) => {
+                    packet.header.authoritative_answer = !packet.answers.is_empty();
+                    self.add_referral(&mut packet, &cut);
+                    return packet;
+                }
+
+                //The owner of the DNAME is swapped for its target, and the client gets
+                //told with a CNAME that has the same ttl as the DNAME
+                Walk::Dname(dname) => {
+                    let (owner, target, ttl) = match dname {
+                        DnsRecord::Dname { domain, host, ttl } => (domain, host, *ttl),
+                        _ => return packet,
+                    };
+
+                    packet.answers.push(dname.clone());
+                    let prefix: Vec<&str> = name.labels().take(name.label_count() - owner.label_count()).collect();
+                    let substituted = DomainName::new(&format!("{}.{}", prefix.join("."), target.as_str()));
+                    if substituted.as_str().len() + 2 > MAX_NAME_LENGTH {
+                        packet.header.rescode = ResultCode::YXDomain;
+                        return packet;
+                    }
+
+                    packet.answers.push(DnsRecord::Cname {
+                        domain: name.clone(),
+                        host: substituted.clone(),
+                        ttl,
+                    });
+                    if !substituted.is_subdomain_of(&self.origin) {
+                        return packet;
+                    }
+                    name = substituted;
+                    continue;
+                }
+
+                //A wildcard directly below the closest encloser stands in for every
+                //name that doesn't exist there. It doesn't match names that do exist,
+                //since those never get here, nor anything below them.
+                Walk::Missing(encloser) => match encloser.children.get("*") {
+                    Some(node) => {
+                        wildcard = true;
+                        node
+                    }
+                    None => {
+                        packet.header.rescode = ResultCode::NXDomain;
+                        self.add_soa(&mut packet);
+                        return packet;
+                    }
+                },
+            };
+
+            //Records taken from a wildcard are handed out as if they were the name's
+            //own, the others keep the owner name the way the zone has it
+            let owned_by = |rec: &DnsRecord| {
+                let mut rec = rec.clone();
+                if wildcard {
+                    rec.set_domain(name.clone());
+                }
+                rec
+            };
+
+            let matching: Vec<DnsRecord> = node.rrset(qtype).iter().map(owned_by).collect();
+            if !matching.is_empty() {
+                packet.answers.extend(matching);
+                return packet;
+            }
+
+            //An alias is followed for as long as it stays in the zone, past that
+            //it's up to the client to resolve the rest
+            match node.records.iter().find(|rec| rec.qtype() == QueryType::Cname) {
+                Some(cname @ DnsRecord::Cname { host, .. }) if qtype != QueryType::Cname => {
+                    packet.answers.push(owned_by(cname));
+                    if !host.is_subdomain_of(&self.origin) {
+                        return packet;
+                    }
+                    name = host.clone();
+                }
+                _ => {
+                    self.add_soa(&mut packet);
+                    return packet;
+                }
+            }
+        }
+
+        packet
+    }
+
+    fn add_referral(&self, packet: &mut DnsPacket, cut: &DomainName) {
+        let nameservers = self.rrset(cut, QueryType::NS);
+
+        for ns in &nameservers {
+            if let DnsRecord::NS { host, .. } = ns {
+                packet.resources.extend(self.addresses(host));
+            }
+        }
+        packet.authorities.extend(nameservers);
+    }
+
+    //Negative answers carry the SOA, with the ttl negative caching should use (RFC 2308)
+    fn add_soa(&self, packet: &mut DnsPacket) {
+        if let Some(soa) = self.soa() {
+            let mut soa = soa.clone();
+            if let DnsRecord::Soa { minimum, ttl, .. } = soa {
+                soa.set_ttl(ttl.min(minimum));
+            }
+            packet.authorities.push(soa);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::zonefile;
+
+    //The zone of RFC 4592 section 2.2.1, with DNAMEs added for RFC 6672
+    const ZONE: &str = r#"
+$ORIGIN example.
+$TTL 3600
+example.                 SOA   ns.example.com. hostmaster.example. 1 7200 3600 1209600 300
+example.                 NS    ns.example.com.
+example.                 NS    ns.example.net.
+*.example.               TXT   "this is a wildcard"
+*.example.               MX    10 host1.example.
+sub.*.example.           TXT   "this is not a wildcard"
+host1.example.           A     192.0.2.1
+_ssh._tcp.host1.example. SRV   0 0 22 host1.example.
+_ssh._tcp.host2.example. SRV   0 0 22 host2.example.
+subdel.example.          NS    ns.example.com.
+subdel.example.          NS    ns.example.net.
+dn.example.              DNAME example.net.
+loc.example.             DNAME host1.example.
+"#;
+
+    fn zone() -> Zone {
+        zonefile::parse(&DomainName::new("example"), ZONE).unwrap()
+    }
+
+    fn lookup(qname: &str, qtype: QueryType) -> DnsPacket {
+        zone().lookup(&DomainName::new(qname), qtype)
+    }
+
+    fn assert_nodata(packet: &DnsPacket) {
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities.len(), 1);
+        assert_eq!(packet.authorities[0].qtype(), QueryType::Soa);
+    }
+
+    fn assert_nxdomain(packet: &DnsPacket) {
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].qtype(), QueryType::Soa);
+    }
+
+    #[test]
+    fn names_in_the_zone_get_their_records() {
+        let packet = lookup("host1.example", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(
+            packet.answers,
+            [DnsRecord::A {
+                domain: DomainName::new("host1.example"),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 3600,
+            }]
+        );
+        assert!(packet.authorities.is_empty());
+
+        let packet = lookup("example", QueryType::NS);
+        assert_eq!(packet.answers.len(), 2);
+        assert!(packet.header.authoritative_answer);
+    }
+
+    #[test]
+    fn missing_names_are_nxdomain_with_the_negative_ttl() {
+        let origin = DomainName::new("example.org");
+        let zone = zonefile::parse(&origin, "$TTL 3600\n@ SOA ns hostmaster 1 7200 3600 1209600 300\n@ NS ns\nns A 192.0.2.1\n").unwrap();
+
+        let packet = zone.lookup(&DomainName::new("nope.example.org"), QueryType::A);
+        assert_nxdomain(&packet);
+        assert!(packet.header.authoritative_answer);
+        //The lower of the SOA's ttl and its minimum
+        assert_eq!(packet.authorities[0].ttl(), 300);
+    }
+
+    #[test]
+    fn referrals_carry_the_glue_we_have() {
+        let mut zone = zone();
+        zone.add(DnsRecord::NS {
+            domain: DomainName::new("subdel.example"),
+            host: DomainName::new("ns.subdel.example"),
+            ttl: 3600,
+        })
+        .unwrap();
+        zone.add(DnsRecord::A {
+            domain: DomainName::new("ns.subdel.example"),
+            addr: "192.0.2.53".parse().unwrap(),
+            ttl: 3600,
+        })
+        .unwrap();
+
+        for qname in ["subdel.example", "www.subdel.example"] {
+            let packet = zone.lookup(&DomainName::new(qname), QueryType::A);
+            assert_eq!(packet.header.rescode, ResultCode::NoError);
+            assert!(!packet.header.authoritative_answer);
+            assert!(packet.answers.is_empty());
+            assert_eq!(packet.authorities.len(), 3);
+            //Only the nameserver inside the zone has an address here
+            assert_eq!(packet.resources.len(), 1);
+            assert_eq!(packet.resources[0].domain(), &DomainName::new("ns.subdel.example"));
+        }
+    }
+
+    #[test]
+    fn wildcards_answer_for_names_that_dont_exist() {
+        let packet = lookup("host3.example", QueryType::MX);
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].qtype(), QueryType::MX);
+        assert_eq!(packet.answers[0].domain(), &DomainName::new("host3.example"));
+
+        let packet = lookup("foo.bar.example", QueryType::Txt);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].domain(), &DomainName::new("foo.bar.example"));
+
+        //The wildcard exists, but has no A record
+        assert_nodata(&lookup("host3.example", QueryType::A));
+    }
+
+    #[test]
+    fn wildcards_dont_answer_for_names_that_exist() {
+        assert_nodata(&lookup("host1.example", QueryType::MX));
+        assert_nodata(&lookup("sub.*.example", QueryType::MX));
+    }
+
+    #[test]
+    fn wildcards_only_apply_right_below_the_closest_encloser() {
+        //_tcp.host1.example exists and has no wildcard below it
+        assert_nxdomain(&lookup("_telnet._tcp.host1.example", QueryType::Srv));
+        //*.example is the closest encloser, and it's no wildcard for the names below it
+        assert_nxdomain(&lookup("ghost.*.example", QueryType::MX));
+    }
+
+    #[test]
+    fn names_below_a_delegation_get_a_referral() {
+        let packet = lookup("host.subdel.example", QueryType::A);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities.len(), 2);
+        assert!(packet.authorities.iter().all(|rec| rec.qtype() == QueryType::NS));
+    }
+
+    #[test]
+    fn empty_non_terminals_are_nodata() {
+        assert_nodata(&lookup("_tcp.host1.example", QueryType::A));
+        assert_nodata(&lookup("host2.example", QueryType::A));
+        assert_nodata(&lookup("_tcp.host2.example", QueryType::Srv));
+    }
+
+    #[test]
+    fn dname_substitutes_names_below_it() {
+        //The target is out of the zone, the client takes it from there
+        let packet = lookup("www.dn.example", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[0].qtype(), QueryType::Dname);
+        match &packet.answers[1] {
+            DnsRecord::Cname { domain, host, ttl } => {
+                assert_eq!(domain, &DomainName::new("www.dn.example"));
+                assert_eq!(host, &DomainName::new("www.example.net"));
+                assert_eq!(*ttl, 3600);
+            }
+            rec => panic!("Expected a CNAME, got {:?}", rec),
+        }
+
+        //Inside the zone it's followed to the answer
+        let packet = lookup("_ssh._tcp.loc.example", QueryType::Srv);
+        let types: Vec<QueryType> = packet.answers.iter().map(|rec| rec.qtype()).collect();
+        assert_eq!(types, [QueryType::Dname, QueryType::Cname, QueryType::Srv]);
+        assert_eq!(packet.answers[2].domain(), &DomainName::new("_ssh._tcp.host1.example"));
+
+        //and to a name that doesn't exist there
+        let packet = lookup("www.loc.example", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert_eq!(packet.answers.len(), 2);
+    }
+
+    #[test]
+    fn dname_owner_itself_isnt_substituted() {
+        let packet = lookup("dn.example", QueryType::Dname);
+        assert_eq!(packet.answers.len(), 1);
+        assert_nodata(&lookup("dn.example", QueryType::A));
+    }
+
+    #[test]
+    fn dname_substitution_past_the_longest_name_is_yxdomain() {
+        let mut zone = zone();
+        let long = format!("{}.example", ["a".repeat(63), "b".repeat(63), "c".repeat(63)].join("."));
+        zone.add(DnsRecord::Dname {
+            domain: DomainName::new("x.example"),
+            host: DomainName::new(&long),
+            ttl: 3600,
+        })
+        .unwrap();
+
+        let qname = DomainName::new(&format!("{}.x.example", "d".repeat(60)));
+        assert_eq!(zone.lookup(&qname, QueryType::A).header.rescode, ResultCode::YXDomain);
+    }
+}
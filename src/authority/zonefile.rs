@@ -0,0 +1,478 @@
+use std::error::Error;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::authority::zone::Zone;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+
+/// Reads a zone from a file in the master file format of RFC 1035 (section 5)
+pub fn load(origin: &DomainName, path: &Path) -> Result<Zone, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Can't read zone file {}: {}", path.display(), e))?;
+    parse(origin, &text).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Parses a zone in master file format. Supported are `$ORIGIN` and `$TTL`,
+/// comments, records spread over several lines with parentheses, `@` and
+/// relative names, blank owners repeating the previous one, and the record
+/// types we know how to send: A, AAAA, NS, CNAME, DNAME, SOA, PTR, MX, SRV, TXT
+/// and NSEC.
+pub fn parse(origin: &DomainName, text: &str) -> Result<Zone, Box<dyn Error>> {
+    let mut zone = Zone::new(origin.clone());
+    for (line_no, rec) in parse_records(origin, text)? {
+        zone.add(rec).map_err(|e| format!("line {}: {}", line_no, e))?;
+    }
+
+    zone.validate()?;
+    Ok(zone)
+}
+
+/// Parses the records of a master file in the order they're written, each with
+/// the line it starts on
+pub fn parse_records(origin: &DomainName, text: &str) -> Result<Vec<(usize, DnsRecord)>, Box<dyn Error>> {
+    let mut parser = Parser {
+        origin: origin.clone(),
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+    };
+
+    let mut records = Vec::new();
+    for (line_no, line) in logical_lines(text)? {
+        let rec = parser.parse_line(&line).map_err(|e| format!("line {}: {}", line_no, e))?;
+        records.extend(rec.map(|rec| (line_no, rec)));
+    }
+    Ok(records)
+}
+
+/// Writes the zone to `path` in master file format, for zones whose latest
+/// version only we have, after dynamic updates. Comments and the layout of the
+/// file that was there are lost.
+pub fn save(zone: &Zone, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut text = format!("; Zone {}, as changed by dynamic updates\n", zone.origin);
+    for rec in zone.records() {
+        text.push_str(&format(&rec)?);
+        text.push('\n');
+    }
+    write_file(path, &text)
+}
+
+/// Writes the file in one go, so a crash halfway leaves the old one in place
+pub fn write_file(path: &Path, text: &str) -> Result<(), Box<dyn Error>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, text).map_err(|e| format!("Can't write {}: {}", tmp.display(), e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Writes a record as a line of a master file, with absolute names so it reads
+/// back the same under any origin. Only the types `parse` knows are supported.
+pub fn format(rec: &DnsRecord) -> Result<String, Box<dyn Error>> {
+    let data = match rec {
+        DnsRecord::A { addr, .. } => format!("A {}", addr),
+        DnsRecord::Aaaa { addr, .. } => format!("AAAA {}", addr),
+        DnsRecord::NS { host, .. } => format!("NS {}", absolute(host)),
+        DnsRecord::Cname { host, .. } => format!("CNAME {}", absolute(host)),
+        DnsRecord::Dname { host, .. } => format!("DNAME {}", absolute(host)),
+        DnsRecord::Ptr { host, .. } => format!("PTR {}", absolute(host)),
+        DnsRecord::MX { priority, host, .. } => format!("MX {} {}", priority, absolute(host)),
+        DnsRecord::Srv {
+            priority,
+            weight,
+            port,
+            host,
+            ..
+        } => format!("SRV {} {} {} {}", priority, weight, port, absolute(host)),
+        DnsRecord::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ..
+        } => format!(
+            "SOA {} {} {} {} {} {} {}",
+            absolute(mname),
+            absolute(rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum
+        ),
+        DnsRecord::Txt { text, .. } => format!("TXT \"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")),
+        DnsRecord::Nsec { next, types, .. } => {
+            let mut data = format!("NSEC {}", absolute(next));
+            for qtype in types {
+                data.push_str(&format!(" {}", QueryType::from_num(*qtype)));
+            }
+            data
+        }
+        _ => return Err(format!("Can't write {:?} to a master file", rec).into()),
+    };
+
+    Ok(format!("{} {} IN {}", absolute(rec.domain()), rec.ttl(), data))
+}
+
+fn absolute(name: &DomainName) -> String {
+    if name.is_root() {
+        ".".to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+struct Parser {
+    origin: DomainName,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<DomainName>,
+}
+
+impl Parser {
+    fn parse_line(&mut self, line: &str) -> Result<Option<DnsRecord>, Box<dyn Error>> {
+        let blank_owner = line.starts_with(char::is_whitespace);
+        let tokens = tokenize(line)?;
+        let mut tokens = tokens.iter().map(String::as_str);
+
+        let first = match tokens.next() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+
+        match first {
+            "$ORIGIN" => {
+                self.origin = self.name(next(&mut tokens, "origin")?);
+                return Ok(None);
+            }
+            "$TTL" => {
+                self.default_ttl = Some(parse_ttl(next(&mut tokens, "ttl")?)?);
+                return Ok(None);
+            }
+            _ if first.starts_with('$') => return Err(format!("Unsupported directive {}", first).into()),
+            _ => {}
+        }
+
+        //The owner is left out when the line starts with whitespace, in which case
+        //the first token is already the ttl, class or type
+        let mut tokens: Vec<&str> = if blank_owner {
+            std::iter::once(first).chain(tokens).collect()
+        } else {
+            self.last_owner = Some(self.name(first));
+            tokens.collect()
+        };
+        let owner = self.last_owner.clone().ok_or("Record without an owner name")?;
+
+        //The ttl and the class may come in either order, and both are optional
+        let mut ttl = None;
+        while let Some(token) = tokens.first() {
+            if token.eq_ignore_ascii_case("IN") {
+                tokens.remove(0);
+            } else if ttl.is_none() && token.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(token)?);
+                tokens.remove(0);
+            } else {
+                break;
+            }
+        }
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("No ttl given and no $TTL set")?;
+        self.last_ttl = Some(ttl);
+
+        let mut tokens = tokens.into_iter();
+        let rtype = next(&mut tokens, "record type")?.to_ascii_uppercase();
+        let domain = owner;
+
+        let rec = match rtype.as_str() {
+            "A" => DnsRecord::A {
+                domain,
+                addr: next(&mut tokens, "address")?
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| "Invalid IPv4 address")?,
+                ttl,
+            },
+            "AAAA" => DnsRecord::Aaaa {
+                domain,
+                addr: next(&mut tokens, "address")?
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| "Invalid IPv6 address")?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: self.name(next(&mut tokens, "nameserver")?),
+                ttl,
+            },
+            "CNAME" => DnsRecord::Cname {
+                domain,
+                host: self.name(next(&mut tokens, "target")?),
+                ttl,
+            },
+            "DNAME" => DnsRecord::Dname {
+                domain,
+                host: self.name(next(&mut tokens, "target")?),
+                ttl,
+            },
+            "PTR" => DnsRecord::Ptr {
+                domain,
+                host: self.name(next(&mut tokens, "target")?),
+                ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: parse_u16(next(&mut tokens, "priority")?)?,
+                host: self.name(next(&mut tokens, "exchange")?),
+                ttl,
+            },
+            "SRV" => DnsRecord::Srv {
+                domain,
+                priority: parse_u16(next(&mut tokens, "priority")?)?,
+                weight: parse_u16(next(&mut tokens, "weight")?)?,
+                port: parse_u16(next(&mut tokens, "port")?)?,
+                host: self.name(next(&mut tokens, "target")?),
+                ttl,
+            },
+            "SOA" => DnsRecord::Soa {
+                domain,
+                mname: self.name(next(&mut tokens, "primary nameserver")?),
+                rname: self.name(next(&mut tokens, "responsible mailbox")?),
+                serial: next(&mut tokens, "serial")?
+                    .parse::<u32>()
+                    .map_err(|_| "Invalid SOA serial")?,
+                refresh: parse_ttl(next(&mut tokens, "refresh")?)?,
+                retry: parse_ttl(next(&mut tokens, "retry")?)?,
+                expire: parse_ttl(next(&mut tokens, "expire")?)?,
+                minimum: parse_ttl(next(&mut tokens, "minimum")?)?,
+                ttl,
+            },
+            "TXT" => {
+                let strings: Vec<&str> = tokens.by_ref().collect();
+                if strings.is_empty() {
+                    return Err("TXT record without text".into());
+                }
+                DnsRecord::Txt {
+                    domain,
+                    text: strings.join(" "),
+                    ttl,
+                }
+            }
+            "NSEC" => DnsRecord::Nsec {
+                domain,
+                next: self.name(next(&mut tokens, "next name")?),
+                types: tokens
+                    .by_ref()
+                    .map(|token| token.parse::<QueryType>().map(QueryType::to_num))
+                    .collect::<Result<_, _>>()?,
+                ttl,
+            },
+            _ => return Err(format!("Unsupported record type {}", rtype).into()),
+        };
+
+        if let Some(extra) = tokens.next() {
+            return Err(format!("Unexpected {:?} after the {} record", extra, rtype).into());
+        }
+
+        Ok(Some(rec))
+    }
+
+    //Names not ending in a dot are relative to the current origin
+    fn name(&self, s: &str) -> DomainName {
+        if s == "@" {
+            self.origin.clone()
+        } else if s.ends_with('.') || self.origin.is_root() {
+            DomainName::new(s)
+        } else {
+            DomainName::new(&format!("{}.{}", s, self.origin.as_str()))
+        }
+    }
+}
+
+fn next<'a>(tokens: &mut impl Iterator<Item = &'a str>, what: &str) -> Result<&'a str, Box<dyn Error>> {
+    tokens.next().ok_or_else(|| format!("Missing {}", what).into())
+}
+
+fn parse_u16(s: &str) -> Result<u16, Box<dyn Error>> {
+    s.parse::<u16>().map_err(|_| format!("Invalid number {:?}", s).into())
+}
+
+//A number of seconds, optionally written with BIND's units like `1h30m` or `2d`
+fn parse_ttl(s: &str) -> Result<u32, Box<dyn Error>> {
+    let invalid = || format!("Invalid time value {:?}", s);
+    if let Ok(secs) = s.parse::<u32>() {
+        return Ok(secs);
+    }
+
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(invalid().into()),
+        };
+        let value = number.parse::<u32>().map_err(|_| invalid())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return Err(invalid().into());
+    }
+    Ok(total)
+}
+
+//Joins the lines of the file into whole records, dropping comments and blank
+//lines. Every record comes with the line number it starts on.
+fn logical_lines(text: &str) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    let mut depth = 0;
+
+    for (i, raw) in text.lines().enumerate() {
+        let mut line = String::new();
+        let mut quoted = false;
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                //An escaped character is taken as it is, even a quote
+                '\\' if quoted => {
+                    line.push(c);
+                    line.extend(chars.next());
+                    continue;
+                }
+                '"' => quoted = !quoted,
+                ';' if !quoted => break,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => {
+                    if depth == 0 {
+                        return Err(format!("line {}: Unbalanced parenthesis", i + 1).into());
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            line.push(c);
+        }
+
+        if current.is_empty() {
+            start = i + 1;
+            current = line;
+        } else {
+            current.push(' ');
+            current.push_str(&line);
+        }
+
+        if depth == 0 {
+            if !current.trim().is_empty() {
+                lines.push((start, std::mem::take(&mut current)));
+            }
+            current.clear();
+        }
+    }
+
+    if depth > 0 {
+        return Err(format!("line {}: Parenthesis never closed", start).into());
+    }
+    Ok(lines)
+}
+
+//Splits a record into its fields. Quoted strings stay together with the quotes
+//removed, and parentheses only separate fields.
+fn tokenize(line: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                quoted = false;
+                tokens.push(std::mem::take(&mut token));
+            }
+            '\\' if quoted => token.extend(chars.next()),
+            _ if quoted => token.push(c),
+            '"' => quoted = true,
+            _ if c.is_whitespace() || c == '(' || c == ')' => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("Unterminated quoted string".into());
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "$TTL 3600
+@ SOA ns hostmaster 1 3600 600 86400 300
+@ NS ns
+ns A 192.0.2.53
+";
+
+    fn txt(text: &str) -> DnsRecord {
+        DnsRecord::Txt {
+            domain: DomainName::new("txt.example.org"),
+            text: text.to_string(),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn escaped_quotes_dont_end_the_string() {
+        let origin = DomainName::new("example.org");
+        let text = format!("{}txt TXT \"6\\\" tall; then ( leave\" ; a comment\n", ZONE);
+        let zone = parse(&origin, &text).unwrap();
+        assert_eq!(zone.records_at(&DomainName::new("txt.example.org")), [txt("6\" tall; then ( leave")]);
+    }
+
+    #[test]
+    fn saved_zones_load_back_the_same() {
+        let origin = DomainName::new("example.org");
+        let mut zone = parse(&origin, ZONE).unwrap();
+        for text in ["quote \" then ; semicolon", "backslash \\ at the end \\", "\"; ( \\\""] {
+            zone.add(txt(text)).unwrap();
+        }
+
+        let dir = std::env::temp_dir().join(format!("dns-server-zonefile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.org.zone");
+        save(&zone, &path).unwrap();
+
+        let loaded = load(&origin, &path).unwrap();
+        assert_eq!(loaded.records(), zone.records());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
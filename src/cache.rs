@@ -0,0 +1,1092 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+
+//We only ever speak the IN class, but the key carries it anyway so that
+//records of different classes can never be mixed up in the cache.
+pub const CLASS_IN: u16 = 1;
+
+//Upstream servers sometimes hand out absurdly long TTLs, we never keep
+//anything around for longer than a day.
+const MAX_TTL: u32 = 86400;
+
+//RFC 2308 recommends not holding on to negative answers for more than a few hours
+const MAX_NEGATIVE_TTL: u32 = 10800;
+
+//An NXDOMAIN says the name has no records of any type, so it's filed under
+//QTYPE * (255) instead of the type that was asked for.
+const NXDOMAIN_QTYPE: QueryType = QueryType::Unknown(255);
+
+//How many CNAME hops we're willing to follow when answering from the cache
+const MAX_CNAME_CHAIN: usize = 8;
+
+//A full cache is cleared down to this percentage of its size in one go, so
+//that it isn't searched for something to evict on every single insert
+const EVICT_TO_PERCENT: usize = 90;
+
+/// Tunables for the cache, including serve-stale (RFC 8767) and prefetching.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    //Maximum number of entries kept in the cache
+    pub max_entries: usize,
+    //How long past its expiry an entry may still be served when upstreams
+    //can't be reached. Zero turns serve-stale off.
+    pub max_stale: Duration,
+    //The ttl put on stale records, RFC 8767 recommends 30 seconds
+    pub stale_ttl: u32,
+    //How long a client waits for a fresh answer before it's given stale data
+    pub client_response_timeout: Duration,
+    //Entries hit at least this often are refreshed in the background...
+    pub prefetch_min_hits: u64,
+    //...once less than this percentage of their original ttl is left
+    pub prefetch_percent: u32,
+    //Answer names that validated NSEC records prove don't exist without asking
+    //upstream (RFC 8198). We don't validate DNSSEC ourselves, so this takes the
+    //AD bit of our forwarders at its word, and only makes sense with validating
+    //forwarders on a path nobody can spoof. Off by default.
+    pub aggressive_nsec: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 10000,
+            max_stale: Duration::from_secs(86400),
+            stale_ttl: 30,
+            client_response_timeout: Duration::from_millis(1800),
+            prefetch_min_hits: 3,
+            prefetch_percent: 10,
+            aggressive_nsec: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DomainName,
+    pub qtype: QueryType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &DomainName, qtype: QueryType) -> CacheKey {
+        CacheKey {
+            name: name.clone(),
+            qtype,
+            class: CLASS_IN,
+        }
+    }
+}
+
+//What we know about a name/type. Negative answers keep the SOA record
+//they were derived from, since it has to be sent along when they're served.
+#[derive(Clone)]
+enum CacheData {
+    Records(Vec<DnsRecord>),
+    NoData { soa: DnsRecord },
+    NxDomain { soa: DnsRecord },
+}
+
+//How far the records of an entry can be trusted, by the section of the
+//response they came from (RFC 2181, section 5.4.1). Glue from the additional
+//section only ever helps us reach a nameserver and is never handed to clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Trust {
+    Additional,
+    Authority,
+    Answer,
+}
+
+//A single entry together with the absolute point in time it stops being valid.
+//The ttl's inside the records are the ones we received, the remaining ttl is
+//always derived from `expires_at` when the entry is served.
+struct CacheEntry {
+    data: CacheData,
+    trust: Trust,
+    ttl: u32,
+    expires_at: Instant,
+    last_used: Instant,
+    hits: u64,
+    prefetching: bool,
+}
+
+impl CacheEntry {
+    fn remaining_ttl(&self, now: Instant) -> u32 {
+        self.expires_at.saturating_duration_since(now).as_secs() as u32
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+
+    //Past this point the entry is useless even as a stale answer
+    fn is_dead(&self, now: Instant, max_stale: Duration) -> bool {
+        self.expires_at + max_stale <= now
+    }
+
+    //A copy of the data with every ttl counted down to what is left.
+    //Stale entries are handed out with a fixed short ttl instead.
+    fn data_at(&self, now: Instant, stale_ttl: u32) -> CacheData {
+        let ttl = if self.is_expired(now) {
+            stale_ttl
+        } else {
+            self.remaining_ttl(now)
+        };
+        let with_ttl = |rec: &DnsRecord| {
+            let mut rec = rec.clone();
+            rec.set_ttl(ttl);
+            rec
+        };
+
+        match &self.data {
+            CacheData::Records(records) => CacheData::Records(records.iter().map(with_ttl).collect()),
+            CacheData::NoData { soa } => CacheData::NoData { soa: with_ttl(soa) },
+            CacheData::NxDomain { soa } => CacheData::NxDomain { soa: with_ttl(soa) },
+        }
+    }
+}
+
+//A validated NSEC record, kept by its owner name. The SOA of its zone comes
+//along since negative answers synthesised from it have to carry one.
+#[derive(Clone)]
+struct NsecEntry {
+    nsec: DnsRecord,
+    next: DomainName,
+    types: Vec<u16>,
+    zone: DomainName,
+    soa: DnsRecord,
+    expires_at: Instant,
+}
+
+impl NsecEntry {
+    fn has_type(&self, qtype: u16) -> bool {
+        self.types.contains(&qtype)
+    }
+
+    //Whether the name this NSEC is for has nothing that could answer `qtype`
+    fn lacks(&self, qtype: QueryType) -> bool {
+        !self.has_type(qtype.to_num()) && !self.has_type(QueryType::Cname.to_num())
+    }
+}
+
+//What an NSEC record has to say about a name
+enum NsecProof {
+    //The NSEC is for the name itself, so it exists with the listed types
+    Matches(NsecEntry),
+    //The name falls into the gap after the NSEC, so it doesn't exist
+    Covers(NsecEntry),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub stale_hits: u64,
+    pub prefetches: u64,
+    pub synthesised: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub expired: u64,
+    pub entries: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entries={} hits={} negative_hits={} stale_hits={} prefetches={} synthesised={} misses={} inserts={} evictions={} expired={}",
+            self.entries,
+            self.hits,
+            self.negative_hits,
+            self.stale_hits,
+            self.prefetches,
+            self.synthesised,
+            self.misses,
+            self.inserts,
+            self.evictions,
+            self.expired
+        )
+    }
+}
+
+/// An in-memory cache of RRsets keyed by name/type/class.
+///
+/// Besides answers, the cache also keeps the NS and glue records of every
+/// referral we see, which lets a lookup start at the closest known zone cut
+/// instead of going back to the root every time.
+///
+/// Expired entries are kept around for a while longer so that they can still be
+/// served when upstreams are unreachable, and popular entries that are about to
+/// expire are queued up for a background refresh.
+///
+/// With `aggressive_nsec` on, the NSEC records of responses our forwarders
+/// validated are kept in canonical order, which lets the cache answer for names
+/// in the gaps they cover (RFC 8198). The cache doesn't check any signatures,
+/// the AD bit of the forwarder is all it goes by. NSEC3 is out of scope: its
+/// gaps are between hashes, which takes computing the hash of every name looked
+/// up, so zones signed with it never get the shortcut.
+pub struct DnsCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    denials: BTreeMap<DomainName, NsecEntry>,
+    config: CacheConfig,
+    stats: CacheStats,
+    prefetch_queue: Vec<CacheKey>,
+}
+
+//Which entries a lookup is allowed to use
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Freshness {
+    Fresh,
+    AllowStale,
+}
+
+impl DnsCache {
+    pub fn new(config: CacheConfig) -> DnsCache {
+        DnsCache {
+            entries: HashMap::new(),
+            denials: BTreeMap::new(),
+            config,
+            stats: CacheStats::default(),
+            prefetch_queue: Vec::new(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    /// Answers `qname`/`qtype` from the cache, following any cached CNAME chain.
+    /// Negative answers come back as NXDOMAIN or an empty NOERROR with the SOA in
+    /// the authority section. Every ttl is counted down to the time left.
+    pub fn lookup(&mut self, qname: &DomainName, qtype: QueryType) -> Option<DnsPacket> {
+        let packet = self.answer(qname, qtype, Freshness::Fresh);
+        if packet.is_none() {
+            self.stats.misses += 1;
+        }
+        packet
+    }
+
+    /// Like `lookup`, but also uses entries that have expired less than
+    /// `max_stale` ago. Only meant as a last resort when resolution fails, which
+    /// is why it only counts as a stale hit once `served_stale` says so.
+    pub fn lookup_stale(&mut self, qname: &DomainName, qtype: QueryType) -> Option<DnsPacket> {
+        self.answer(qname, qtype, Freshness::AllowStale)
+    }
+
+    /// Counts a stale answer that was handed to a client
+    pub fn served_stale(&mut self) {
+        self.stats.stale_hits += 1;
+    }
+
+    /// Hands out the entries that have been queued for a background refresh
+    pub fn take_prefetches(&mut self) -> Vec<CacheKey> {
+        self.stats.prefetches += self.prefetch_queue.len() as u64;
+        std::mem::take(&mut self.prefetch_queue)
+    }
+
+    fn answer(&mut self, qname: &DomainName, qtype: QueryType, freshness: Freshness) -> Option<DnsPacket> {
+        let now = Instant::now();
+        let counted = freshness == Freshness::Fresh;
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new(qname.clone(), qtype));
+
+        let mut name = qname.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(CacheData::NxDomain { soa }) = self.get(&CacheKey::new(&name, NXDOMAIN_QTYPE), now, freshness, Trust::Authority) {
+                packet.header.rescode = ResultCode::NXDomain;
+                packet.authorities.push(soa);
+                if counted {
+                    self.stats.negative_hits += 1;
+                }
+                return Some(packet);
+            }
+
+            let key = CacheKey::new(&name, qtype);
+            match self.get(&key, now, freshness, Trust::Authority) {
+                Some(CacheData::Records(records)) => {
+                    packet.answers.extend(records);
+                    if counted {
+                        self.stats.hits += 1;
+                        self.check_prefetch(key, now);
+                    }
+                    return Some(packet);
+                }
+                Some(CacheData::NoData { soa }) => {
+                    packet.authorities.push(soa);
+                    if counted {
+                        self.stats.negative_hits += 1;
+                    }
+                    return Some(packet);
+                }
+                _ => {}
+            }
+
+            if qtype == QueryType::Cname {
+                return self.synthesise(packet, &name, qtype, now, freshness);
+            }
+
+            //No direct hit, but if the name is an alias we might still know
+            //the records of whatever it points to.
+            match self.get(&CacheKey::new(&name, QueryType::Cname), now, freshness, Trust::Authority) {
+                Some(CacheData::Records(records)) => {
+                    let target = match records.first() {
+                        Some(DnsRecord::Cname { host, .. }) => host.clone(),
+                        _ => break,
+                    };
+                    packet.answers.extend(records);
+                    name = target;
+                }
+                _ => return self.synthesise(packet, &name, qtype, now, freshness),
+            }
+        }
+
+        None
+    }
+
+    //Tries to answer `name`/`qtype` from the cached NSEC records alone: NODATA
+    //when the NSEC for the name lacks the type, and for names in an NSEC gap
+    //either an answer expanded from a cached wildcard, or NODATA/NXDOMAIN when
+    //NSEC records rule out the wildcard as well. Only fresh data is used.
+    fn synthesise(
+        &mut self,
+        mut packet: DnsPacket,
+        name: &DomainName,
+        qtype: QueryType,
+        now: Instant,
+        freshness: Freshness,
+    ) -> Option<DnsPacket> {
+        if !self.config.aggressive_nsec || freshness != Freshness::Fresh {
+            return None;
+        }
+
+        let gap = match self.find_nsec(name, now)? {
+            NsecProof::Matches(entry) if entry.lacks(qtype) => {
+                return self.denial(packet, ResultCode::NoError, vec![entry], now);
+            }
+            NsecProof::Matches(_) => return None,
+            NsecProof::Covers(entry) => entry,
+        };
+
+        //The name doesn't exist. Its closest encloser is the deepest ancestor it
+        //shares with the names on either side of the gap, and a wildcard right
+        //below that is the only thing that could still produce an answer.
+        let encloser = name
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| gap.nsec.domain().is_subdomain_of(ancestor) || gap.next.is_subdomain_of(ancestor))?;
+        let wildcard = DomainName::new(&format!("*.{}", encloser.as_str()));
+
+        if let Some(CacheData::Records(records)) = self.get(&CacheKey::new(&wildcard, qtype), now, freshness, Trust::Authority) {
+            for mut rec in records {
+                rec.set_domain(name.clone());
+                packet.answers.push(rec);
+            }
+            packet.authorities.push(gap.nsec);
+            self.stats.synthesised += 1;
+            return Some(packet);
+        }
+
+        match self.find_nsec(&wildcard, now)? {
+            NsecProof::Matches(entry) if entry.lacks(qtype) => {
+                self.denial(packet, ResultCode::NoError, vec![gap, entry], now)
+            }
+            NsecProof::Matches(_) => None,
+            NsecProof::Covers(entry) => self.denial(packet, ResultCode::NXDomain, vec![gap, entry], now),
+        }
+    }
+
+    //The fresh NSEC record that says something about `name`, if we have one
+    fn find_nsec(&self, name: &DomainName, now: Instant) -> Option<NsecProof> {
+        let (owner, entry) = self.denials.range::<DomainName, _>(..=name).next_back()?;
+
+        if entry.expires_at <= now || !name.is_subdomain_of(&entry.zone) {
+            return None;
+        }
+
+        if owner == name {
+            return Some(NsecProof::Matches(entry.clone()));
+        }
+
+        //An NSEC at a delegation or a DNAME says nothing about the names below it
+        let cut = entry.has_type(QueryType::NS.to_num()) && !entry.has_type(QueryType::Soa.to_num());
+        if name.is_subdomain_of(owner) && (cut || entry.has_type(QueryType::Dname.to_num())) {
+            return None;
+        }
+
+        //The last NSEC of a zone points back to the apex, and covers everything after it
+        if *name < entry.next || entry.next <= *owner {
+            Some(NsecProof::Covers(entry.clone()))
+        } else {
+            None
+        }
+    }
+
+    //Builds a synthesised negative answer out of the SOA and NSEC records that
+    //prove it, all with the ttl of whichever of them expires first
+    fn denial(&mut self, mut packet: DnsPacket, rescode: ResultCode, proofs: Vec<NsecEntry>, now: Instant) -> Option<DnsPacket> {
+        let ttl = proofs
+            .iter()
+            .map(|entry| entry.expires_at.saturating_duration_since(now).as_secs() as u32)
+            .min()?;
+
+        let mut soa = proofs.first()?.soa.clone();
+        soa.set_ttl(ttl);
+        packet.header.rescode = rescode;
+        packet.authorities.push(soa);
+
+        for entry in proofs {
+            let mut nsec = entry.nsec;
+            nsec.set_ttl(ttl);
+            if !packet.authorities.contains(&nsec) {
+                packet.authorities.push(nsec);
+            }
+        }
+
+        self.stats.synthesised += 1;
+        Some(packet)
+    }
+
+    //Counts a hit on the entry and queues it for a refresh if it's popular
+    //and close to expiring. Each entry is only queued once, until it's replaced.
+    fn check_prefetch(&mut self, key: CacheKey, now: Instant) {
+        let config = self.config;
+        let entry = match self.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        entry.hits += 1;
+        if entry.prefetching || entry.hits < config.prefetch_min_hits {
+            return;
+        }
+
+        if entry.remaining_ttl(now) as u64 * 100 < entry.ttl as u64 * config.prefetch_percent as u64 {
+            entry.prefetching = true;
+            self.prefetch_queue.push(key);
+        }
+    }
+
+    /// Stores every record of a response, grouped into RRsets. This includes the
+    /// NS records of the authority section and the glue in the additional section,
+    /// which are only trusted as far as their section goes. Glue is used to reach
+    /// nameservers but never served to clients.
+    pub fn store_packet(&mut self, packet: &DnsPacket) {
+        let mut rrsets: HashMap<CacheKey, (Trust, Vec<DnsRecord>)> = HashMap::new();
+
+        let records = packet
+            .answers
+            .iter()
+            .map(|rec| (Trust::Answer, rec))
+            .chain(packet.authorities.iter().map(|rec| (Trust::Authority, rec)))
+            .chain(packet.resources.iter().map(|rec| (Trust::Additional, rec)));
+
+        for (trust, rec) in records {
+            //We can't write unknown records back out, so there's no point keeping them,
+            //and an OPT record belongs to the message rather than to any name
+            if let DnsRecord::Unknown { .. } | DnsRecord::Opt { .. } = rec {
+                continue;
+            }
+
+            let key = CacheKey::new(rec.domain(), rec.qtype());
+            let (_, rrset) = rrsets.entry(key).or_insert((trust, Vec::new()));
+            if !rrset.contains(rec) {
+                rrset.push(rec.clone());
+            }
+        }
+
+        for (key, (trust, records)) in rrsets {
+            self.insert(key, records, trust);
+        }
+    }
+
+    /// Remembers a negative answer for `qname`/`qtype` (RFC 2308). An NXDOMAIN
+    /// covers every type of the name, an empty NOERROR (NODATA) only the type
+    /// asked for. Responses without an SOA in the authority section can't be
+    /// cached since they don't tell us for how long the answer holds.
+    pub fn store_negative(&mut self, qname: &DomainName, qtype: QueryType, response: &DnsPacket) {
+        let soa = match response.authorities.iter().find(|rec| rec.qtype() == QueryType::Soa) {
+            Some(soa) => soa.clone(),
+            None => return,
+        };
+
+        //The negative ttl is the lower of the SOA's own ttl and its minimum field
+        let ttl = match soa {
+            DnsRecord::Soa { ttl, minimum, .. } => ttl.min(minimum).min(MAX_NEGATIVE_TTL),
+            _ => return,
+        };
+
+        //If the answer section holds a CNAME chain, the negative answer is about
+        //the name at the end of the chain rather than the one we asked for
+        let mut name = qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let target = response.answers.iter().find_map(|rec| match rec {
+                DnsRecord::Cname { domain, host, .. } if *domain == name => Some(host.clone()),
+                _ => None,
+            });
+            match target {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+
+        let only_cnames = response.answers.iter().all(|rec| rec.qtype() == QueryType::Cname);
+        let (key, data) = match response.header.rescode {
+            ResultCode::NXDomain => (CacheKey::new(&name, NXDOMAIN_QTYPE), CacheData::NxDomain { soa }),
+            ResultCode::NoError if only_cnames && qtype != QueryType::Cname => {
+                (CacheKey::new(&name, qtype), CacheData::NoData { soa })
+            }
+            _ => return,
+        };
+
+        self.put(key, data, ttl, Trust::Answer);
+    }
+
+    /// Remembers the NSEC records of a response so that the names they prove
+    /// don't exist can be answered without asking again. Responses only get here
+    /// once they've been DNSSEC validated, the cache takes them at their word.
+    /// NSEC records without an SOA of their zone next to them are ignored.
+    pub fn store_validated_denials(&mut self, packet: &DnsPacket) {
+        if !self.config.aggressive_nsec {
+            return;
+        }
+
+        let soa = match packet.authorities.iter().find(|rec| rec.qtype() == QueryType::Soa) {
+            Some(soa) => soa.clone(),
+            None => return,
+        };
+
+        //Like any negative answer, an NSEC is good for no longer than the SOA says
+        let (zone, soa_ttl) = match soa {
+            DnsRecord::Soa {
+                ref domain,
+                ttl,
+                minimum,
+                ..
+            } => (domain.clone(), ttl.min(minimum)),
+            _ => return,
+        };
+
+        let now = Instant::now();
+        for rec in &packet.authorities {
+            let (owner, next, types, ttl) = match rec {
+                DnsRecord::Nsec {
+                    domain,
+                    next,
+                    types,
+                    ttl,
+                } if domain.is_subdomain_of(&zone) => (domain, next, types, *ttl),
+                _ => continue,
+            };
+
+            let ttl = ttl.min(soa_ttl).min(MAX_NEGATIVE_TTL);
+            if ttl == 0 {
+                continue;
+            }
+
+            if !self.denials.contains_key(owner) && self.denials.len() >= self.config.max_entries {
+                self.denials.retain(|_, entry| entry.expires_at > now);
+                if self.denials.len() >= self.config.max_entries {
+                    continue;
+                }
+            }
+
+            self.denials.insert(
+                owner.clone(),
+                NsecEntry {
+                    nsec: rec.clone(),
+                    next: next.clone(),
+                    types: types.clone(),
+                    zone: zone.clone(),
+                    soa: soa.clone(),
+                    expires_at: now + Duration::from_secs(ttl as u64),
+                },
+            );
+        }
+    }
+
+    //Stores a single RRset. The whole set expires together, using the lowest
+    //ttl among its records.
+    fn insert(&mut self, key: CacheKey, records: Vec<DnsRecord>, trust: Trust) {
+        let ttl = match records.iter().map(|rec| rec.ttl()).min() {
+            Some(ttl) => ttl.min(MAX_TTL),
+            None => return,
+        };
+
+        self.put(key, CacheData::Records(records), ttl, trust);
+    }
+
+    fn put(&mut self, key: CacheKey, data: CacheData, ttl: u32, trust: Trust) {
+        //A ttl of zero means the answer must not be cached at all
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        match self.entries.get(&key) {
+            //Glue doesn't get to replace an answer we still hold
+            Some(entry) if entry.trust > trust && !entry.is_expired(now) => return,
+            Some(_) => {}
+            None if self.entries.len() >= self.config.max_entries => self.make_room(now),
+            None => {}
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                data,
+                trust,
+                ttl,
+                expires_at: now + Duration::from_secs(ttl as u64),
+                last_used: now,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+        self.stats.inserts += 1;
+    }
+
+    /// Finds the deepest zone cut we have nameserver addresses for. Walks up from
+    /// `qname` towards the root and returns the zone and the addresses of all of
+    /// its nameservers we know, taken from the cached NS and glue records.
+    pub fn closest_nameservers(&mut self, qname: &DomainName) -> Option<(DomainName, Vec<Ipv4Addr>)> {
+        let now = Instant::now();
+
+        //Walk up through the parents of the name, stopping short of the root
+        for zone in qname.ancestors().take_while(|zone| !zone.is_root()) {
+            let hosts: Vec<DomainName> = self
+                .get_records(&CacheKey::new(&zone, QueryType::NS), now, Trust::Additional)
+                .into_iter()
+                .filter_map(|rec| match rec {
+                    DnsRecord::NS { host, .. } => Some(host),
+                    _ => None,
+                })
+                .collect();
+
+            let mut addrs = Vec::new();
+            for host in hosts {
+                addrs.extend(
+                    self.get_records(&CacheKey::new(&host, QueryType::A), now, Trust::Additional)
+                        .into_iter()
+                        .filter_map(|rec| match rec {
+                            DnsRecord::A { addr, .. } => Some(addr),
+                            _ => None,
+                        }),
+                );
+            }
+
+            if !addrs.is_empty() {
+                return Some((zone, addrs));
+            }
+        }
+
+        None
+    }
+
+    /// The fresh A and AAAA records of `host`, without counting it as a lookup.
+    /// These go to clients, so glue is left out.
+    pub fn addresses(&mut self, host: &DomainName) -> Vec<DnsRecord> {
+        let now = Instant::now();
+        let mut addrs = self.get_records(&CacheKey::new(host, QueryType::A), now, Trust::Authority);
+        addrs.extend(self.get_records(&CacheKey::new(host, QueryType::Aaaa), now, Trust::Authority));
+        addrs
+    }
+
+    //Fetches an entry without touching the hit/miss counters, if it's trusted
+    //at least as far as `trust`. Entries that are too old even to be served
+    //stale are dropped on the way.
+    fn get(&mut self, key: &CacheKey, now: Instant, freshness: Freshness, trust: Trust) -> Option<CacheData> {
+        let entry = self.entries.get_mut(key)?;
+
+        if entry.is_dead(now, self.config.max_stale) {
+            self.entries.remove(key);
+            self.stats.expired += 1;
+            return None;
+        }
+
+        if entry.is_expired(now) && freshness == Freshness::Fresh || entry.trust < trust {
+            return None;
+        }
+
+        entry.last_used = now;
+        Some(entry.data_at(now, self.config.stale_ttl))
+    }
+
+    //Like `get`, but only interested in fresh positive RRsets
+    fn get_records(&mut self, key: &CacheKey, now: Instant, trust: Trust) -> Vec<DnsRecord> {
+        match self.get(key, now, Freshness::Fresh, trust) {
+            Some(CacheData::Records(records)) => records,
+            _ => Vec::new(),
+        }
+    }
+
+    //Called when the cache is full. Entries past the stale window go first, and
+    //if that didn't free enough, the least recently used entries are evicted,
+    //preferring ones that have already expired, until the cache is down to
+    //EVICT_TO_PERCENT of its size.
+    fn make_room(&mut self, now: Instant) {
+        let max_stale = self.config.max_stale;
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_dead(now, max_stale));
+        self.stats.expired += (before - self.entries.len()) as u64;
+
+        let target = self.config.max_entries * EVICT_TO_PERCENT / 100;
+        if self.entries.len() <= target {
+            return;
+        }
+
+        let excess = self.entries.len() - target;
+        let mut candidates: Vec<(bool, Instant, &CacheKey)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (!entry.is_expired(now), entry.last_used, key))
+            .collect();
+        candidates.select_nth_unstable_by_key(excess - 1, |&(fresh, last_used, _)| (fresh, last_used));
+        let evicted: Vec<CacheKey> = candidates[..excess].iter().map(|&(_, _, key)| key.clone()).collect();
+
+        for key in evicted {
+            self.entries.remove(&key);
+        }
+        self.stats.evictions += excess as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        DomainName::new(s)
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::Nsec {
+            domain: name(owner),
+            next: name(next),
+            types: types.iter().map(|qtype| qtype.to_num()).collect(),
+            ttl: 3600,
+        }
+    }
+
+    fn soa() -> DnsRecord {
+        DnsRecord::Soa {
+            domain: name("example.org"),
+            mname: name("ns.example.org"),
+            rname: name("hostmaster.example.org"),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    //Synthesised answers carry the NSEC records proving them, with their ttl counted down
+    fn has_nsec(packet: &DnsPacket, owner: &str) -> bool {
+        packet
+            .authorities
+            .iter()
+            .any(|rec| rec.qtype() == QueryType::Nsec && rec.domain() == &name(owner))
+    }
+
+    //A cache that has seen the validated NSEC chain of example.org:
+    //example.org -> *.example.org -> a.example.org -> sub.example.org (a delegation) -> example.org
+    fn cache(aggressive_nsec: bool) -> DnsCache {
+        let mut cache = DnsCache::new(CacheConfig {
+            aggressive_nsec,
+            ..CacheConfig::default()
+        });
+        let mut packet = DnsPacket::new();
+        packet.header.authed_data = true;
+        packet.authorities = vec![
+            soa(),
+            nsec("example.org", "*.example.org", &[QueryType::Soa, QueryType::NS, QueryType::Nsec]),
+            nsec("*.example.org", "a.example.org", &[QueryType::A, QueryType::Nsec]),
+            nsec("a.example.org", "sub.example.org", &[QueryType::A, QueryType::Nsec]),
+            nsec("sub.example.org", "example.org", &[QueryType::NS, QueryType::Nsec]),
+        ];
+        cache.store_validated_denials(&packet);
+        cache
+    }
+
+    #[test]
+    fn nsec_of_the_name_proves_nodata() {
+        let mut cache = cache(true);
+        let packet = cache.lookup(&name("a.example.org"), QueryType::MX).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities[0].qtype(), QueryType::Soa);
+        assert!(has_nsec(&packet, "a.example.org"));
+
+        //It has an A record, which isn't something to deny
+        assert!(cache.lookup(&name("a.example.org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn covered_names_are_nxdomain_once_the_wildcard_is_ruled_out() {
+        let mut cache = cache(true);
+        //a.example.org is the closest encloser, and the same NSEC covers *.a.example.org
+        let packet = cache.lookup(&name("b.a.example.org"), QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert!(has_nsec(&packet, "a.example.org"));
+
+        //Below the apex, *.example.org exists and only has an A record
+        let packet = cache.lookup(&name("b.example.org"), QueryType::MX).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert!(packet.answers.is_empty());
+        assert!(has_nsec(&packet, "*.example.org"));
+        assert_eq!(cache.stats().synthesised, 2);
+    }
+
+    #[test]
+    fn cached_wildcards_are_expanded() {
+        let mut cache = cache(true);
+        let wildcard = DnsRecord::A {
+            domain: name("*.example.org"),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 3600,
+        };
+        cache.insert(CacheKey::new(&name("*.example.org"), QueryType::A), vec![wildcard], Trust::Answer);
+
+        let packet = cache.lookup(&name("b.example.org"), QueryType::A).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].domain(), &name("b.example.org"));
+        assert!(has_nsec(&packet, "a.example.org"));
+    }
+
+    #[test]
+    fn nxdomain_when_no_wildcard_exists() {
+        let mut cache = DnsCache::new(CacheConfig {
+            aggressive_nsec: true,
+            ..CacheConfig::default()
+        });
+        let mut packet = DnsPacket::new();
+        packet.authorities = vec![
+            soa(),
+            nsec("example.org", "a.example.org", &[QueryType::Soa, QueryType::NS, QueryType::Nsec]),
+            nsec("a.example.org", "example.org", &[QueryType::A, QueryType::Nsec]),
+        ];
+        cache.store_validated_denials(&packet);
+
+        let packet = cache.lookup(&name("b.example.org"), QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert_eq!(packet.authorities[0].qtype(), QueryType::Soa);
+    }
+
+    #[test]
+    fn delegations_say_nothing_about_the_names_below_them() {
+        let mut cache = cache(true);
+        assert!(cache.lookup(&name("www.sub.example.org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn nothing_is_synthesised_when_turned_off_or_without_an_soa() {
+        let mut cache = cache(false);
+        assert!(cache.lookup(&name("a.example.org"), QueryType::MX).is_none());
+
+        let mut cache = DnsCache::new(CacheConfig {
+            aggressive_nsec: true,
+            ..CacheConfig::default()
+        });
+        let mut packet = DnsPacket::new();
+        packet.authorities = vec![nsec("a.example.org", "sub.example.org", &[QueryType::A, QueryType::Nsec])];
+        cache.store_validated_denials(&packet);
+        assert!(cache.lookup(&name("a.example.org"), QueryType::MX).is_none());
+    }
+
+    #[test]
+    fn off_by_default() {
+        assert!(!CacheConfig::default().aggressive_nsec);
+    }
+
+    fn a(owner: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(owner),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 3600,
+        }
+    }
+
+    //A referral to example.org's nameserver, with its address as glue
+    fn referral(glue: u8) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(DnsRecord::NS {
+            domain: name("example.org"),
+            host: name("ns.example.org"),
+            ttl: 3600,
+        });
+        packet.resources.push(a("ns.example.org", glue));
+        packet
+    }
+
+    //Moves the expiry of an entry `secs` seconds into the past
+    fn age(cache: &mut DnsCache, owner: &str, qtype: QueryType, secs: u64) {
+        let entry = cache.entries.get_mut(&CacheKey::new(&name(owner), qtype)).unwrap();
+        entry.expires_at = entry.expires_at.checked_sub(Duration::from_secs(secs)).unwrap();
+    }
+
+    //A negative answer about `qname`, with an SOA of the given ttl and minimum
+    fn negative(rescode: ResultCode, ttl: u32, minimum: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        let mut soa = soa();
+        if let DnsRecord::Soa { ttl: ref mut soa_ttl, minimum: ref mut soa_minimum, .. } = soa {
+            *soa_ttl = ttl;
+            *soa_minimum = minimum;
+        }
+        packet.authorities.push(soa);
+        packet
+    }
+
+    #[test]
+    fn negative_answers_last_the_lower_of_the_soa_ttl_and_minimum() {
+        let mut cache = DnsCache::new(CacheConfig::default());
+        let nope = name("nope.example.org");
+
+        //The minimum is lower, and an NXDOMAIN holds for every type of the name
+        cache.store_negative(&nope, QueryType::A, &negative(ResultCode::NXDomain, 3600, 300));
+        let packet = cache.lookup(&nope, QueryType::Aaaa).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert!((299..=300).contains(&packet.authorities[0].ttl()));
+        age(&mut cache, "nope.example.org", NXDOMAIN_QTYPE, 300);
+        assert!(cache.lookup(&nope, QueryType::A).is_none());
+
+        //The SOA's own ttl is lower, and NODATA only holds for the type asked for
+        cache.store_negative(&nope, QueryType::A, &negative(ResultCode::NoError, 60, 300));
+        let packet = cache.lookup(&nope, QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert!(packet.answers.is_empty());
+        assert!((59..=60).contains(&packet.authorities[0].ttl()));
+        assert!(cache.lookup(&nope, QueryType::Aaaa).is_none());
+
+        //Neither holds for longer than three hours
+        cache.store_negative(&nope, QueryType::MX, &negative(ResultCode::NoError, 86400, 86400));
+        let ttl = cache.lookup(&nope, QueryType::MX).unwrap().authorities[0].ttl();
+        assert!((MAX_NEGATIVE_TTL - 1..=MAX_NEGATIVE_TTL).contains(&ttl));
+
+        //Without an SOA there's no telling for how long
+        let mut packet = negative(ResultCode::NXDomain, 3600, 300);
+        packet.authorities.clear();
+        cache.store_negative(&name("gone.example.org"), QueryType::A, &packet);
+        assert!(cache.lookup(&name("gone.example.org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn stale_entries_are_served_within_max_stale_only() {
+        let mut cache = DnsCache::new(CacheConfig {
+            max_stale: Duration::from_secs(600),
+            ..CacheConfig::default()
+        });
+        cache.insert(CacheKey::new(&name("www.example.org"), QueryType::A), vec![a("www.example.org", 1)], Trust::Answer);
+
+        //Expired a minute ago
+        age(&mut cache, "www.example.org", QueryType::A, 3660);
+        assert!(cache.lookup(&name("www.example.org"), QueryType::A).is_none());
+        let packet = cache.lookup_stale(&name("www.example.org"), QueryType::A).unwrap();
+        assert_eq!(packet.answers[0].ttl(), CacheConfig::default().stale_ttl);
+        //Only counted once it's actually handed out
+        assert_eq!(cache.stats().stale_hits, 0);
+        cache.served_stale();
+        assert_eq!(cache.stats().stale_hits, 1);
+
+        //Past the stale window it's gone for good
+        age(&mut cache, "www.example.org", QueryType::A, 600);
+        assert!(cache.lookup_stale(&name("www.example.org"), QueryType::A).is_none());
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().expired, 1);
+    }
+
+    #[test]
+    fn no_stale_entries_without_a_stale_window() {
+        let mut cache = DnsCache::new(CacheConfig {
+            max_stale: Duration::ZERO,
+            ..CacheConfig::default()
+        });
+        cache.insert(CacheKey::new(&name("www.example.org"), QueryType::A), vec![a("www.example.org", 1)], Trust::Answer);
+        age(&mut cache, "www.example.org", QueryType::A, 3601);
+        assert!(cache.lookup_stale(&name("www.example.org"), QueryType::A).is_none());
+    }
+
+    #[test]
+    fn full_caches_evict_in_batches_least_recently_used_first() {
+        let mut cache = DnsCache::new(CacheConfig {
+            max_entries: 100,
+            ..CacheConfig::default()
+        });
+        for i in 0..100 {
+            let owner = format!("host{}.example.org", i);
+            cache.insert(CacheKey::new(&name(&owner), QueryType::A), vec![a(&owner, 1)], Trust::Answer);
+        }
+        //The first ten are the oldest, but get used again
+        for i in 0..10 {
+            assert!(cache.lookup(&name(&format!("host{}.example.org", i)), QueryType::A).is_some());
+        }
+
+        cache.insert(CacheKey::new(&name("new.example.org"), QueryType::A), vec![a("new.example.org", 1)], Trust::Answer);
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 10);
+        assert_eq!(stats.entries, 91);
+        for i in 0..10 {
+            assert!(cache.lookup(&name(&format!("host{}.example.org", i)), QueryType::A).is_some());
+        }
+        for i in 10..20 {
+            assert!(cache.lookup(&name(&format!("host{}.example.org", i)), QueryType::A).is_none());
+        }
+
+        //There's room again, the next inserts evict nothing
+        for i in 0..9 {
+            let owner = format!("more{}.example.org", i);
+            cache.insert(CacheKey::new(&name(&owner), QueryType::A), vec![a(&owner, 1)], Trust::Answer);
+        }
+        assert_eq!(cache.stats().evictions, 10);
+        assert_eq!(cache.stats().entries, 100);
+    }
+
+    #[test]
+    fn glue_reaches_nameservers_but_not_clients() {
+        let mut cache = DnsCache::new(CacheConfig::default());
+        cache.store_packet(&referral(1));
+
+        assert!(cache.lookup(&name("ns.example.org"), QueryType::A).is_none());
+        assert!(cache.addresses(&name("ns.example.org")).is_empty());
+        assert_eq!(
+            cache.closest_nameservers(&name("www.example.org")),
+            Some((name("example.org"), vec![Ipv4Addr::new(192, 0, 2, 1)]))
+        );
+
+        //An answer for the name takes the place of its glue...
+        let mut answer = DnsPacket::new();
+        answer.answers.push(a("ns.example.org", 2));
+        cache.store_packet(&answer);
+        let served = |cache: &mut DnsCache| -> Vec<Ipv4Addr> {
+            let packet = cache.lookup(&name("ns.example.org"), QueryType::A).unwrap();
+            packet
+                .answers
+                .iter()
+                .filter_map(|rec| match rec {
+                    DnsRecord::A { addr, .. } => Some(*addr),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(served(&mut cache), [Ipv4Addr::new(192, 0, 2, 2)]);
+
+        //...and glue of a later referral doesn't replace the answer
+        cache.store_packet(&referral(3));
+        assert_eq!(served(&mut cache), [Ipv4Addr::new(192, 0, 2, 2)]);
+        assert_eq!(cache.addresses(&name("ns.example.org")).len(), 1);
+    }
+}
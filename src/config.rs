@@ -0,0 +1,642 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::acl::{self, Network};
+use crate::authority::{Allow, Authority};
+use crate::dns64::Dns64Config;
+use crate::forwarder::{parse_upstream, ForwardStrategy, ForwardZone, Forwarder};
+use crate::protocol::domainname::DomainName;
+use crate::resolver::{ResolutionMode, ResolverConfig};
+use crate::tsig::Key;
+
+//The port we listen on when the configuration doesn't name one
+const DEFAULT_PORT: u16 = 2053;
+
+/// How the server itself runs, as opposed to where its answers come from
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    //Where we take queries, each address over both UDP and TCP
+    pub listen: Vec<SocketAddr>,
+    //Number of threads resolving queries at the same time
+    pub workers: usize,
+    //Queries waiting for a free worker. Anything arriving while the queue is
+    //full gets dropped, the client will retry, and we don't pile up work we can't do.
+    pub max_queued_queries: usize,
+    //TCP connections served at the same time, each gets a thread of its own
+    pub max_tcp_connections: usize,
+    //A TCP connection that stays quiet this long gets closed
+    pub tcp_idle_timeout: Duration,
+    //Leave the additional section to what the answer itself brings along,
+    //instead of adding the addresses of the hosts it names
+    pub minimal_responses: bool,
+    //Print every query and the records of its answer
+    pub log_queries: bool,
+    //Print the cache statistics every this many queries, never if zero
+    pub cache_stats_interval: u64,
+    //Clients we answer at all, anyone else gets REFUSED
+    pub allow_query: Vec<Network>,
+    //Clients we resolve names outside our zones for. Only this machine by
+    //default, anything wider is an open resolver for whoever can reach us.
+    pub allow_recursion: Vec<Network>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))],
+            workers: 16,
+            max_queued_queries: 256,
+            max_tcp_connections: 32,
+            tcp_idle_timeout: Duration::from_secs(10),
+            minimal_responses: false,
+            log_queries: true,
+            cache_stats_interval: 100,
+            allow_query: acl::anyone(),
+            allow_recursion: acl::localhost(),
+        }
+    }
+}
+
+/// Everything the configuration file sets up. The command line adds to it.
+#[derive(Default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub resolver: ResolverConfig,
+    pub authority: Authority,
+    //Where the journals of our zones go, only opened once all zones are known
+    pub journal_dir: Option<PathBuf>,
+}
+
+//The file as it's written. Every setting is optional and defaults to what the
+//server does without a configuration file, unknown ones are an error so typos
+//don't go unnoticed.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    journal_dir: Option<PathBuf>,
+    server: ServerSection,
+    resolver: ResolverSection,
+    cache: CacheSection,
+    dns64: Option<Dns64Section>,
+    keys: Vec<KeySection>,
+    zones: Vec<ZoneSection>,
+    log: LogSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    listen: Option<Vec<String>>,
+    workers: Option<usize>,
+    max_queued_queries: Option<usize>,
+    max_tcp_connections: Option<usize>,
+    tcp_idle_timeout_secs: Option<u64>,
+    minimal_responses: Option<bool>,
+    allow_query: Option<Vec<String>>,
+    allow_recursion: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ResolverSection {
+    mode: Option<String>,
+    root_servers: Option<Vec<Ipv4Addr>>,
+    upstreams: Vec<String>,
+    forward_strategy: Option<String>,
+    upstream_timeout_ms: Option<u64>,
+    max_waiters_per_query: Option<usize>,
+    qname_minimisation: Option<String>,
+    forward_zones: Vec<ForwardZoneSection>,
+    limits: LimitsSection,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardZoneSection {
+    zone: String,
+    upstreams: Vec<String>,
+    #[serde(default)]
+    fallback: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_referrals: Option<usize>,
+    max_depth: Option<usize>,
+    max_ns_fetches: Option<usize>,
+    max_queries: Option<usize>,
+    max_time_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSection {
+    max_entries: Option<usize>,
+    max_stale_secs: Option<u64>,
+    stale_ttl: Option<u32>,
+    client_response_timeout_ms: Option<u64>,
+    prefetch_min_hits: Option<u64>,
+    prefetch_percent: Option<u32>,
+    aggressive_nsec: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Dns64Section {
+    prefix: Option<String>,
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeySection {
+    name: String,
+    algorithm: String,
+    secret: String,
+    //Servers whose messages to and from us get signed with this key
+    #[serde(default)]
+    servers: Vec<IpAddr>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneSection {
+    origin: String,
+    //A primary zone comes from a master file...
+    file: Option<PathBuf>,
+    //...a secondary one from its primary
+    primary: Option<String>,
+    #[serde(default)]
+    notify: Vec<String>,
+    #[serde(default)]
+    allow_transfer: Vec<String>,
+    #[serde(default)]
+    allow_update: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    queries: Option<bool>,
+    cache_stats_interval: Option<u64>,
+}
+
+/// Reads the configuration file at `path` and checks it. Errors say which
+/// setting is wrong, and where in the file when it doesn't parse at all.
+/// Relative paths in the file are relative to the directory it's in.
+pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let file: File = toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    file.build(base)
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+impl File {
+    fn build(self, base: &Path) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config {
+            server: self.server.build()?,
+            resolver: self.resolver.build()?,
+            authority: Authority::new(),
+            journal_dir: self.journal_dir.map(|dir| base.join(dir)),
+        };
+
+        if let Some(queries) = self.log.queries {
+            config.server.log_queries = queries;
+        }
+        if let Some(interval) = self.log.cache_stats_interval {
+            config.server.cache_stats_interval = interval;
+        }
+
+        self.cache.apply(&mut config.resolver)?;
+        //Only forwarders set the AD bit we go by, the recursive mode never asks for DNSSEC
+        let forwards = matches!(config.resolver.mode, ResolutionMode::Forwarding(_))
+            || !config.resolver.forward_zones.is_empty();
+        if config.resolver.cache.aggressive_nsec && !forwards {
+            return Err("cache.aggressive_nsec needs validating forwarders to trust, there are none".into());
+        }
+        if let Some(dns64) = self.dns64 {
+            config.resolver.dns64 = Some(dns64.build()?);
+        }
+
+        //Keys come first, the zones' allow lists may name them
+        for key in self.keys {
+            key.add_to(&mut config.authority)?;
+        }
+
+        let mut origins = HashSet::new();
+        for zone in &self.zones {
+            let origin = DomainName::new(&zone.origin);
+            if !origins.insert(origin.clone()) {
+                return Err(format!("Zone {} is configured twice", origin).into());
+            }
+            zone.add_to(&mut config.authority, &origin, base)
+                .map_err(|e| format!("Zone {}: {}", origin, e))?;
+        }
+
+        if let ResolutionMode::Authoritative = config.resolver.mode {
+            if self.zones.is_empty() {
+                return Err("The authoritative mode needs at least one zone to serve".into());
+            }
+            if !config.resolver.forward_zones.is_empty() || config.resolver.dns64.is_some() {
+                return Err("Forward zones and DNS64 need a resolver, the authoritative mode has none".into());
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl ServerSection {
+    fn build(self) -> Result<ServerConfig, Box<dyn Error>> {
+        let mut server = ServerConfig::default();
+
+        if let Some(listen) = self.listen {
+            if listen.is_empty() {
+                return Err("server.listen needs at least one address".into());
+            }
+            server.listen = listen
+                .iter()
+                .map(|addr| parse_listen(addr))
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+        if let Some(workers) = self.workers {
+            server.workers = at_least_one("server.workers", workers)?;
+        }
+        if let Some(queued) = self.max_queued_queries {
+            server.max_queued_queries = at_least_one("server.max_queued_queries", queued)?;
+        }
+        if let Some(connections) = self.max_tcp_connections {
+            server.max_tcp_connections = at_least_one("server.max_tcp_connections", connections)?;
+        }
+        if let Some(secs) = self.tcp_idle_timeout_secs {
+            server.tcp_idle_timeout = Duration::from_secs(at_least_one("server.tcp_idle_timeout_secs", secs)?);
+        }
+        if let Some(minimal) = self.minimal_responses {
+            server.minimal_responses = minimal;
+        }
+        if let Some(clients) = self.allow_query {
+            if clients.is_empty() {
+                return Err("server.allow_query needs at least one network, or nobody gets an answer".into());
+            }
+            server.allow_query = parse_networks(&clients)?;
+        }
+        //Empty is fine here, that's recursion turned off for everyone
+        if let Some(clients) = self.allow_recursion {
+            server.allow_recursion = parse_networks(&clients)?;
+        }
+
+        Ok(server)
+    }
+}
+
+impl ResolverSection {
+    fn build(self) -> Result<ResolverConfig, Box<dyn Error>> {
+        let mut config = ResolverConfig::default();
+
+        let upstreams = self
+            .upstreams
+            .iter()
+            .map(|upstream| parse_upstream(upstream))
+            .collect::<Result<Vec<_>, _>>()?;
+        let strategy = match self.forward_strategy {
+            Some(strategy) => strategy.parse()?,
+            None => ForwardStrategy::Sequential,
+        };
+
+        //Without a mode, upstreams mean forwarding just like on the command line
+        let default_mode = if upstreams.is_empty() {
+            "recursive"
+        } else {
+            "forwarding"
+        };
+        let mode = self.mode.as_deref().unwrap_or(default_mode);
+        config.mode = match mode {
+            "forwarding" if upstreams.is_empty() => {
+                return Err("The forwarding mode needs resolver.upstreams to forward to".into())
+            }
+            "forwarding" => ResolutionMode::Forwarding(Forwarder::new(upstreams, strategy)),
+            "recursive" | "authoritative" if !upstreams.is_empty() => {
+                return Err(format!("resolver.upstreams are only used in the forwarding mode, not {}", mode).into())
+            }
+            "recursive" => ResolutionMode::Recursive,
+            "authoritative" => ResolutionMode::Authoritative,
+            _ => {
+                return Err(format!(
+                    "Unknown mode {:?}, expected recursive, forwarding or authoritative",
+                    mode
+                )
+                .into())
+            }
+        };
+
+        if let Some(root_servers) = self.root_servers {
+            if root_servers.is_empty() {
+                return Err("resolver.root_servers needs at least one address".into());
+            }
+            config.root_servers = root_servers;
+        }
+        if let Some(ms) = self.upstream_timeout_ms {
+            config.upstream_timeout = Duration::from_millis(at_least_one("resolver.upstream_timeout_ms", ms)?);
+        }
+        if let Some(max) = self.max_waiters_per_query {
+            config.max_waiters_per_query = at_least_one("resolver.max_waiters_per_query", max)?;
+        }
+        if let Some(minimisation) = self.qname_minimisation {
+            config.qname_minimisation = minimisation.parse()?;
+        }
+
+        for rule in self.forward_zones {
+            if rule.upstreams.is_empty() {
+                return Err(format!("The forward zone {} needs at least one upstream", rule.zone).into());
+            }
+            let upstreams = rule
+                .upstreams
+                .iter()
+                .map(|upstream| parse_upstream(upstream))
+                .collect::<Result<Vec<_>, _>>()?;
+            config.forward_zones.push(ForwardZone {
+                zone: DomainName::new(&rule.zone),
+                forwarder: Forwarder::new(upstreams, ForwardStrategy::Sequential),
+                fallback: rule.fallback,
+            });
+        }
+
+        let limits = &mut config.limits;
+        let section = self.limits;
+        if let Some(max) = section.max_referrals {
+            limits.max_referrals = at_least_one("resolver.limits.max_referrals", max)?;
+        }
+        if let Some(max) = section.max_depth {
+            limits.max_depth = max;
+        }
+        if let Some(max) = section.max_ns_fetches {
+            limits.max_ns_fetches = max;
+        }
+        if let Some(max) = section.max_queries {
+            limits.max_queries = at_least_one("resolver.limits.max_queries", max)?;
+        }
+        if let Some(secs) = section.max_time_secs {
+            limits.max_time = Duration::from_secs(at_least_one("resolver.limits.max_time_secs", secs)?);
+        }
+
+        Ok(config)
+    }
+}
+
+impl CacheSection {
+    fn apply(self, config: &mut ResolverConfig) -> Result<(), Box<dyn Error>> {
+        let cache = &mut config.cache;
+        if let Some(max) = self.max_entries {
+            cache.max_entries = at_least_one("cache.max_entries", max)?;
+        }
+        if let Some(secs) = self.max_stale_secs {
+            cache.max_stale = Duration::from_secs(secs);
+        }
+        if let Some(ttl) = self.stale_ttl {
+            cache.stale_ttl = ttl;
+        }
+        if let Some(ms) = self.client_response_timeout_ms {
+            cache.client_response_timeout = Duration::from_millis(ms);
+        }
+        if let Some(hits) = self.prefetch_min_hits {
+            cache.prefetch_min_hits = hits;
+        }
+        if let Some(percent) = self.prefetch_percent {
+            if percent > 100 {
+                return Err(format!("cache.prefetch_percent is a percentage, {} is too much", percent).into());
+            }
+            cache.prefetch_percent = percent;
+        }
+        if let Some(aggressive) = self.aggressive_nsec {
+            cache.aggressive_nsec = aggressive;
+        }
+        Ok(())
+    }
+}
+
+impl Dns64Section {
+    fn build(self) -> Result<Dns64Config, Box<dyn Error>> {
+        let mut dns64 = Dns64Config::default();
+        if let Some(prefix) = self.prefix {
+            dns64.set_prefix(prefix.parse()?)?;
+        }
+        for prefix in self.exclude {
+            dns64.exclude.push(prefix.parse()?);
+        }
+        Ok(dns64)
+    }
+}
+
+impl KeySection {
+    fn add_to(self, authority: &mut Authority) -> Result<(), Box<dyn Error>> {
+        let key = Key::new(&self.name, &self.algorithm, &self.secret)?;
+        if authority.keyring().contains(&key.name) {
+            return Err(format!("TSIG key {} is configured twice", key.name).into());
+        }
+
+        let name = key.name.clone();
+        authority.add_key(key);
+        for server in self.servers {
+            authority.use_key(server, &name)?;
+        }
+        Ok(())
+    }
+}
+
+impl ZoneSection {
+    fn add_to(&self, authority: &mut Authority, origin: &DomainName, base: &Path) -> Result<(), Box<dyn Error>> {
+        match (&self.file, &self.primary) {
+            (Some(file), None) => authority.load_zone(origin.clone(), base.join(file))?,
+            (None, Some(primary)) => {
+                authority.add_secondary(origin.clone(), SocketAddr::from(parse_upstream(primary)?));
+            }
+            _ => return Err("Needs either a file or a primary, but not both".into()),
+        }
+
+        if !self.notify.is_empty() {
+            let secondaries = self
+                .notify
+                .iter()
+                .map(|secondary| parse_upstream(secondary).map(SocketAddr::from))
+                .collect::<Result<Vec<_>, _>>()?;
+            authority.also_notify(origin, secondaries)?;
+        }
+        if !self.allow_transfer.is_empty() {
+            authority.allow_transfer(origin, parse_allow(&self.allow_transfer)?)?;
+        }
+        if !self.allow_update.is_empty() {
+            authority.allow_update(origin, parse_allow(&self.allow_update)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an address to listen on, `<address>:<port>` or an address alone for
+/// the default port. IPv6 addresses with a port go in brackets.
+pub fn parse_listen(s: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|addr| SocketAddr::new(addr, DEFAULT_PORT)))
+        .map_err(|_| format!("Invalid listen address {:?}", s).into())
+}
+
+/// Parses networks of clients, like `192.0.2.0/24` or a single address
+pub fn parse_networks<S: AsRef<str>>(networks: &[S]) -> Result<Vec<Network>, Box<dyn Error>> {
+    networks.iter().map(|network| network.as_ref().parse::<Network>()).collect()
+}
+
+fn parse_allow(clients: &[String]) -> Result<Vec<Allow>, Box<dyn Error>> {
+    clients.iter().map(|client| client.parse::<Allow>()).collect()
+}
+
+//Zero would stop the server from doing anything at all
+fn at_least_one<T: PartialOrd + From<u8>>(setting: &str, value: T) -> Result<T, Box<dyn Error>> {
+    if value < T::from(1) {
+        return Err(format!("{} has to be at least 1", setting).into());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(text: &str) -> Result<Config, Box<dyn Error>> {
+        let file: File = toml::from_str(text)?;
+        file.build(Path::new(""))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = load(Path::new("config.example.toml")).unwrap();
+        assert_eq!(config.server.listen, vec![SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))]);
+        assert_eq!(config.server.allow_query, acl::anyone());
+        assert_eq!(config.server.allow_recursion, acl::localhost());
+    }
+
+    #[test]
+    fn recursion_is_for_localhost_by_default() {
+        let config = build("").unwrap();
+        assert!(matches!(config.resolver.mode, ResolutionMode::Recursive));
+        assert!(acl::admits(&config.server.allow_query, ip("192.0.2.1")));
+        assert!(acl::admits(&config.server.allow_recursion, ip("127.0.0.1")));
+        assert!(acl::admits(&config.server.allow_recursion, ip("::1")));
+        assert!(!acl::admits(&config.server.allow_recursion, ip("192.0.2.1")));
+        assert!(!acl::admits(&config.server.allow_recursion, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn client_networks() {
+        let config = build(
+            r#"
+            [server]
+            allow_query = ["192.0.2.0/24", "2001:db8::/32"]
+            allow_recursion = ["192.0.2.1"]
+            "#,
+        )
+        .unwrap();
+        assert!(acl::admits(&config.server.allow_query, ip("192.0.2.99")));
+        assert!(acl::admits(&config.server.allow_query, ip("2001:db8::53")));
+        assert!(!acl::admits(&config.server.allow_query, ip("198.51.100.1")));
+        assert!(acl::admits(&config.server.allow_recursion, ip("192.0.2.1")));
+        assert!(!acl::admits(&config.server.allow_recursion, ip("192.0.2.2")));
+        assert!(!acl::admits(&config.server.allow_recursion, ip("127.0.0.1")));
+
+        let config = build("[server]\nallow_recursion = []").unwrap();
+        assert!(config.server.allow_recursion.is_empty());
+    }
+
+    #[test]
+    fn invalid_client_networks() {
+        assert!(build("[server]\nallow_query = []").is_err());
+        assert!(build(r#"[server]
+            allow_query = ["192.0.2.0/33"]"#).is_err());
+        assert!(build(r#"[server]
+            allow_recursion = ["localhost"]"#).is_err());
+        assert!(build(r#"[server]
+            allow_recursion = "127.0.0.1""#).is_err());
+    }
+
+    #[test]
+    fn server_settings() {
+        let config = build(
+            r#"
+            [server]
+            listen = ["127.0.0.1", "[::1]:53"]
+            workers = 4
+            tcp_idle_timeout_secs = 3
+            minimal_responses = true
+
+            [log]
+            queries = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.server.listen,
+            vec![SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT)), "[::1]:53".parse().unwrap()]
+        );
+        assert_eq!(config.server.workers, 4);
+        assert_eq!(config.server.tcp_idle_timeout, Duration::from_secs(3));
+        assert!(config.server.minimal_responses);
+        assert!(!config.server.log_queries);
+
+        assert!(build("[server]\nlisten = []").is_err());
+        assert!(build("[server]\nworkers = 0").is_err());
+        assert!(build("[server]\nlisten = [\"nowhere\"]").is_err());
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        assert!(build("[server]\nallow_querys = [\"0.0.0.0/0\"]").is_err());
+        assert!(build("[nowhere]").is_err());
+    }
+
+    #[test]
+    fn modes_and_upstreams() {
+        let config = build("[resolver]\nupstreams = [\"192.0.2.53\"]").unwrap();
+        assert!(matches!(config.resolver.mode, ResolutionMode::Forwarding(_)));
+
+        assert!(build("[resolver]\nmode = \"forwarding\"").is_err());
+        assert!(build("[resolver]\nmode = \"recursive\"\nupstreams = [\"192.0.2.53\"]").is_err());
+        assert!(build("[resolver]\nmode = \"iterative\"").is_err());
+        //Nothing to serve
+        assert!(build("[resolver]\nmode = \"authoritative\"").is_err());
+    }
+
+    #[test]
+    fn waiters_per_query() {
+        assert_eq!(build("").unwrap().resolver.max_waiters_per_query, 100);
+        let config = build("[resolver]\nmax_waiters_per_query = 5").unwrap();
+        assert_eq!(config.resolver.max_waiters_per_query, 5);
+        assert!(build("[resolver]\nmax_waiters_per_query = 0").is_err());
+    }
+
+    #[test]
+    fn aggressive_nsec_needs_forwarders() {
+        assert!(build("[cache]\naggressive_nsec = true").is_err());
+        let config = build(
+            r#"
+            [resolver]
+            upstreams = ["192.0.2.53"]
+
+            [cache]
+            aggressive_nsec = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.resolver.cache.aggressive_nsec);
+    }
+}
@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::resolver::Resolver;
+
+//The ttl of the CNAME that points a synthesised ip6.arpa name at its in-addr.arpa
+//counterpart. The mapping never changes as long as the prefix doesn't.
+const PTR_CNAME_TTL: u32 = 3600;
+
+//The prefix lengths RFC 6052 defines a place for the IPv4 address for
+const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+//Bits 64 to 71 of an IPv6 address (the "u" octet) are reserved, an IPv4 address
+//embedded after a shorter prefix is split around them
+const RESERVED_OCTET: usize = 8;
+
+/// An IPv6 address prefix, written like `64:ff9b::/96`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv6Prefix {
+    pub addr: Ipv6Addr,
+    pub len: u8,
+}
+
+impl Ipv6Prefix {
+    fn mask(&self) -> u128 {
+        match self.len {
+            0 => 0,
+            len => u128::MAX << (128 - len as u32),
+        }
+    }
+
+    pub fn contains(&self, addr: &Ipv6Addr) -> bool {
+        u128::from(*addr) & self.mask() == u128::from(self.addr) & self.mask()
+    }
+}
+
+impl FromStr for Ipv6Prefix {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("IPv6 prefix {:?} should look like address/length", s))?;
+
+        let addr = addr
+            .parse::<Ipv6Addr>()
+            .map_err(|_| format!("Invalid address in IPv6 prefix {:?}", s))?;
+        let len = match len.parse::<u8>() {
+            Ok(len) if len <= 128 => len,
+            _ => return Err(format!("Invalid length in IPv6 prefix {:?}", s).into()),
+        };
+
+        Ok(Ipv6Prefix { addr, len })
+    }
+}
+
+/// Settings for DNS64 (RFC 6147), which hands IPv6-only clients AAAA records
+/// made up from A records, pointing at a NAT64 gateway
+#[derive(Clone, Debug)]
+pub struct Dns64Config {
+    //Where IPv4 addresses get embedded: right after the prefix, which is one of
+    //the lengths of RFC 6052 (/32, /40, /48, /56, /64 or /96)
+    pub prefix: Ipv6Prefix,
+    //AAAA records in these ranges count as missing, so the name gets a
+    //synthesised AAAA instead. IPv4-mapped addresses are always excluded.
+    pub exclude: Vec<Ipv6Prefix>,
+}
+
+impl Default for Dns64Config {
+    fn default() -> Self {
+        Dns64Config {
+            prefix: Ipv6Prefix {
+                addr: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+                len: 96,
+            },
+            exclude: vec![Ipv6Prefix {
+                addr: Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0),
+                len: 96,
+            }],
+        }
+    }
+}
+
+impl Dns64Config {
+    pub fn set_prefix(&mut self, prefix: Ipv6Prefix) -> Result<(), Box<dyn Error>> {
+        if !PREFIX_LENGTHS.contains(&prefix.len) {
+            return Err(format!(
+                "NAT64 prefixes are /32, /40, /48, /56, /64 or /96 long (RFC 6052), not /{}",
+                prefix.len
+            )
+            .into());
+        }
+
+        self.prefix = prefix;
+        Ok(())
+    }
+
+    //The octets of an IPv6 address holding the IPv4 address, in order (RFC 6052 section 2.2)
+    fn ipv4_octets(&self) -> impl Iterator<Item = usize> {
+        (self.prefix.len as usize / 8..).filter(|&i| i != RESERVED_OCTET).take(4)
+    }
+
+    fn embed(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = Ipv6Addr::from(u128::from(self.prefix.addr) & self.prefix.mask()).octets();
+        for (i, octet) in self.ipv4_octets().zip(addr.octets()) {
+            octets[i] = octet;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    fn extract(&self, addr: &Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = addr.octets();
+        if !self.prefix.contains(addr) || (self.prefix.len <= 64 && octets[RESERVED_OCTET] != 0) {
+            return None;
+        }
+
+        let mut ipv4 = [0; 4];
+        for (octet, i) in ipv4.iter_mut().zip(self.ipv4_octets()) {
+            *octet = octets[i];
+        }
+        Some(Ipv4Addr::from(ipv4))
+    }
+
+    fn is_excluded(&self, addr: &Ipv6Addr) -> bool {
+        self.exclude.iter().any(|prefix| prefix.contains(addr))
+    }
+
+    //For a full ip6.arpa name of an address under the prefix, the in-addr.arpa
+    //name of the IPv4 address embedded in it
+    fn ptr_target(&self, qname: &DomainName) -> Option<DomainName> {
+        let labels: Vec<&str> = qname.labels().collect();
+        if labels.len() != 34 || !labels[32].eq_ignore_ascii_case("ip6") || !labels[33].eq_ignore_ascii_case("arpa") {
+            return None;
+        }
+
+        //The nibbles come least significant first
+        let mut value: u128 = 0;
+        for label in labels[..32].iter().rev() {
+            if label.len() != 1 {
+                return None;
+            }
+            value = value << 4 | u8::from_str_radix(label, 16).ok()? as u128;
+        }
+
+        let [a, b, c, d] = self.extract(&Ipv6Addr::from(value))?.octets();
+        Some(DomainName::new(&format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)))
+    }
+}
+
+/// Answers a client query the DNS64 way. AAAA queries for names without (usable)
+/// AAAA records get AAAA records synthesised from their A records, and PTR queries
+/// for addresses under the NAT64 prefix are pointed at the IPv4 address' PTR
+/// record with a CNAME. Anything else is resolved as usual.
+pub fn resolve(
+    resolver: &Arc<Resolver>,
+    config: &Dns64Config,
+    qname: &DomainName,
+    qtype: QueryType,
+) -> Result<DnsPacket, Box<dyn Error>> {
+    match qtype {
+        QueryType::Aaaa => resolve_aaaa(resolver, config, qname),
+        QueryType::Ptr => match config.ptr_target(qname) {
+            Some(target) => resolve_ptr(resolver, qname, target),
+            None => resolver.resolve(qname, qtype),
+        },
+        _ => resolver.resolve(qname, qtype),
+    }
+}
+
+fn resolve_aaaa(resolver: &Arc<Resolver>, config: &Dns64Config, qname: &DomainName) -> Result<DnsPacket, Box<dyn Error>> {
+    let mut response = resolver.resolve(qname, QueryType::Aaaa)?;
+
+    //Only an empty answer leads to synthesis, an NXDOMAIN stands as it is
+    if response.header.rescode != ResultCode::NoError {
+        return Ok(response);
+    }
+
+    response
+        .answers
+        .retain(|rec| !matches!(rec, DnsRecord::Aaaa { addr, .. } if config.is_excluded(addr)));
+    if response.answers.iter().any(|rec| rec.qtype() == QueryType::Aaaa) {
+        return Ok(response);
+    }
+
+    let mut synthesised = resolver.resolve(qname, QueryType::A)?;
+    if synthesised.header.rescode != ResultCode::NoError || !synthesised.answers.iter().any(|rec| rec.qtype() == QueryType::A) {
+        return Ok(response);
+    }
+
+    //The made up records can't outlive the empty AAAA answer they stand in for
+    let negative_ttl = response.authorities.iter().find_map(|rec| match rec {
+        DnsRecord::Soa { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+        _ => None,
+    });
+
+    synthesised.answers = synthesised
+        .answers
+        .into_iter()
+        .map(|rec| match rec {
+            DnsRecord::A { domain, addr, ttl } => DnsRecord::Aaaa {
+                domain,
+                addr: config.embed(addr),
+                ttl: negative_ttl.map_or(ttl, |negative_ttl| ttl.min(negative_ttl)),
+            },
+            rec => rec,
+        })
+        .collect();
+
+    println!("Synthesised DNS64 answer for {}", qname);
+    Ok(synthesised)
+}
+
+fn resolve_ptr(resolver: &Arc<Resolver>, qname: &DomainName, target: DomainName) -> Result<DnsPacket, Box<dyn Error>> {
+    let mut response = resolver.resolve(&target, QueryType::Ptr)?;
+
+    response.answers.insert(
+        0,
+        DnsRecord::Cname {
+            domain: qname.clone(),
+            host: target,
+            ttl: PTR_CNAME_TTL,
+        },
+    );
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(prefix: &str) -> Dns64Config {
+        let mut config = Dns64Config::default();
+        config.set_prefix(prefix.parse().unwrap()).unwrap();
+        config
+    }
+
+    //The examples of RFC 6052 section 2.4
+    const EXAMPLES: [(&str, &str); 6] = [
+        ("2001:db8::/32", "2001:db8:c000:221::"),
+        ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+        ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+        ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+        ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+        ("2001:db8:122:344::/96", "2001:db8:122:344::c000:221"),
+    ];
+
+    #[test]
+    fn addresses_are_embedded_after_every_prefix_length() {
+        let ipv4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in EXAMPLES {
+            let config = config(prefix);
+            let expected: Ipv6Addr = expected.parse().unwrap();
+            assert_eq!(config.embed(ipv4), expected, "{}", prefix);
+            assert_eq!(config.extract(&expected), Some(ipv4), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn extraction_needs_the_prefix_and_a_zero_reserved_octet() {
+        let config = config("2001:db8:122:344::/64");
+        assert_eq!(config.extract(&"2001:db8:122:345:c0:2:2100:0".parse().unwrap()), None);
+        assert_eq!(config.extract(&"2001:db8:122:344:1c0:2:2100:0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn ptr_names_under_a_short_prefix_point_at_in_addr_arpa() {
+        let config = config("2001:db8::/32");
+        let qname = DomainName::new("0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.1.2.2.0.0.0.0.c.8.b.d.0.1.0.0.2.ip6.arpa");
+        assert_eq!(config.ptr_target(&qname), Some(DomainName::new("33.2.0.192.in-addr.arpa")));
+    }
+
+    #[test]
+    fn other_prefix_lengths_are_rejected() {
+        let mut config = Dns64Config::default();
+        assert!(config.set_prefix("2001:db8::/33".parse().unwrap()).is_err());
+        assert!(config.set_prefix("2001:db8::/128".parse().unwrap()).is_err());
+        assert_eq!(config.prefix.len, 96);
+    }
+}
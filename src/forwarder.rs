@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::protocol::domainname::DomainName;
+use crate::server_selection::ServerSelection;
+
+/// In which order the upstreams of a forwarder are tried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardStrategy {
+    //Always the first one, the next ones only when it fails
+    Sequential,
+    //Every query starts at the next upstream in the list
+    RoundRobin,
+    //The one with the lowest smoothed round trip time first
+    FastestFirst,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(ForwardStrategy::Sequential),
+            "round-robin" => Ok(ForwardStrategy::RoundRobin),
+            "fastest" => Ok(ForwardStrategy::FastestFirst),
+            _ => Err(format!("Unknown forwarding strategy {:?}, expected sequential, round-robin or fastest", s).into()),
+        }
+    }
+}
+
+/// A list of upstream resolvers queries get forwarded to, instead of being
+/// resolved from the root
+#[derive(Debug)]
+pub struct Forwarder {
+    pub upstreams: Vec<(Ipv4Addr, u16)>,
+    pub strategy: ForwardStrategy,
+    //Where the next round robin query starts
+    next: AtomicUsize,
+}
+
+impl Clone for Forwarder {
+    fn clone(&self) -> Self {
+        Forwarder::new(self.upstreams.clone(), self.strategy)
+    }
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<(Ipv4Addr, u16)>, strategy: ForwardStrategy) -> Forwarder {
+        Forwarder {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The upstreams in the order they should be tried for the next query
+    pub fn order(&self, servers: &ServerSelection) -> Vec<(Ipv4Addr, u16)> {
+        let mut upstreams = self.upstreams.clone();
+
+        match self.strategy {
+            ForwardStrategy::Sequential => {}
+            ForwardStrategy::RoundRobin => {
+                if !upstreams.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+                    upstreams.rotate_left(start);
+                }
+            }
+            ForwardStrategy::FastestFirst => {
+                upstreams.sort_by(|a, b| {
+                    servers
+                        .rank(&a.0)
+                        .partial_cmp(&servers.rank(&b.0))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+            }
+        }
+
+        upstreams
+    }
+}
+
+/// Parses an upstream given as `address` or `address:port`, the port defaults to 53
+pub fn parse_upstream(s: &str) -> Result<(Ipv4Addr, u16), Box<dyn Error>> {
+    let (addr, port) = match s.split_once(':') {
+        Some((addr, port)) => (addr, port.parse::<u16>().map_err(|_| format!("Invalid port in upstream {:?}", s))?),
+        None => (s, 53),
+    };
+
+    let addr = addr
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("Invalid upstream address {:?}", s))?;
+
+    Ok((addr, port))
+}
+
+/// A conditional forwarding rule: names in `zone` go to the rule's own upstreams
+/// instead of being resolved the normal way
+#[derive(Clone, Debug)]
+pub struct ForwardZone {
+    pub zone: DomainName,
+    pub forwarder: Forwarder,
+    //Whether to resolve the name recursively when none of the upstreams can
+    pub fallback: bool,
+}
+
+/// Picks the rule for `qname`. When several zones match, the longest one wins,
+/// so a rule for `eu.corp.internal` overrides one for `corp.internal`.
+pub fn find_forward_zone<'a>(zones: &'a [ForwardZone], qname: &DomainName) -> Option<&'a ForwardZone> {
+    zones
+        .iter()
+        .filter(|rule| qname.is_subdomain_of(&rule.zone))
+        .max_by_key(|rule| rule.zone.label_count())
+}
+
+/// Parses a rule given as `zone=upstream[,upstream...]`
+pub fn parse_forward_zone(s: &str, fallback: bool) -> Result<ForwardZone, Box<dyn Error>> {
+    let (zone, upstreams) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Forward zone {:?} should look like zone=upstream[,upstream...]", s))?;
+
+    let upstreams = upstreams
+        .split(',')
+        .map(parse_upstream)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ForwardZone {
+        zone: DomainName::new(zone),
+        forwarder: Forwarder::new(upstreams, ForwardStrategy::Sequential),
+        fallback,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(zone: &str, upstream: &str) -> ForwardZone {
+        parse_forward_zone(&format!("{}={}", zone, upstream), false).unwrap()
+    }
+
+    fn find<'a>(zones: &'a [ForwardZone], qname: &str) -> Option<&'a str> {
+        find_forward_zone(zones, &DomainName::new(qname)).map(|rule| rule.zone.as_str())
+    }
+
+    #[test]
+    fn the_longest_zone_wins() {
+        let zones = [rule("corp.internal", "10.0.0.53"), rule("eu.corp.internal", "10.1.0.53"), rule("internal", "10.2.0.53")];
+        assert_eq!(find(&zones, "www.eu.corp.internal"), Some("eu.corp.internal"));
+        assert_eq!(find(&zones, "eu.corp.internal"), Some("eu.corp.internal"));
+        assert_eq!(find(&zones, "www.us.corp.internal"), Some("corp.internal"));
+        assert_eq!(find(&zones, "WWW.Corp.Internal"), Some("corp.internal"));
+        assert_eq!(find(&zones, "other.internal"), Some("internal"));
+    }
+
+    #[test]
+    fn zones_match_whole_labels_only() {
+        let zones = [rule("corp.internal", "10.0.0.53")];
+        assert_eq!(find(&zones, "notcorp.internal"), None);
+        assert_eq!(find(&zones, "internal"), None);
+        assert_eq!(find(&zones, "example.org"), None);
+        assert_eq!(find(&[], "corp.internal"), None);
+    }
+
+    #[test]
+    fn forward_zones_from_the_command_line() {
+        let zone = parse_forward_zone("corp.internal=10.0.0.53,10.0.0.54:5353", true).unwrap();
+        assert_eq!(zone.zone, DomainName::new("corp.internal"));
+        assert_eq!(zone.forwarder.upstreams, [(Ipv4Addr::new(10, 0, 0, 53), 53), (Ipv4Addr::new(10, 0, 0, 54), 5353)]);
+        assert!(zone.fallback);
+
+        assert!(parse_forward_zone("corp.internal", false).is_err());
+        assert!(parse_forward_zone("corp.internal=", false).is_err());
+        assert!(parse_forward_zone("corp.internal=10.0.0.53:dns", false).is_err());
+    }
+}
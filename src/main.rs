@@ -1,69 +1,147 @@
 // use std::fs::File;
 // use std::io::Read;
-use std::net::UdpSocket;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
 // use std::result;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+mod acl;
+mod authority;
+mod cache;
+mod config;
+mod dns64;
+mod forwarder;
 mod protocol;
+mod resolver;
+mod scrub;
+mod server_selection;
+mod tcp;
+mod trace;
+mod tsig;
+mod udp;
 
 use protocol::byte_packet_buffer::BytePacketBuffer;
 use protocol::dnspacket::DnsPacket;
+use protocol::dnsrecord::DnsRecord;
+use protocol::opcode::Opcode;
+use protocol::domainname::DomainName;
 use protocol::querytype::QueryType;
-use protocol::dnsquestion::DnsQuestion;
 use protocol::resultcode::ResultCode;
-use std::net::Ipv4Addr;
+use authority::secondary;
+use authority::{reload, transfer, update, Allow, Authority};
+use config::{Config, ServerConfig};
+use dns64::Dns64Config;
+use forwarder::{parse_forward_zone, parse_upstream, ForwardStrategy, Forwarder};
+use resolver::{ResolutionMode, Resolver, ResolverConfig};
+use tsig::{Key, Signer, TsigError};
 
-fn lookup(qname : &str , qtype: QueryType , server : (Ipv4Addr , u16)) -> Result<DnsPacket ,  Box<dyn std::error::Error>> {
-    //Forward queries to Google's public DNS server
-    // let server = ("8.8.8.8",53);
+//Largest reply we send over UDP, the limit for clients without EDNS
+const UDP_MESSAGE_SIZE : usize = 512;
 
-    let socket = UdpSocket::bind(("0.0.0.0" , 43210))?;
+//A query waiting for a worker: the packet, where it came from and which of
+//our sockets it came in on
+type Query = (BytePacketBuffer, SocketAddr, usize);
 
-    let mut packet = DnsPacket::new();
-
-    packet.header.id = 6666;
-    packet.header.questions =1;
-    packet.header.recursion_desired = true;
-
-    packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+//Everything the workers share while answering queries
+struct Server {
+    //One for every address we listen on, replies go out through the socket
+    //the query came in on
+    sockets : Vec<UdpSocket>,
+    resolver : Arc<Resolver>,
+    authority : Arc<Authority>,
+    config : ServerConfig,
+    handled : AtomicU64,
+    tcp_connections : AtomicUsize,
+}
 
-    let mut req_buffer = BytePacketBuffer::new();
-    let _ = packet.write(&mut req_buffer);
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+//Handle a single incoming packet with this, `src` is where it came from and
+//where the reply goes
+fn handle_query(server : &Server, socket : &UdpSocket, mut req_buffer : BytePacketBuffer, src : SocketAddr) -> Result<() , Box<dyn std::error::Error>> {
+    //Next, we'll parse the packet into a `DnsPacket` struct
+    let request = DnsPacket::from_buffer(&mut req_buffer)?;
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf)?;
+    //A signed request needs a signature we can check, and gets a signed reply
+    let signature = match tsig::verify(server.authority.keyring(), &mut req_buffer) {
+        Ok(signature) => signature,
+        Err(e) => {
+            println!("Rejected a request from {}: {}", src, e);
+            let res_buffer = reject(&request, &e)?;
+            socket.send_to(res_buffer.get_range(0, res_buffer.pos())?, src)?;
+            return Ok(());
+        }
+    };
+    let key = signature.as_ref().map(|signature| signature.key.name.clone());
+    let mut packet = answer(server, request, &mut req_buffer, key.as_ref(), src.ip());
 
-    DnsPacket::from_buffer(&mut res_buffer)
-}
+    //Now we can just encode our response and send it back to the client
+    let mut signer = signature.map(Signer::answer);
+    let res_buffer = encode_signed(&mut packet, UDP_MESSAGE_SIZE, signer.as_mut())?;
 
-//Handle a single incoming packet with this
-fn handle_query(socket : &UdpSocket) -> Result<() , Box<dyn std::error::Error>> {
-    //With a socket ready, we can go ahead and read a packet.
-    //This will block until one is received
+    let len = res_buffer.pos();
+    let data = res_buffer.get_range(0, len)?;
 
-    let mut req_buffer = BytePacketBuffer::new();
+    socket.send_to(data, src)?;
 
-    //The `recv_from` function will write the data into the provided buffer,
-    //And return the length of the data read as well as the source address.
-    //We are essentially not interested in length , but we need to keep track of
-    //the source in order to send our reply later on.
+    Ok(())
+}
 
-    let (_ , src) = socket.recv_from(&mut req_buffer.buf)?;
+//Works out the reply to a query, whichever way it came in. `req_buffer` holds
+//the request as it came in, `key` is the TSIG key it was signed with.
+fn answer(server : &Server, mut request : DnsPacket, req_buffer : &mut BytePacketBuffer, key : Option<&DomainName>, client : IpAddr) -> DnsPacket {
+    //Clients outside allow_query get nothing at all, not even our own zones,
+    //and can't NOTIFY or UPDATE them either
+    if !acl::admits(&server.config.allow_query, client) {
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.opcode = request.header.opcode;
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::Refused;
+        packet.questions = request.questions;
+        return packet;
+    }
 
-    //Next, we'll parse the packet into a `DnsPacket` struct
-    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
+    match request.header.opcode {
+        Opcode::Query => {}
+        //A NOTIFY isn't a query, it tells us one of our secondary zones has changed
+        Opcode::Notify => return secondary::notify(&server.authority, &request, client, key),
+        Opcode::Update => return update::answer(&server.authority, req_buffer, client, key),
+        opcode => {
+            let mut packet = DnsPacket::new();
+            packet.header.id = request.header.id;
+            packet.header.opcode = opcode;
+            packet.header.response = true;
+            packet.header.rescode = ResultCode::NotImp;
+            return packet;
+        }
+    }
 
     //We'll create a new packet to hold our response
     let mut packet  = DnsPacket::new();
-    
+
+    //In the authoritative mode there's no resolver behind our zones, and
+    //clients outside allow_recursion only get those zones either
+    let recursive = !matches!(server.resolver.config.mode, ResolutionMode::Authoritative)
+        && acl::admits(&server.config.allow_recursion, client);
+
     packet.header.id = request.header.id;
     packet.header.response = true;
-    packet.header.recursion_available = true;
+    packet.header.recursion_available = recursive;
     packet.header.recursion_desired = true;
 
     //In the normal case, exactly one question is present
     if let Some(question) = request.questions.pop() {
-        println!("Received query: {:?}", question);
+        if server.config.log_queries {
+            println!("Received query: {:?}", question);
+        }
+
+        //Zone transfers only go over TCP, transfers over TCP never get here
+        if matches!(question.qtype, QueryType::Axfr | QueryType::Ixfr) {
+            request.questions.push(question);
+            return transfer::answer_udp(&server.authority, &request, client, key);
+        }
 
         //Since all is set up and as expectrd, the query can be forwarded to the 
         //target server. There's always the possibility that the query will
@@ -73,25 +151,58 @@ fn handle_query(socket : &UdpSocket) -> Result<() , Box<dyn std::error::Error>>
         // if let Ok(result) = lookup(&question.name, question.qtype) {
         //     packet.questions.push(question);
         //     packet.header.rescode = result.header.rescode;
-        if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
+        //Our own zones come first, only names outside of them are resolved
+        let local = server.authority.answer(&question.name, question.qtype);
+        let authoritative = local.is_some();
+        let result = match (local, &server.resolver.config.dns64) {
+            (Some(packet), _) => Ok(packet),
+            (None, _) if !recursive => {
+                packet.questions.push(question);
+                packet.header.rescode = ResultCode::Refused;
+                return packet;
+            }
+            (None, Some(dns64)) => dns64::resolve(&server.resolver, dns64, &question.name, question.qtype),
+            (None, None) => server.resolver.resolve(&question.name, question.qtype),
+        };
+
+        if let Ok(result) = result {
             packet.questions.push(question.clone());
             packet.header.rescode = result.header.rescode;
+            packet.header.authoritative_answer = authoritative && result.header.authoritative_answer;
 
+            //Clients don't get DNSSEC records unless they ask for them by type,
+            //and records we can't encode would only break the reply
+            let wanted = |rec : &DnsRecord| {
+                rec.qtype() == question.qtype || !matches!(rec, DnsRecord::Unknown { .. } | DnsRecord::Opt { .. } | DnsRecord::Nsec { .. })
+            };
+            let answers = result.answers.into_iter().filter(wanted);
+            let authorities = result.authorities.into_iter().filter(wanted);
+            let resources = result.resources.into_iter().filter(wanted);
 
-            for rec in result.answers {
-                println!("Answer: {:?}", rec);
+            for rec in answers {
+                if server.config.log_queries {
+                    println!("Answer: {:?}", rec);
+                }
                 packet.answers.push(rec);
             }
 
-            for rec in result.authorities {
-                println!("Authority: {:?}", rec);
+            for rec in authorities {
+                if server.config.log_queries {
+                    println!("Authority: {:?}", rec);
+                }
                 packet.authorities.push(rec);
             }
 
-            for rec in result.resources {
-                println!("Resource: {:?}", rec);
+            for rec in resources {
+                if server.config.log_queries {
+                    println!("Resource: {:?}", rec);
+                }
                 packet.resources.push(rec);
             }
+
+            if !server.config.minimal_responses {
+                add_additional(server, &mut packet);
+            }
         }
         else{
             packet.header.rescode =  ResultCode::ServFail;
@@ -103,88 +214,899 @@ fn handle_query(socket : &UdpSocket) -> Result<() , Box<dyn std::error::Error>>
         packet.header.rescode = ResultCode::FormErr;
     }
 
-    //Now we can just encode our response and send it back to the client
-    let mut res_buffer = BytePacketBuffer::new();
+    packet
+}
 
-    let _ = packet.write(&mut res_buffer);
+//Serves the queries coming in over a single TCP connection, one after another,
+//until the client is done. This is the only way to get a zone transfer.
+fn handle_tcp(server : &Server, mut stream : TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    let client = stream.peer_addr()?.ip();
+    stream.set_read_timeout(Some(server.config.tcp_idle_timeout))?;
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+    while let Some(mut req_buffer) = tcp::read_message(&mut stream)? {
+        let request = DnsPacket::from_buffer(&mut req_buffer)?;
 
-    socket.send_to(data, src)?;
+        let signature = match tsig::verify(server.authority.keyring(), &mut req_buffer) {
+            Ok(signature) => signature,
+            Err(e) => {
+                println!("Rejected a request from {}: {}", client, e);
+                tcp::write_message(&mut stream, &reject(&request, &e)?)?;
+                continue;
+            }
+        };
+        let key = signature.as_ref().map(|signature| signature.key.name.clone());
+
+        let replies = match request.questions.first() {
+            Some(question) if matches!(question.qtype, QueryType::Axfr | QueryType::Ixfr) => {
+                println!("Received transfer request: {:?} from {}", question, client);
+                transfer::answer(&server.authority, &request, client, key.as_ref())?
+            }
+            _ => vec![answer(server, request, &mut req_buffer, key.as_ref(), client)],
+        };
+
+        //The messages of a transfer are signed as one chain
+        let mut signer = signature.map(Signer::answer);
+        for mut reply in replies {
+            let res_buffer = encode_signed(&mut reply, tcp::MAX_MESSAGE_SIZE, signer.as_mut())?;
+            tcp::write_message(&mut stream, &res_buffer)?;
+        }
+    }
 
     Ok(())
 }
 
-//Implementing recursive lookup
-pub fn recursive_lookup(qname : &str, qtype : QueryType) -> Result <DnsPacket , Box<dyn std::error::Error>> {
-    //for now we're always starting with *a.root-servers.net*
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+//Accepts TCP connections and hands each to a thread of its own, as long as
+//there aren't too many of them already
+fn run_tcp(server : Arc<Server>, listener : TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("An error occured : {}", e);
+                continue;
+            }
+        };
 
-    //Since it might take an arbitrary number of queries to get to the final answer,
-    //We start the loop
-    loop {
-        println!("Attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+        if server.tcp_connections.fetch_add(1, Ordering::Relaxed) >= server.config.max_tcp_connections {
+            server.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+            eprintln!("Too many TCP connections, closing the one from {:?}", stream.peer_addr());
+            continue;
+        }
 
-        //The next step is to send the query to the active server
-        let ns_copy = ns;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = handle_tcp(&server, stream) {
+                eprintln!("An error occured on a TCP connection : {}", e);
+            }
+            server.tcp_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname , qtype , server)?;
+//Adds the addresses of the hosts that MX, NS and SRV records point at, so the
+//client doesn't have to look them up itself. Only what our zones or the cache
+//already know goes in, we don't go out resolving for it.
+fn add_additional(server : &Server, packet : &mut DnsPacket) {
+    let hosts : Vec<DomainName> = packet
+        .answers
+        .iter()
+        .chain(packet.authorities.iter())
+        .filter_map(|rec| match rec {
+            DnsRecord::MX { host, .. } | DnsRecord::NS { host, .. } | DnsRecord::Srv { host, .. } => Some(host.clone()),
+            _ => None,
+        })
+        .collect();
 
-        //If there are entries in the answer section, we can return the packet
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NoError {
-            return Ok(response);
+    for host in hosts {
+        let mut addrs = server.authority.addresses(&host);
+        if addrs.is_empty() {
+            addrs = server.resolver.cache.lock().unwrap().addresses(&host);
         }
 
-        // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
-        // way of telling us that the name doesn't exist.
-        if response.header.rescode == ResultCode::NXDomain {
-            return Ok(response);
+        for rec in addrs {
+            if !packet.answers.contains(&rec) && !packet.resources.contains(&rec) {
+                if server.config.log_queries {
+                    println!("Additional: {:?}", rec);
+                }
+                packet.resources.push(rec);
+            }
         }
+    }
+}
 
-        // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
-        // record in the additional section. If this succeeds, we can switch name server
-        // and retry the loop.
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
+//Encodes the reply so it fits into `size` bytes, 512 for UDP. The additional
+//records are the first to go, and if the answer still doesn't fit, the client
+//gets the question alone with TC set and has to ask again over TCP.
+fn encode_reply(packet : &mut DnsPacket, size : usize) -> Result<BytePacketBuffer, Box<dyn std::error::Error>> {
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    if packet.write(&mut res_buffer).is_ok() {
+        return Ok(res_buffer);
+    }
 
-            continue;
+    packet.resources.clear();
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    if packet.write(&mut res_buffer).is_ok() {
+        return Ok(res_buffer);
+    }
+
+    packet.header.truncated_message = true;
+    packet.answers.clear();
+    packet.authorities.clear();
+    let mut res_buffer = BytePacketBuffer::with_size(size);
+    packet.write(&mut res_buffer)?;
+    Ok(res_buffer)
+}
+
+//Encodes the reply like `encode_reply`, leaving room for the signature when
+//there's a signer
+fn encode_signed(packet : &mut DnsPacket, size : usize, signer : Option<&mut Signer>) -> Result<BytePacketBuffer, Box<dyn std::error::Error>> {
+    match signer {
+        Some(signer) => {
+            let mut res_buffer = encode_reply(packet, size.saturating_sub(signer.overhead()))?;
+            signer.sign(&mut res_buffer)?;
+            Ok(res_buffer)
         }
-        // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
-    // we'll go with what the last server told us.
-    let new_ns_name = match response.get_unresolved_ns(qname) {
-        Some(x) => x,
-        None => return Ok(response),
+        None => encode_reply(packet, size),
+    }
+}
+
+//The reply to a request whose signature didn't check out
+fn reject(request : &DnsPacket, error : &TsigError) -> Result<BytePacketBuffer, Box<dyn std::error::Error>> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.opcode = request.header.opcode;
+    packet.header.response = true;
+    packet.header.rescode = error.rescode();
+    packet.questions = request.questions.clone();
+
+    let mut res_buffer = encode_reply(&mut packet, UDP_MESSAGE_SIZE)?;
+    tsig::write_error(&mut res_buffer, error)?;
+    Ok(res_buffer)
+}
+
+//A worker takes queries off the shared queue one at a time until the server
+//shuts down and the queue is closed
+fn run_worker(server : Arc<Server>, queue : Arc<Mutex<Receiver<Query>>>) {
+    loop {
+        //The lock is only held while waiting for the next query, not while resolving it
+        let next = queue.lock().unwrap().recv();
+        let (req_buffer, src, socket) = match next {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+
+        if let Err(e) = handle_query(&server, &server.sockets[socket], req_buffer, src) {
+            eprintln!("An error occured : {}", e);
+        }
+
+        let count = server.handled.fetch_add(1, Ordering::Relaxed) + 1;
+        let interval = server.config.cache_stats_interval;
+        if interval > 0 && count.is_multiple_of(interval) {
+            println!("Cache stats: {}", server.resolver.cache.lock().unwrap().stats());
+        }
+    }
+}
+
+//Reads packets off one of our UDP sockets and queues them up for the workers,
+//until the workers are gone
+fn run_udp(server : Arc<Server>, socket : usize, queue : mpsc::SyncSender<Query>) {
+    loop {
+        let mut req_buffer = BytePacketBuffer::new();
+
+        //The `recv_from` function will write the data into the provided buffer,
+        //And return the length of the data read as well as the source address.
+        //We are essentially not interested in length , but we need to keep track of
+        //the source in order to send our reply later on.
+        let src = match server.sockets[socket].recv_from(&mut req_buffer.buf) {
+            Ok((_ , src)) => src,
+            Err(e) => {
+                eprintln!("An error occured : {}",e);
+                continue;
+            }
+        };
+
+        match queue.try_send((req_buffer, src, socket)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => eprintln!("Too many queries in flight, dropping query from {}", src),
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+//Works out the settings from the configuration file and the command line.
+//`--config <file>` reads a TOML configuration file first, wherever it is on
+//the command line, and the other options add to it or override it.
+//`--check-config` checks the configuration, zone files included, and exits.
+//`--listen <address>[:port]` (as often as needed) listens on those addresses
+//instead, over both UDP and TCP.
+//By default we resolve recursively, `--forward <address>[:port]` (as often as
+//needed) switches to forwarding and `--forward-strategy` picks how the
+//upstreams are used.
+//`--forward-zone <zone>=<upstream>[,<upstream>...]` sends a single zone to its
+//own upstreams, `--forward-zone-fallback` does the same but resolves the name
+//recursively if those upstreams fail.
+//`--dns64` turns on AAAA synthesis, `--dns64-prefix` and `--dns64-exclude`
+//(as often as needed) change the NAT64 prefix and the excluded AAAA ranges.
+//`--trace <name> [--trace-type <type>]` resolves a single name, prints every
+//step on the way and exits instead of starting the server.
+//`--zone <origin>=<file>` (as often as needed) serves a zone authoritatively,
+//and reloads it whenever the file changes.
+//`--secondary <zone>=<primary>[:port]` (as often as needed) serves a copy of
+//a zone transferred from its primary, and keeps it up to date.
+//`--notify <zone>=<address>[:port][,<address>[:port]...]` tells those
+//secondaries whenever the zone changes.
+//`--journal-dir <dir>` keeps the journals of our zones' changes in that
+//directory, so IXFR keeps working across restarts.
+//`--allow-transfer <zone>=<client>[,<client>...]` lets those clients transfer
+//one of our zones over TCP.
+//`--allow-update <zone>=<client>[,<client>...]` lets those clients change one
+//of our primary zones with dynamic updates. The zone file gets rewritten with
+//every update, losing its comments and layout.
+//A client on these lists is an address, or `key:<name>` for anyone signing
+//with that TSIG key.
+//`--tsig-key <name>=<algorithm>:<secret>` (as often as needed) adds a TSIG key,
+//hmac-sha256 or hmac-sha512 with a base64 secret.
+//`--server-key <address>=<key>` signs everything we send to that server, its
+//transfers and NOTIFY included, and expects its answers signed the same way.
+//`--allow-query <network>[,<network>...]` only answers clients in those
+//networks, like `192.0.2.0/24` or a single address. Anyone by default.
+//`--allow-recursion <network>[,<network>...]` resolves names outside our zones
+//for clients in those networks, only for this machine by default. Anyone else
+//gets REFUSED for them, so the server isn't an open resolver.
+//Both replace the lists of the configuration file.
+//`--minimal-responses` stops adding the addresses of MX, NS and SRV targets.
+fn parse_args(args : Vec<String>) -> Result<Options , Box<dyn std::error::Error>> {
+    let file = match args.iter().position(|arg| arg == "--config") {
+        Some(index) => Some(args.get(index + 1).ok_or("--config needs a file")?),
+        None => None,
     };
+    let Config { mut server, resolver : mut config, mut authority, mut journal_dir } = match file {
+        Some(path) => config::load(Path::new(path))?,
+        None => Config::default(),
+    };
+
+    let mut trace = None;
+    let mut trace_type = QueryType::A;
+    let mut check_config = false;
+    let mut listen = Vec::new();
+    let mut transfer_acls = Vec::new();
+    let mut update_acls = Vec::new();
+    let mut server_keys = Vec::new();
+    let mut notify_targets = Vec::new();
+    let mut upstreams = Vec::new();
+    let mut strategy = ForwardStrategy::Sequential;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            //Already read
+            "--config" => {
+                args.next();
+            }
+            "--check-config" => {
+                check_config = true;
+            }
+            "--listen" => {
+                let value = args.next().ok_or("--listen needs an address")?;
+                listen.push(config::parse_listen(&value)?);
+            }
+            "--forward" => {
+                let value = args.next().ok_or("--forward needs an upstream address")?;
+                upstreams.push(parse_upstream(&value)?);
+            }
+            "--forward-strategy" => {
+                let value = args.next().ok_or("--forward-strategy needs a value")?;
+                strategy = value.parse()?;
+            }
+            "--forward-zone" | "--forward-zone-fallback" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a zone=upstream value", arg))?;
+                config.forward_zones.push(parse_forward_zone(&value, arg == "--forward-zone-fallback")?);
+            }
+            "--dns64" => {
+                config.dns64.get_or_insert_with(Dns64Config::default);
+            }
+            "--dns64-prefix" => {
+                let value = args.next().ok_or("--dns64-prefix needs a prefix")?;
+                config.dns64.get_or_insert_with(Dns64Config::default).set_prefix(value.parse()?)?;
+            }
+            "--dns64-exclude" => {
+                let value = args.next().ok_or("--dns64-exclude needs a prefix")?;
+                config.dns64.get_or_insert_with(Dns64Config::default).exclude.push(value.parse()?);
+            }
+            "--zone" => {
+                let value = args.next().ok_or("--zone needs an origin=file value")?;
+                let (origin, path) = value.split_once('=').ok_or("--zone needs an origin=file value")?;
+                authority.load_zone(DomainName::new(origin), PathBuf::from(path))?;
+            }
+            "--secondary" => {
+                let value = args.next().ok_or("--secondary needs a zone=primary value")?;
+                let (origin, primary) = value.split_once('=').ok_or("--secondary needs a zone=primary value")?;
+                authority.add_secondary(DomainName::new(origin), SocketAddr::from(parse_upstream(primary)?));
+            }
+            "--notify" => {
+                let value = args.next().ok_or("--notify needs a zone=address value")?;
+                let (zone, secondaries) = value.split_once('=').ok_or("--notify needs a zone=address value")?;
+                let secondaries = secondaries
+                    .split(',')
+                    .map(|secondary| parse_upstream(secondary).map(SocketAddr::from))
+                    .collect::<Result<Vec<_>, _>>()?;
+                notify_targets.push((DomainName::new(zone), secondaries));
+            }
+            "--journal-dir" => {
+                let value = args.next().ok_or("--journal-dir needs a directory")?;
+                journal_dir = Some(PathBuf::from(value));
+            }
+            "--allow-transfer" => {
+                let value = args.next().ok_or("--allow-transfer needs a zone=client value")?;
+                transfer_acls.push(parse_acl(&arg, &value)?);
+            }
+            "--allow-update" => {
+                let value = args.next().ok_or("--allow-update needs a zone=client value")?;
+                update_acls.push(parse_acl(&arg, &value)?);
+            }
+            "--tsig-key" => {
+                let value = args.next().ok_or("--tsig-key needs a name=algorithm:secret value")?;
+                authority.add_key(Key::parse(&value)?);
+            }
+            "--server-key" => {
+                let value = args.next().ok_or("--server-key needs an address=key value")?;
+                let (server, key) = value.split_once('=').ok_or("--server-key needs an address=key value")?;
+                let server = server.parse::<IpAddr>().map_err(|_| format!("Invalid address {:?} in --server-key", server))?;
+                server_keys.push((server, DomainName::new(key)));
+            }
+            "--trace" => {
+                let value = args.next().ok_or("--trace needs a domain name")?;
+                trace = Some(DomainName::new(&value));
+            }
+            "--trace-type" => {
+                let value = args.next().ok_or("--trace-type needs a query type")?;
+                trace_type = value.parse()?;
+            }
+            "--allow-query" => {
+                let value = args.next().ok_or("--allow-query needs a network")?;
+                server.allow_query = parse_networks(&arg, &value)?;
+            }
+            "--allow-recursion" => {
+                let value = args.next().ok_or("--allow-recursion needs a network")?;
+                server.allow_recursion = parse_networks(&arg, &value)?;
+            }
+            "--minimal-responses" => {
+                server.minimal_responses = true;
+            }
+            _ => return Err(format!("Unknown argument {:?}", arg).into()),
+        }
+    }
 
-    // Here we go down the rabbit hole by starting _another_ lookup sequence in the
-    // midst of our current one. Hopefully, this will give us the IP of an appropriate
-    // name server.
-    let recursive_response = recursive_lookup(new_ns_name, QueryType::A)?;
+    //Only now that all zones and keys are loaded, as the options may come in any order
+    for (server, key) in server_keys {
+        authority.use_key(server, &key)?;
+    }
+    for (zone, clients) in transfer_acls {
+        authority.allow_transfer(&zone, clients)?;
+    }
+    for (zone, clients) in update_acls {
+        authority.allow_update(&zone, clients)?;
+    }
+    for (zone, secondaries) in notify_targets {
+        authority.also_notify(&zone, secondaries)?;
+    }
+    //Opening the journals brings them up to date with the zones, which a check
+    //shouldn't do
+    match journal_dir {
+        Some(dir) if check_config && !dir.is_dir() => {
+            return Err(format!("The journal directory {} doesn't exist", dir.display()).into());
+        }
+        Some(dir) if !check_config => authority.open_journals(&dir)?,
+        _ => {}
+    }
 
-    // Finally, we pick a random ip from the result, and restart the loop. If no such
-    // record is available, we again return the last result we got.
-    if let Some(new_ns) = recursive_response.get_random_a() {
-        ns = new_ns;
-    } else {
-        return Ok(response);
+    if !upstreams.is_empty() {
+        config.mode = ResolutionMode::Forwarding(Forwarder::new(upstreams, strategy));
     }
+    if !listen.is_empty() {
+        server.listen = listen;
+    }
+
+    Ok(Options {
+        server,
+        resolver : config,
+        authority,
+        trace : trace.map(|name| (name, trace_type)),
+        check_config,
+    })
+}
+
+//Parses the `<zone>=<client>[,<client>...]` value of an allow list option
+fn parse_acl(option : &str, value : &str) -> Result<(DomainName, Vec<Allow>), Box<dyn std::error::Error>> {
+    let (zone, clients) = value.split_once('=').ok_or_else(|| format!("{} needs a zone=client value", option))?;
+    let clients = clients
+        .split(',')
+        .map(|client| client.parse::<Allow>().map_err(|e| format!("{} in {}", e, option)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((DomainName::new(zone), clients))
+}
+
+//Parses the `<network>[,<network>...]` value of --allow-query and --allow-recursion
+fn parse_networks(option : &str, value : &str) -> Result<Vec<acl::Network>, Box<dyn std::error::Error>> {
+    let networks : Vec<&str> = value.split(',').collect();
+    config::parse_networks(&networks).map_err(|e| format!("{} in {}", e, option).into())
 }
+
+//Everything the configuration file and the command line can ask for
+struct Options {
+    server : ServerConfig,
+    resolver : ResolverConfig,
+    authority : Authority,
+    //Trace the resolution of this name and type instead of running the server
+    trace : Option<(DomainName, QueryType)>,
+    //Only check the configuration, don't run the server
+    check_config : bool,
 }
 
+//A list of networks as the log shows it
+fn networks(networks : &[acl::Network]) -> String {
+    if networks.is_empty() {
+        return String::from("nobody");
+    }
+    networks.iter().map(|network| network.to_string()).collect::<Vec<_>>().join(", ")
+}
 
+//Runs a single resolution with tracing on and prints it, much like `dig +trace`
+fn run_trace(config : ResolverConfig, qname : &DomainName, qtype : QueryType) -> Result<(), Box<dyn std::error::Error>> {
+    let resolver = Resolver::new(config);
+    let (result, steps) = resolver.trace(qname, qtype);
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
- //Bind an UDP socket on port 2053
- let socket = UdpSocket::bind(("0.0.0.0" , 2053))?;
+    for step in steps {
+        println!("{}", step);
+    }
+
+    let result = result?;
+    println!("Result: {:?}", result.header.rescode);
+    for rec in result.answers {
+        println!("  {:?}", rec);
+    }
+
+    Ok(())
+}
 
- println!("Server started successfully on port 2053");
- //For now, queries area handled sequentially, so an infinite loop for requests is initiated
- loop {
-    match handle_query(&socket) {
-        Ok(_) =>{},
-        Err(e) =>eprintln!("An error occured : {}",e),
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+ let options = match parse_args(std::env::args().skip(1).collect()) {
+    Ok(options) => options,
+    Err(e) => {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
     }
+ };
+ let (server_config, config, authority) = (options.server, options.resolver, options.authority);
+
+ if let Some((qname, qtype)) = options.trace {
+    return run_trace(config, &qname, qtype);
+ }
+
+ match config.mode {
+    ResolutionMode::Recursive => println!("Resolving recursively from the root servers"),
+    ResolutionMode::Forwarding(ref forwarder) => println!("Forwarding to {:?} ({:?})", forwarder.upstreams, forwarder.strategy),
+    ResolutionMode::Authoritative => println!("Answering for our own zones only, not resolving anything else"),
+ }
+ for rule in &config.forward_zones {
+    println!("Forwarding {} to {:?} (fallback to recursion: {})", rule.zone, rule.forwarder.upstreams, rule.fallback);
+ }
+ if let Some(ref dns64) = config.dns64 {
+    println!("DNS64 enabled with prefix {}/{}", dns64.prefix.addr, dns64.prefix.len);
+ }
+ if authority.zone_count() > 0 {
+    println!("Serving {} zone(s) authoritatively", authority.zone_count());
+ }
+
+ println!("Answering queries from {}", networks(&server_config.allow_query));
+ if !matches!(config.mode, ResolutionMode::Authoritative) {
+    println!("Resolving names outside our zones for {}", networks(&server_config.allow_recursion));
+ }
+
+ if options.check_config {
+    println!("Configuration is valid, listening on {:?} with {} workers", server_config.listen, server_config.workers);
+    return Ok(());
+ }
+
+ //Bind an UDP socket on every address we listen on, the workers send their
+ //replies through the one the query came in on. TCP goes on the same
+ //addresses, for zone transfers and any other query sent over TCP.
+ let mut sockets = Vec::new();
+ let mut listeners = Vec::new();
+ for addr in &server_config.listen {
+    sockets.push(UdpSocket::bind(addr).map_err(|e| format!("Can't listen on {}: {}", addr, e))?);
+    listeners.push(TcpListener::bind(addr).map_err(|e| format!("Can't listen on {} over TCP: {}", addr, e))?);
+ }
+
+ //The resolver and its cache live as long as the server does and are shared
+ //by every query, as well as by the lookups running in the background
+ let server = Arc::new(Server {
+    sockets,
+    resolver : Arc::new(Resolver::new(config)),
+    authority : Arc::new(authority),
+    config : server_config,
+    handled : AtomicU64::new(0),
+    tcp_connections : AtomicUsize::new(0),
+ });
+
+ //Queries are handed to a pool of workers, so one slow resolution doesn't hold
+ //up everyone else
+ let (sender, receiver) = mpsc::sync_channel(server.config.max_queued_queries);
+ let receiver = Arc::new(Mutex::new(receiver));
+ for _ in 0..server.config.workers {
+    let server = Arc::clone(&server);
+    let receiver = Arc::clone(&receiver);
+    thread::spawn(move || run_worker(server, receiver));
+ }
+
+ //Every secondary zone gets a thread keeping it up to date
+ for zone in server.authority.secondaries() {
+    println!("Secondary for {}, transferred from {}", zone.origin, zone.primary);
+    let authority = Arc::clone(&server.authority);
+    let zone = Arc::clone(zone);
+    thread::spawn(move || secondary::run(&authority, &zone));
+ }
+
+ //Zone files are watched for changes, and the secondaries get told about the
+ //zones as we have them now
+ for (origin, path) in server.authority.zone_files() {
+    let authority = Arc::clone(&server.authority);
+    let (origin, path) = (origin.clone(), path.clone());
+    thread::spawn(move || reload::run(&authority, &origin, &path));
+ }
+ server.authority.notify_all();
+
+ for listener in listeners {
+    let tcp_server = Arc::clone(&server);
+    thread::spawn(move || run_tcp(tcp_server, listener));
+ }
+
+ println!("Server started successfully on {:?} with {} workers", server.config.listen, server.config.workers);
+ //Every socket gets a thread reading packets and queueing them up, they only
+ //stop once the workers are gone
+ let readers : Vec<_> = (0..server.sockets.len())
+    .map(|socket| {
+        let server = Arc::clone(&server);
+        let sender = sender.clone();
+        thread::spawn(move || run_udp(server, socket, sender))
+    })
+    .collect();
+ for reader in readers {
+    let _ = reader.join();
  }
-}
\ No newline at end of file
+
+ Err("All workers have stopped".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use protocol::dnsquestion::DnsQuestion;
+    use resolver::ResolverLimits;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn args(args : &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn ip(s : &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn server(mode : ResolutionMode, allow_query : &str, allow_recursion : &str) -> Server {
+        let mut authority = Authority::new();
+        let origin = DomainName::new("example.org");
+        let zone = authority::zonefile::parse(&origin, "$TTL 3600\n@ SOA ns hostmaster 1 3600 600 86400 300\n@ NS ns\nNs A 192.0.2.53\n").unwrap();
+        authority.add_zone(zone);
+
+        let resolver = ResolverConfig { mode, ..ResolverConfig::default() };
+        let config = ServerConfig {
+            allow_query : parse_networks("--allow-query", allow_query).unwrap(),
+            allow_recursion : parse_networks("--allow-recursion", allow_recursion).unwrap(),
+            log_queries : false,
+            ..ServerConfig::default()
+        };
+        Server {
+            sockets : Vec::new(),
+            resolver : Arc::new(Resolver::new(resolver)),
+            authority : Arc::new(authority),
+            config,
+            handled : AtomicU64::new(0),
+            tcp_connections : AtomicUsize::new(0),
+        }
+    }
+
+    fn query(server : &Server, name : &str, client : &str) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request.header.id = 7;
+        request.header.recursion_desired = true;
+        request.questions.push(DnsQuestion::new(DomainName::new(name), QueryType::A));
+        answer(server, request, &mut BytePacketBuffer::new(), None, ip(client))
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let dir = std::env::temp_dir().join(format!("dns-server-args-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "[server]\nlisten = [\"127.0.0.1:5300\"]\nworkers = 4\nallow_query = [\"192.0.2.0/24\"]\nallow_recursion = [\"192.0.2.1\"]\n\n[resolver]\nupstreams = [\"192.0.2.53\"]\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        //Nothing on the command line, the file as it is
+        let options = parse_args(args(&["--config", path])).unwrap();
+        assert_eq!(options.server.listen, vec![SocketAddr::from(([127, 0, 0, 1], 5300))]);
+        assert_eq!(options.server.allow_query, config::parse_networks(&["192.0.2.0/24"]).unwrap());
+        assert_eq!(options.server.allow_recursion, config::parse_networks(&["192.0.2.1"]).unwrap());
+        assert!(matches!(options.resolver.mode, ResolutionMode::Forwarding(_)));
+
+        //Options replace what they name and leave the rest, before or after --config
+        let options = parse_args(args(&[
+            "--allow-recursion", "10.0.0.0/8,::1",
+            "--config", path,
+            "--listen", "127.0.0.1:5301",
+            "--minimal-responses",
+        ]))
+        .unwrap();
+        assert_eq!(options.server.listen, vec![SocketAddr::from(([127, 0, 0, 1], 5301))]);
+        assert_eq!(options.server.workers, 4);
+        assert!(options.server.minimal_responses);
+        assert_eq!(options.server.allow_query, config::parse_networks(&["192.0.2.0/24"]).unwrap());
+        assert_eq!(options.server.allow_recursion, config::parse_networks(&["10.0.0.0/8", "::1"]).unwrap());
+        assert!(matches!(options.resolver.mode, ResolutionMode::Forwarding(_)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn command_line_defaults() {
+        let options = parse_args(Vec::new()).unwrap();
+        assert!(matches!(options.resolver.mode, ResolutionMode::Recursive));
+        assert_eq!(options.server.allow_query, acl::anyone());
+        assert_eq!(options.server.allow_recursion, acl::localhost());
+
+        let options = parse_args(args(&["--allow-query", "192.0.2.1"])).unwrap();
+        assert_eq!(options.server.allow_query, config::parse_networks(&["192.0.2.1/32"]).unwrap());
+    }
+
+    #[test]
+    fn invalid_command_lines() {
+        let error = parse_args(args(&["--allow-query", "192.0.2.0/33"])).err().unwrap();
+        assert!(error.to_string().contains("--allow-query"), "{}", error);
+        assert!(parse_args(args(&["--allow-recursion", "127.0.0.1,"])).is_err());
+        assert!(parse_args(args(&["--allow-recursion"])).is_err());
+        assert!(parse_args(args(&["--config"])).is_err());
+        assert!(parse_args(args(&["--config", "/nonexistent/config.toml"])).is_err());
+        assert!(parse_args(args(&["--allow-everyone"])).is_err());
+    }
+
+    #[test]
+    fn recursion_only_for_allowed_clients() {
+        let server = server(ResolutionMode::Recursive, "0.0.0.0/0,::/0", "127.0.0.0/8");
+
+        //Our own zones are there for anyone allowed to query
+        let response = query(&server, "ns.example.org", "192.0.2.1");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert!(response.header.authoritative_answer);
+        assert!(!response.header.recursion_available);
+        assert_eq!(response.answers.len(), 1);
+
+        //Everything else only for those allowed to recurse
+        let response = query(&server, "example.com", "192.0.2.1");
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert_eq!(response.header.id, 7);
+        assert!(response.answers.is_empty());
+
+        let response = query(&server, "ns.example.org", "127.0.0.1");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert!(response.header.recursion_available);
+        let response = query(&server, "ns.example.org", "::ffff:127.0.0.1");
+        assert!(response.header.recursion_available);
+    }
+
+    #[test]
+    fn questions_go_back_in_the_case_they_came_in() {
+        let server = server(ResolutionMode::Recursive, "0.0.0.0/0", "0.0.0.0/0");
+
+        let response = query(&server, "nS.ExAmPlE.oRg", "192.0.2.1");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.questions[0].name.as_str(), "nS.ExAmPlE.oRg");
+        //The answer is named the way the zone file has it
+        assert_eq!(response.answers[0].domain().as_str(), "Ns.example.org");
+    }
+
+    #[test]
+    fn clients_outside_allow_query_are_refused() {
+        let server = server(ResolutionMode::Recursive, "192.0.2.0/24", "192.0.2.0/24");
+
+        let response = query(&server, "ns.example.org", "198.51.100.1");
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions.len(), 1);
+
+        let response = query(&server, "ns.example.org", "192.0.2.1");
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn updates_from_outside_allow_query_are_refused() {
+        let mut server = server(ResolutionMode::Recursive, "192.0.2.0/24", "192.0.2.0/24");
+        let origin = DomainName::new("example.org");
+        let allowed = vec![authority::Allow::Address(ip("198.51.100.1")), authority::Allow::Address(ip("192.0.2.1"))];
+        Arc::get_mut(&mut server.authority).unwrap().allow_update(&origin, allowed).unwrap();
+
+        let update = |client : &str, last : u8| {
+            let mut request = DnsPacket::new();
+            request.header.id = 9;
+            request.header.opcode = Opcode::Update;
+            request.questions.push(DnsQuestion::new(origin.clone(), QueryType::Soa));
+            request.authorities.push(DnsRecord::A { domain : DomainName::new("new.example.org"), addr : [192, 0, 2, last].into(), ttl : 300 });
+            let mut buffer = BytePacketBuffer::new();
+            request.write(&mut buffer).unwrap();
+            answer(&server, request, &mut buffer, None, ip(client))
+        };
+
+        //Allowed to update the zone, but not to talk to us at all
+        let response = update("198.51.100.1", 1);
+        assert_eq!(response.header.rescode, ResultCode::Refused);
+        assert_eq!(response.header.opcode, Opcode::Update);
+        assert_eq!(response.header.id, 9);
+        assert_eq!(query(&server, "new.example.org", "192.0.2.1").header.rescode, ResultCode::NXDomain);
+
+        let response = update("192.0.2.1", 2);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(query(&server, "new.example.org", "192.0.2.1").answers.len(), 1);
+    }
+
+    #[test]
+    fn slow_lookups_dont_hold_up_other_clients() {
+        //An upstream that only ever answers for fast.example
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, client) = upstream.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let name = request.questions[0].name.clone();
+            if name != DomainName::new("fast.example") {
+                continue;
+            }
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions;
+            response.answers.push(DnsRecord::A { domain : name, addr : [192, 0, 2, 2].into(), ttl : 300 });
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            upstream.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+        });
+
+        let forwarder = Forwarder::new(vec![(Ipv4Addr::LOCALHOST, port)], ForwardStrategy::Sequential);
+        let mut server = server(ResolutionMode::Forwarding(forwarder), "0.0.0.0/0", "127.0.0.0/8");
+        server.sockets = vec![UdpSocket::bind("127.0.0.1:0").unwrap()];
+        let addr = server.sockets[0].local_addr().unwrap();
+        let server = Arc::new(server);
+
+        let (sender, receiver) = mpsc::sync_channel(server.config.max_queued_queries);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..2 {
+            let server = Arc::clone(&server);
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || run_worker(server, receiver));
+        }
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || run_udp(server, 0, sender));
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        for (id, name) in [(1, "slow.example"), (2, "fast.example")] {
+            let mut request = DnsPacket::new();
+            request.header.id = id;
+            request.header.recursion_desired = true;
+            request.questions.push(DnsQuestion::new(DomainName::new(name), QueryType::A));
+            let mut buffer = BytePacketBuffer::new();
+            request.write(&mut buffer).unwrap();
+            client.send_to(buffer.get_range(0, buffer.pos()).unwrap(), addr).unwrap();
+        }
+
+        //fast.example is answered while the worker on slow.example still waits
+        //for its upstream
+        let mut buffer = BytePacketBuffer::new();
+        client.recv_from(&mut buffer.buf).unwrap();
+        let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(response.header.id, 2);
+        assert_eq!(response.header.rescode, ResultCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn used_up_budgets_are_servfail() {
+        let mut server = server(ResolutionMode::Recursive, "0.0.0.0/0", "127.0.0.0/8");
+        let resolver = Resolver::new(ResolverConfig {
+            limits : ResolverLimits { max_queries : 0, ..ResolverLimits::default() },
+            ..server.resolver.config.clone()
+        });
+        server.resolver = Arc::new(resolver);
+
+        let response = query(&server, "example.com", "127.0.0.1");
+        assert_eq!(response.header.rescode, ResultCode::ServFail);
+        assert_eq!(response.header.id, 7);
+        assert!(response.answers.is_empty());
+    }
+
+    fn a(owner : &str, last : u8) -> DnsRecord {
+        DnsRecord::A { domain : DomainName::new(owner), addr : [192, 0, 2, last].into(), ttl : 300 }
+    }
+
+    #[test]
+    fn targets_get_their_addresses_in_the_additional_section() {
+        let server = server(ResolutionMode::Recursive, "0.0.0.0/0", "0.0.0.0/0");
+        let mut cached = DnsPacket::new();
+        cached.answers.push(a("mail.example.com", 25));
+        server.resolver.cache.lock().unwrap().store_packet(&cached);
+
+        let origin = DomainName::new("example.org");
+        let ns = DomainName::new("ns.example.org");
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::MX { domain : origin.clone(), priority : 10, host : DomainName::new("mail.example.com"), ttl : 300 });
+        packet.answers.push(DnsRecord::Srv { domain : DomainName::new("_dns._udp.example.org"), priority : 0, weight : 0, port : 53, host : ns.clone(), ttl : 300 });
+        packet.authorities.push(DnsRecord::NS { domain : origin, host : ns, ttl : 3600 });
+        //Nobody knows where this one is
+        packet.answers.push(DnsRecord::MX { domain : DomainName::new("example.org"), priority : 20, host : DomainName::new("mx.example.net"), ttl : 300 });
+        add_additional(&server, &mut packet);
+
+        //Addresses come from our zones or the cache, each one only once
+        let addrs : Vec<(String, [u8; 4])> = packet.resources.iter().map(|rec| match rec {
+            DnsRecord::A { domain, addr, .. } => (domain.to_string(), addr.octets()),
+            rec => panic!("Expected an address, got {:?}", rec),
+        }).collect();
+        assert_eq!(addrs, [("mail.example.com".to_string(), [192, 0, 2, 25]), ("Ns.example.org".to_string(), [192, 0, 2, 53])]);
+    }
+
+    #[test]
+    fn oversized_replies_lose_the_additional_section_before_they_get_truncated() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new(DomainName::new("example.org"), QueryType::A));
+        for last in 0..10 {
+            packet.answers.push(a("example.org", last));
+        }
+        for last in 0..30 {
+            packet.resources.push(a(&format!("host{}.example.org", last), last));
+        }
+
+        //Without the additional records the answers fit
+        let mut reply = packet.clone();
+        encode_reply(&mut reply, UDP_MESSAGE_SIZE).unwrap();
+        assert!(!reply.header.truncated_message);
+        assert_eq!(reply.answers.len(), 10);
+        assert!(reply.resources.is_empty());
+
+        //Once they don't either, only the question is left
+        for last in 10..40 {
+            packet.answers.push(a("example.org", last));
+        }
+        let buffer = encode_reply(&mut packet, UDP_MESSAGE_SIZE).unwrap();
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.is_empty() && packet.authorities.is_empty() && packet.resources.is_empty());
+        assert_eq!(packet.questions.len(), 1);
+        assert!(buffer.pos() <= UDP_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn no_recursion_in_the_authoritative_mode() {
+        let server = server(ResolutionMode::Authoritative, "0.0.0.0/0", "0.0.0.0/0");
+        let response = query(&server, "ns.example.org", "127.0.0.1");
+        assert!(!response.header.recursion_available);
+        assert_eq!(query(&server, "example.com", "127.0.0.1").header.rescode, ResultCode::Refused);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::protocol::domainname::DomainName;
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize, // Current position in the buffer
 }
 
@@ -10,10 +12,15 @@ impl Default for BytePacketBuffer {
 }
 
 impl BytePacketBuffer {
-    // Initialize a new buffer
+    // Initialize a new buffer, big enough for a UDP message
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(512)
+    }
+
+    // Initialize a new buffer of `size` bytes, TCP messages may be up to 65535
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -26,7 +33,8 @@ impl BytePacketBuffer {
     // Advance the buffer position by a specific number of steps
     pub fn step(&mut self, steps: usize) -> Result<(), Box<dyn std::error::Error>> {
         self.pos += steps;
-        if self.pos >= 512 {
+        //Stepping right up to the end is fine, the last record of a message does
+        if self.pos > self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(())
@@ -35,7 +43,8 @@ impl BytePacketBuffer {
     // Change the buffer position
     pub fn seek(&mut self, pos: usize) -> Result<(), Box<dyn std::error::Error>> {
         self.pos = pos;
-        if self.pos >= 512 {
+        //Like `step`, the end itself is a fine place to be once a message is read
+        if self.pos > self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(())
@@ -43,7 +52,7 @@ impl BytePacketBuffer {
 
     // Read a single byte from the buffer and advance the position
     pub fn read(&mut self) -> Result<u8, Box<dyn std::error::Error>> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         let res = self.buf[self.pos];
@@ -53,7 +62,7 @@ impl BytePacketBuffer {
 
     // Get a single byte without changing the buffer position
     pub fn get(&self, pos: usize) -> Result<u8, Box<dyn std::error::Error>> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(self.buf[pos])
@@ -61,7 +70,7 @@ impl BytePacketBuffer {
 
     // Get a range of bytes from the buffer
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8], Box<dyn std::error::Error>> {
-        if start + len > 512 {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(&self.buf[start..start + len])
@@ -124,9 +133,16 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    // Read a domain name from the buffer as a `DomainName`
+    pub fn read_name(&mut self) -> Result<DomainName, Box<dyn std::error::Error>> {
+        let mut name = String::new();
+        self.read_qname(&mut name)?;
+        Ok(DomainName::from(name))
+    }
+
     // Write a single byte to the buffer and advance the position
     pub fn write(&mut self, val: u8) -> Result<(), Box<dyn std::error::Error>> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         self.buf[self.pos] = val;
@@ -158,7 +174,8 @@ impl BytePacketBuffer {
 
     // Write a qname to the buffer
     pub fn write_qname(&mut self, qname: &str) -> Result<(), Box<dyn std::error::Error>> {
-        for part in qname.split('.') {
+        // The root name has no labels, only the terminating zero
+        for part in qname.split('.').filter(|part| !part.is_empty()) {
             if part.len() > 63 {
                 return Err("Label too long".into());
             }
@@ -184,3 +201,65 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::dnspacket::DnsPacket;
+    use crate::protocol::dnsquestion::DnsQuestion;
+    use crate::protocol::dnsrecord::DnsRecord;
+    use crate::protocol::querytype::QueryType;
+
+    //A buffer holding exactly `bytes`, the way messages read off TCP are
+    fn exact(bytes: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::with_size(bytes.len());
+        buffer.buf.copy_from_slice(bytes);
+        buffer
+    }
+
+    #[test]
+    fn messages_may_end_in_a_name() {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new(DomainName::new("www.example.org"), QueryType::Cname));
+        packet.answers.push(DnsRecord::Cname {
+            domain: DomainName::new("www.example.org"),
+            host: DomainName::new("example.org"),
+            ttl: 300,
+        });
+        let mut written = BytePacketBuffer::new();
+        packet.write(&mut written).unwrap();
+
+        let mut buffer = exact(written.get_range(0, written.pos()).unwrap());
+        let read = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(read.answers, packet.answers);
+        assert_eq!(buffer.pos(), buffer.buf.len());
+    }
+
+    #[test]
+    fn messages_may_end_in_a_compressed_name() {
+        let mut bytes = vec![0, 1, 0x81, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+        //The question, at offset 12
+        bytes.extend_from_slice(b"\x07example\x03org\x00\x00\x05\x00\x01");
+        //The answer, owner and CNAME target both pointing at it
+        bytes.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 44, 0, 2, 0xc0, 12]);
+
+        let mut buffer = exact(&bytes);
+        let read = DnsPacket::from_buffer(&mut buffer).unwrap();
+        match &read.answers[..] {
+            [DnsRecord::Cname { domain, host, .. }] => {
+                assert_eq!(domain, &DomainName::new("example.org"));
+                assert_eq!(host, &DomainName::new("example.org"));
+            }
+            answers => panic!("Expected a CNAME, got {:?}", answers),
+        }
+    }
+
+    #[test]
+    fn seeking_past_the_end_fails() {
+        let mut buffer = BytePacketBuffer::with_size(4);
+        assert!(buffer.seek(4).is_ok());
+        assert!(buffer.seek(5).is_err());
+        assert!(buffer.read().is_err());
+    }
+}
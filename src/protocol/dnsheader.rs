@@ -1,5 +1,6 @@
 use crate::protocol::byte_packet_buffer::BytePacketBuffer;
 // use std::io::Error;
+use crate::protocol::opcode::Opcode;
 use crate::protocol::resultcode::ResultCode;
 #[derive(Clone, Debug)]
 pub struct DnsHeader {
@@ -8,7 +9,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,    // 1 bit
     pub truncated_message: bool,    // 1 bit
     pub authoritative_answer: bool, // 1 bit
-    pub opcode: u8,                 // 4 bits
+    pub opcode: Opcode,             // 4 bits
     pub response: bool,             // 1 bit
 
     pub rescode: ResultCode,       // 4 bits
@@ -38,7 +39,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::Query,
             response: false,
 
             rescode: ResultCode::NoError,
@@ -63,7 +64,7 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
         self.rescode = ResultCode::from_num(b & 0x0F);
@@ -86,7 +87,7 @@ impl DnsHeader {
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
-                | (self.opcode << 3)
+                | ((self.opcode.to_num() & 0x0F) << 3)
                 | ((self.response as u8) << 7),
         )?;
 
@@ -4,6 +4,7 @@ use crate::protocol::byte_packet_buffer::BytePacketBuffer;
 use crate::protocol::dnsheader::DnsHeader;
 use crate::protocol::dnsquestion::DnsQuestion;
 use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
 use crate::protocol::querytype::QueryType;
 
 #[derive(Clone, Debug)]
@@ -37,7 +38,7 @@ impl DnsPacket {
         result.header.read(buffer)?;
 
         for _ in 0..result.header.questions {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::Unknown(0));
+            let mut question = DnsQuestion::new(DomainName::root(), QueryType::Unknown(0));
             question.read(buffer)?;
             result.questions.push(question);
         }
@@ -81,40 +82,41 @@ impl DnsPacket {
         Ok(())
     }
 
-    //It is useful to be able to pick a random A record from a packet, 
-    //since when we get multiple nIP's for a single name, it doesn't matter which one we use.
-    //Isliye random A record pick karne ke liye ye function banaya hai.
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+    //When we get multiple IP's for a single name, we want all of them, so that
+    //the resolver can pick the one that answers fastest.
+    //Isliye saare A records ek saath return karte hai.
+    pub fn get_a_addrs(&self) -> Vec<Ipv4Addr> {
         self.answers.iter()
         .filter_map(|record| match record {
             DnsRecord::A {addr, ..} => Some(*addr),
             _ => None,
         })
-        .next()
+        .collect()
     }
 
     //A helper function which returns an iterator over all name servers
     //in the authorities section, represented as (domain , host) tuples.
-    pub fn get_ns<'a>(&'a self , qname : &'a str) -> impl Iterator<Item = (&'a str , &'a str)> {
+    pub fn get_ns<'a>(&'a self , qname : &'a DomainName) -> impl Iterator<Item = (&'a DomainName , &'a DomainName)> {
         self.authorities.iter()
         //In practice, these are always NS records in well formed packages.
         //Convert the NS records to a tuple which has only the data we need
         //to make it easy to work with
         .filter_map(|record| match record {
-            DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
+            DnsRecord::NS { domain, host, .. } => Some((domain, host)),
             _ => None,
         })
-        //Filter out the records which are not for the domain we are looking for
-        .filter(move |(domain , _)| qname.ends_with(*domain))
+        //Filter out the records which are not for the domain we are looking for.
+        //This compares whole labels, so `notexample.com` doesn't match `example.com`
+        .filter(move |(domain , _)| qname.is_subdomain_of(domain))
     }
     //We will use the fact that name servers often bundle the corresponding A records
-    //When repluing to an NS query to implement a function that returns the actual IP 
-    //for an NS record if possible.
-    pub fn get_resolved_ns (&self , qname : &str) -> Option<Ipv4Addr> {
+    //When repluing to an NS query to implement a function that returns the actual IPs
+    //for the NS records if possible.
+    pub fn get_resolved_ns (&self , qname : &DomainName) -> Vec<Ipv4Addr> {
         //Get an iterator over the nameservers in the authorities section
         self.get_ns(qname)
         //Now we need to look for a matching A record in the additional section.
-        //Scince we just want the first one, we can just build a stream of matching records.
+        //Every one of them is a candidate, so we build a stream of matching records.
         .flat_map(|(_, host)| {
             self.resources.iter()
             // Filter for A records where the domain match the host
@@ -124,14 +126,14 @@ impl DnsPacket {
                 _ => None,
             })
         })
-        //Finally pick the first valid entry
-        .next()
+        //Finally collect every valid entry
+        .collect()
     }
     /// However, not all name servers are as that nice. In certain cases there won't
     /// be any A records in the additional section, and we'll have to perform *another*
     /// lookup in the midst. For this, we introduce a method for returning the host
     /// name of an appropriate name server.
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a DomainName) -> Option<&'a DomainName> {
         // Get an iterator over the nameservers in the authorities section
         self.get_ns(qname)
             .map(|(_, host)| host)
@@ -1,15 +1,16 @@
 use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::domainname::DomainName;
 use crate::protocol::querytype::QueryType;
 use std::error::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
-    pub name: String,
+    pub name: DomainName,
     pub qtype: QueryType,
 }
 
 impl DnsQuestion {
-    pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
+    pub fn new(name: DomainName, qtype: QueryType) -> DnsQuestion {
         DnsQuestion {
             name,
             qtype,
@@ -17,14 +18,14 @@ impl DnsQuestion {
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Box<dyn Error>> {
-        buffer.read_qname(&mut self.name)?;
+        self.name = buffer.read_name()?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
         let _ = buffer.read_u16()?; // class
     
         Ok(())
     }
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<() , Box<dyn Error>> {
-        buffer.write_qname(&self.name)?;
+        buffer.write_qname(self.name.as_str())?;
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
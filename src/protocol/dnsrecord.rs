@@ -1,4 +1,5 @@
 use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::domainname::DomainName;
 use crate::protocol::querytype::QueryType;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
@@ -7,30 +8,30 @@ use std::net::Ipv6Addr;
 #[allow(dead_code)]
 pub enum DnsRecord {
     Unknown {
-        domain: String,
+        domain: DomainName,
         qtype: u16,
         data_len: u16,
         ttl: u32,
     }, // 0
     A {
-        domain: String,
+        domain: DomainName,
         addr: Ipv4Addr,
         ttl: u32,
     }, // 1
     NS {
-        domain: String,
-        host: String,
+        domain: DomainName,
+        host: DomainName,
         ttl: u32,
     }, // 2
     Cname {
-        domain: String,
-        host: String,
+        domain: DomainName,
+        host: DomainName,
         ttl: u32,
     }, // 5
     Soa {
-        domain: String,
-        mname: String,
-        rname: String,
+        domain: DomainName,
+        mname: DomainName,
+        rname: DomainName,
         serial: u32,
         refresh: u32,
         retry: u32,
@@ -38,35 +39,195 @@ pub enum DnsRecord {
         minimum: u32,
         ttl: u32,
     }, // 6
+    Ptr {
+        domain: DomainName,
+        host: DomainName,
+        ttl: u32,
+    }, // 12
     MX {
-        domain: String,
+        domain: DomainName,
         priority: u16,
-        host: String,
+        host: DomainName,
         ttl: u32,
     }, // 15
     Txt {
-        domain: String,
+        domain: DomainName,
         text: String,
         ttl: u32,
     }, // 16
     Aaaa {
-        domain: String,
+        domain: DomainName,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    Srv {
+        domain: DomainName,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: DomainName,
+        ttl: u32,
+    }, // 33
+    //Redirects everything below `domain` to the same names below `host` (RFC 6672)
+    Dname {
+        domain: DomainName,
+        host: DomainName,
+        ttl: u32,
+    }, // 39
+    //The EDNS(0) pseudo record (RFC 6891). It abuses the class field for the
+    //largest UDP payload the sender accepts and the ttl field for flags.
+    Opt {
+        domain: DomainName,
+        udp_size: u16,
+        flags: u32,
+    }, // 41
+    //Proof that nothing exists between `domain` and `next` in canonical order,
+    //and that `domain` has only the record `types` listed (RFC 4034)
+    Nsec {
+        domain: DomainName,
+        next: DomainName,
+        types: Vec<u16>,
+        ttl: u32,
+    }, // 47
 }
 
+//The DO bit in the flags of an OPT record, asking for DNSSEC records (RFC 3225)
+pub const EDNS_DNSSEC_OK: u32 = 0x8000;
+
 impl DnsRecord {
+    //Accessors for the fields every record has, so that code handling records
+    //generically (like the cache) doesn't have to match on every variant.
+    pub fn domain(&self) -> &DomainName {
+        match self {
+            DnsRecord::Unknown { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::Cname { domain, .. }
+            | DnsRecord::Soa { domain, .. }
+            | DnsRecord::Ptr { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::Txt { domain, .. }
+            | DnsRecord::Aaaa { domain, .. }
+            | DnsRecord::Srv { domain, .. }
+            | DnsRecord::Dname { domain, .. }
+            | DnsRecord::Opt { domain, .. }
+            | DnsRecord::Nsec { domain, .. } => domain,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::Unknown { qtype, .. } => QueryType::from_num(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::Cname { .. } => QueryType::Cname,
+            DnsRecord::Soa { .. } => QueryType::Soa,
+            DnsRecord::Ptr { .. } => QueryType::Ptr,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::Txt { .. } => QueryType::Txt,
+            DnsRecord::Aaaa { .. } => QueryType::Aaaa,
+            DnsRecord::Srv { .. } => QueryType::Srv,
+            DnsRecord::Dname { .. } => QueryType::Dname,
+            DnsRecord::Opt { .. } => QueryType::Opt,
+            DnsRecord::Nsec { .. } => QueryType::Nsec,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::Unknown { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::Cname { ttl, .. }
+            | DnsRecord::Soa { ttl, .. }
+            | DnsRecord::Ptr { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::Txt { ttl, .. }
+            | DnsRecord::Aaaa { ttl, .. }
+            | DnsRecord::Srv { ttl, .. }
+            | DnsRecord::Dname { ttl, .. }
+            | DnsRecord::Nsec { ttl, .. } => *ttl,
+            //The ttl field of an OPT record holds flags, it has no ttl of its own
+            DnsRecord::Opt { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::Unknown { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::Cname { ttl, .. }
+            | DnsRecord::Soa { ttl, .. }
+            | DnsRecord::Ptr { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::Txt { ttl, .. }
+            | DnsRecord::Aaaa { ttl, .. }
+            | DnsRecord::Srv { ttl, .. }
+            | DnsRecord::Dname { ttl, .. }
+            | DnsRecord::Nsec { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::Opt { .. } => {}
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: DomainName) {
+        match self {
+            DnsRecord::Unknown { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::Cname { domain, .. }
+            | DnsRecord::Soa { domain, .. }
+            | DnsRecord::Ptr { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::Txt { domain, .. }
+            | DnsRecord::Aaaa { domain, .. }
+            | DnsRecord::Srv { domain, .. }
+            | DnsRecord::Dname { domain, .. }
+            | DnsRecord::Opt { domain, .. }
+            | DnsRecord::Nsec { domain, .. } => *domain = new_domain,
+        }
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord, Box<dyn std::error::Error>> {
-        let mut domain = String::new();
-        buffer.read_qname(&mut domain)?;
+        Ok(DnsRecord::read_with_class(buffer)?.0)
+    }
+
+    //Reads a record along with its class, which only UPDATE messages (RFC 2136)
+    //put to use. Everywhere else it's IN, or something we don't serve anyway.
+    pub fn read_with_class(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord, u16), Box<dyn std::error::Error>> {
+        let domain = buffer.read_name()?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
+        //Records without data stand for a whole RRset or name in UPDATE messages,
+        //there's nothing to read for them
+        if data_len == 0 && qtype != QueryType::Opt {
+            let rec = DnsRecord::Unknown {
+                domain,
+                qtype: qtype_num,
+                data_len,
+                ttl,
+            };
+            return Ok((rec, class));
+        }
+
+        let rec = DnsRecord::read_data(buffer, domain, qtype_num, class, ttl, data_len)?;
+        Ok((rec, class))
+    }
+
+    fn read_data(
+        buffer: &mut BytePacketBuffer,
+        domain: DomainName,
+        qtype_num: u16,
+        class: u16,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord, Box<dyn std::error::Error>> {
+        let qtype = QueryType::from_num(qtype_num);
         match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -108,8 +269,7 @@ impl DnsRecord {
             }
 
             QueryType::NS => {
-                let mut ns = String::new();
-                buffer.read_qname(&mut ns)?;
+                let ns = buffer.read_name()?;
         
                 Ok(DnsRecord::NS {
                     domain,
@@ -119,8 +279,7 @@ impl DnsRecord {
             }
 
             QueryType::Cname => {
-                let mut cname = String::new();
-                buffer.read_qname(&mut cname)?;
+                let cname = buffer.read_name()?;
         
                 Ok(DnsRecord::Cname {
                     domain,
@@ -130,11 +289,9 @@ impl DnsRecord {
             }
 
             QueryType::Soa => {
-                let mut mname = String::new();
-                buffer.read_qname(&mut mname)?;
+                let mname = buffer.read_name()?;
         
-                let mut rname = String::new();
-                buffer.read_qname(&mut rname)?;
+                let rname = buffer.read_name()?;
         
                 let serial = buffer.read_u32()?;
                 let refresh = buffer.read_u32()?;
@@ -155,11 +312,20 @@ impl DnsRecord {
                 })
             }
 
+            QueryType::Ptr => {
+                let host = buffer.read_name()?;
+
+                Ok(DnsRecord::Ptr {
+                    domain,
+                    host,
+                    ttl,
+                })
+            }
+
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
         
-                let mut mx = String::new();
-                buffer.read_qname(&mut mx)?;
+                let mx = buffer.read_name()?;
         
                 Ok(DnsRecord::MX {
                     domain,
@@ -180,7 +346,81 @@ impl DnsRecord {
                 })
             }
 
-            QueryType::Unknown(_) => {
+            QueryType::Srv => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let host = buffer.read_name()?;
+
+                Ok(DnsRecord::Srv {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    ttl,
+                })
+            }
+
+            QueryType::Dname => {
+                let host = buffer.read_name()?;
+
+                Ok(DnsRecord::Dname {
+                    domain,
+                    host,
+                    ttl,
+                })
+            }
+
+            QueryType::Opt => {
+                //We don't use any of the options, so they're skipped
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::Opt {
+                    domain,
+                    udp_size: class,
+                    flags: ttl,
+                })
+            }
+
+            QueryType::Nsec => {
+                let end = buffer.pos() + data_len as usize;
+                let next = buffer.read_name()?;
+
+                //The type bitmap comes in windows of 256 types each, a window
+                //number, the length of its bitmap and then the bitmap itself.
+                //A window holds 32 bytes at most and has to fit in the record.
+                let mut types = Vec::new();
+                while buffer.pos() < end {
+                    let window = buffer.read()? as u32;
+                    let len = buffer.read()? as usize;
+                    if len == 0 || len > 32 || buffer.pos() + len > end {
+                        return Err(format!("Invalid NSEC type bitmap of {} bytes in window {}", len, window).into());
+                    }
+                    for i in 0..len as u32 {
+                        let bits = buffer.read()?;
+                        for bit in 0..8 {
+                            if bits & (0x80 >> bit) != 0 {
+                                types.push((window * 256 + i * 8 + bit) as u16);
+                            }
+                        }
+                    }
+                }
+                if buffer.pos() != end {
+                    return Err("NSEC record runs past its data".into());
+                }
+
+                Ok(DnsRecord::Nsec {
+                    domain,
+                    next,
+                    types,
+                    ttl,
+                })
+            }
+
+            //AXFR, IXFR and ANY only ever appear in questions, a record claiming
+            //one of them is as good as unknown. TSIG records are read on their own.
+            QueryType::Unknown(_) | QueryType::Ixfr | QueryType::Axfr | QueryType::Any | QueryType::Tsig => {
                 buffer.step(data_len as usize)?;
         
                 Ok(DnsRecord::Unknown {
@@ -201,7 +441,7 @@ impl DnsRecord {
                 ref addr,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::A.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -221,7 +461,7 @@ impl DnsRecord {
                 ref host,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::NS.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -229,7 +469,7 @@ impl DnsRecord {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
 
-                buffer.write_qname(host)?;
+                buffer.write_qname(host.as_str())?;
 
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
@@ -239,7 +479,7 @@ impl DnsRecord {
                 ref host,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::Cname.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -247,7 +487,7 @@ impl DnsRecord {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
 
-                buffer.write_qname(host)?;
+                buffer.write_qname(host.as_str())?;
 
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
@@ -263,7 +503,7 @@ impl DnsRecord {
                 minimum,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::Soa.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -271,8 +511,8 @@ impl DnsRecord {
                 let pos = buffer.pos();
                 buffer.write_u16(0)?;
 
-                buffer.write_qname(mname)?;
-                buffer.write_qname(rname)?;
+                buffer.write_qname(mname.as_str())?;
+                buffer.write_qname(rname.as_str())?;
                 buffer.write_u32(serial)?;
                 buffer.write_u32(refresh)?;
                 buffer.write_u32(retry)?;
@@ -282,13 +522,31 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::Ptr {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain.as_str())?;
+                buffer.write_u16(QueryType::Ptr.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host.as_str())?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
                 ref host,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::MX.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -297,7 +555,7 @@ impl DnsRecord {
                 buffer.write_u16(0)?;
 
                 buffer.write_u16(priority)?;
-                buffer.write_qname(host)?;
+                buffer.write_qname(host.as_str())?;
 
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
@@ -307,7 +565,7 @@ impl DnsRecord {
                 ref text,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::Txt.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -325,7 +583,7 @@ impl DnsRecord {
                 ref addr,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_qname(domain.as_str())?;
                 buffer.write_u16(QueryType::Aaaa.to_num())?;
                 buffer.write_u16(1)?;
                 buffer.write_u32(ttl)?;
@@ -341,7 +599,161 @@ impl DnsRecord {
                 buffer.write_u16(segments[6])?;
                 buffer.write_u16(segments[7])?;
             }
+            DnsRecord::Srv {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain.as_str())?;
+                buffer.write_u16(QueryType::Srv.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname(host.as_str())?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::Dname {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain.as_str())?;
+                buffer.write_u16(QueryType::Dname.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host.as_str())?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::Opt {
+                ref domain,
+                udp_size,
+                flags,
+            } => {
+                buffer.write_qname(domain.as_str())?;
+                buffer.write_u16(QueryType::Opt.to_num())?;
+                buffer.write_u16(udp_size)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(0)?;
+            }
+            DnsRecord::Nsec {
+                ref domain,
+                ref next,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain.as_str())?;
+                buffer.write_u16(QueryType::Nsec.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(next.as_str())?;
+
+                let mut types = types.clone();
+                types.sort_unstable();
+                types.dedup();
+                let mut rest = &types[..];
+                while let Some(first) = rest.first() {
+                    let window = first / 256;
+                    let count = rest.iter().take_while(|t| *t / 256 == window).count();
+                    let (in_window, others) = rest.split_at(count);
+
+                    let mut bitmap = [0u8; 32];
+                    let mut len = 0;
+                    for t in in_window {
+                        let low = (t % 256) as usize;
+                        bitmap[low / 8] |= 0x80 >> (low % 8);
+                        len = low / 8 + 1;
+                    }
+
+                    buffer.write_u8(window as u8)?;
+                    buffer.write_u8(len as u8)?;
+                    for b in &bitmap[..len] {
+                        buffer.write_u8(*b)?;
+                    }
+                    rest = others;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
         }
         Ok(buffer.pos() - start_pos)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //An NSEC record for a.example. with its rdata written by hand
+    fn nsec(bitmap: &[u8], data_len: u16) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("a.example").unwrap();
+        buffer.write_u16(QueryType::Nsec.to_num()).unwrap();
+        buffer.write_u16(1).unwrap();
+        buffer.write_u32(300).unwrap();
+        buffer.write_u16(data_len).unwrap();
+        buffer.write_qname("b.example").unwrap();
+        for b in bitmap {
+            buffer.write_u8(*b).unwrap();
+        }
+        //Another record right after, for the bitmap not to run into
+        buffer.write_qname("c.example").unwrap();
+        buffer.seek(0).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn nsec_type_bitmaps_round_trip() {
+        let record = DnsRecord::Nsec {
+            domain: DomainName::new("a.example"),
+            next: DomainName::new("b.example"),
+            types: vec![1, 2, 46, 47, 256, 65535],
+            ttl: 300,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), record);
+    }
+
+    #[test]
+    fn malformed_nsec_type_bitmaps_are_errors() {
+        //"b.example" takes 11 bytes of the rdata
+        let mut too_long = vec![255, 33];
+        too_long.extend([0xff; 33]);
+        assert!(DnsRecord::read(&mut nsec(&too_long, 11 + 35)).is_err());
+
+        assert!(DnsRecord::read(&mut nsec(&[0, 0], 11 + 2)).is_err());
+
+        //The bitmap claims more bytes than the record has
+        assert!(DnsRecord::read(&mut nsec(&[0, 4, 0x40, 0], 11 + 4)).is_err());
+
+        //The last window of all, fully set, is fine
+        let mut last = vec![255, 32];
+        last.extend([0xff; 32]);
+        match DnsRecord::read(&mut nsec(&last, 11 + 34)).unwrap() {
+            DnsRecord::Nsec { types, .. } => assert_eq!(types, (65280..=65535).collect::<Vec<u16>>()),
+            record => panic!("Expected NSEC, got {:?}", record),
+        }
+    }
+}
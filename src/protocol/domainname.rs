@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A domain name, stored without the trailing dot. The root is the empty name.
+///
+/// Names keep the case they were written in, which is how they go back out in
+/// answers, but are compared label by label and without regard to case. So
+/// `WWW.Example.com` equals `www.example.com` and `notexample.com` is not a
+/// subdomain of `example.com`. Ordering follows the canonical DNS name order
+/// of RFC 4034 (section 6.1), which sorts by the rightmost label first.
+#[derive(Clone, Default)]
+pub struct DomainName {
+    name: String,
+}
+
+impl DomainName {
+    pub fn new(name: &str) -> DomainName {
+        DomainName {
+            name: name.trim_end_matches('.').to_string(),
+        }
+    }
+
+    pub fn root() -> DomainName {
+        DomainName::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    //The labels from left to right, so `www`, `google`, `com` for www.google.com.
+    //The root has no labels at all.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.name.split('.').filter(|label| !label.is_empty())
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    /// The name with its leftmost label removed, or None for the root
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(match self.name.split_once('.') {
+            Some((_, parent)) => DomainName { name: parent.to_string() },
+            None => DomainName::root(),
+        })
+    }
+
+    /// Iterates over this name and all of its parents, ending with the root
+    pub fn ancestors(&self) -> impl Iterator<Item = DomainName> {
+        std::iter::successors(Some(self.clone()), |name| name.parent())
+    }
+
+    /// The rightmost `count` labels of this name, so `suffix(2)` of
+    /// www.google.com is google.com
+    pub fn suffix(&self, count: usize) -> DomainName {
+        let labels: Vec<&str> = self.labels().collect();
+        let start = labels.len().saturating_sub(count);
+        DomainName {
+            name: labels[start..].join("."),
+        }
+    }
+
+    /// Whether this name is `other` or anywhere below it
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        let count = other.label_count();
+        self.label_count() >= count && self.suffix(count) == *other
+    }
+
+    /// The same name in lowercase, the canonical form that goes into signatures
+    pub fn to_lowercase(&self) -> DomainName {
+        DomainName {
+            name: self.name.to_ascii_lowercase(),
+        }
+    }
+}
+
+//Labels compare by their lowercase bytes, which is what RFC 4034 orders by
+fn lowercase(label: &str) -> impl Iterator<Item = u8> + '_ {
+    label.bytes().map(|b| b.to_ascii_lowercase())
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in lowercase(&self.name) {
+            state.write_u8(b);
+        }
+        state.write_u8(0xff);
+    }
+}
+
+impl Ord for DomainName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.labels()
+            .rev()
+            .map(lowercase)
+            .zip(other.labels().rev().map(lowercase))
+            .map(|(a, b)| a.cmp(b))
+            .find(|order| order.is_ne())
+            .unwrap_or_else(|| self.label_count().cmp(&other.label_count()))
+    }
+}
+
+impl PartialOrd for DomainName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<&str> for DomainName {
+    fn from(name: &str) -> Self {
+        DomainName::new(name)
+    }
+}
+
+impl From<String> for DomainName {
+    fn from(name: String) -> Self {
+        DomainName::new(&name)
+    }
+}
+
+//Printed the same way a plain string would be, so that logs of packets and
+//records look the same as before
+impl fmt::Debug for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.name, f)
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_root() {
+            write!(f, ".")
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn name(s: &str) -> DomainName {
+        DomainName::new(s)
+    }
+
+    #[test]
+    fn names_keep_their_case_but_compare_without_it() {
+        let mixed = name("WwW.Example.COM.");
+        assert_eq!(mixed.as_str(), "WwW.Example.COM");
+        assert_eq!(mixed.to_string(), "WwW.Example.COM");
+        assert_eq!(mixed.to_lowercase().as_str(), "www.example.com");
+        assert_eq!(mixed, name("www.example.com"));
+        assert_ne!(mixed, name("www.example.co"));
+
+        let set: HashSet<DomainName> = [name("www.example.com")].into_iter().collect();
+        assert!(set.contains(&mixed));
+    }
+
+    #[test]
+    fn subdomains_go_by_whole_labels() {
+        assert!(name("www.example.com").is_subdomain_of(&name("example.com")));
+        assert!(name("example.com").is_subdomain_of(&name("example.com")));
+        assert!(name("WWW.EXAMPLE.com").is_subdomain_of(&name("example.COM")));
+        assert!(!name("notexample.com").is_subdomain_of(&name("example.com")));
+        assert!(!name("example.com").is_subdomain_of(&name("www.example.com")));
+        assert!(!name("com").is_subdomain_of(&name("example.com")));
+
+        assert!(name("example.com").is_subdomain_of(&DomainName::root()));
+        assert!(DomainName::root().is_subdomain_of(&DomainName::root()));
+        assert!(!DomainName::root().is_subdomain_of(&name("com")));
+    }
+
+    #[test]
+    fn labels_and_parents() {
+        let www = name("www.google.com");
+        assert_eq!(www.labels().collect::<Vec<_>>(), ["www", "google", "com"]);
+        assert_eq!(www.label_count(), 3);
+        assert_eq!(DomainName::root().labels().count(), 0);
+        assert_eq!(name(".").label_count(), 0);
+
+        assert_eq!(www.parent(), Some(name("google.com")));
+        assert_eq!(name("com").parent(), Some(DomainName::root()));
+        assert_eq!(DomainName::root().parent(), None);
+        assert_eq!(
+            www.ancestors().collect::<Vec<_>>(),
+            [name("www.google.com"), name("google.com"), name("com"), DomainName::root()]
+        );
+        assert_eq!(www.suffix(2), name("google.com"));
+        assert_eq!(www.suffix(5), www);
+        assert_eq!(www.suffix(0), DomainName::root());
+    }
+
+    #[test]
+    fn canonical_order_of_rfc_4034() {
+        //The example of section 6.1 without its escaped labels, already in order
+        let ordered: Vec<DomainName> = ["example", "a.example", "yljkjljk.a.example", "Z.a.example", "zABC.a.EXAMPLE", "z.example", "*.z.example"]
+            .iter()
+            .map(|s| name(s))
+            .collect();
+        let mut sorted = ordered.clone();
+        sorted.reverse();
+        sorted.sort();
+        assert_eq!(sorted, ordered);
+
+        assert!(DomainName::root() < name("com"));
+        assert!(name("a.example") < name("B.example"));
+        assert!(name("B.example") < name("a.b.example"));
+        assert_eq!(name("Z.a.example").cmp(&name("z.A.example")), Ordering::Equal);
+    }
+}
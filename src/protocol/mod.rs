@@ -5,4 +5,7 @@ pub mod querytype;
 pub mod resultcode;
 pub mod dnsheader;
 pub mod dnsquestion;
+pub mod opcode;
+pub mod tsigrecord;
+pub mod domainname;
 pub mod main;
\ No newline at end of file
@@ -0,0 +1,37 @@
+/// The kind of message, from the header's 4 bit OPCODE field
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Unknown(u8),
+    Query,  // 0
+    IQuery, // 1, obsolete (RFC 3425)
+    Status, // 2
+    Notify, // 4, RFC 1996
+    Update, // 5, RFC 2136
+    Dso,    // 6, RFC 8490
+}
+
+impl Opcode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            Opcode::Unknown(x) => x,
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Dso => 6,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            6 => Opcode::Dso,
+            _ => Opcode::Unknown(num),
+        }
+    }
+}
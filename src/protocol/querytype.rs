@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
 pub enum QueryType {
     Unknown(u16),
@@ -5,9 +8,18 @@ pub enum QueryType {
     NS, // 2
     Cname, // 5
     Soa, // 6
+    Ptr, // 12
     MX , // 15
     Txt, // 16
     Aaaa, // 28
+    Srv, // 33
+    Dname, // 39
+    Opt, // 41
+    Nsec, // 47
+    Tsig, // 250
+    Ixfr, // 251
+    Axfr, // 252
+    Any, // 255
 }
 
 impl QueryType {
@@ -18,9 +30,18 @@ impl QueryType {
             QueryType::NS => 2,
             QueryType::Cname => 5,
             QueryType::Soa => 6,
+            QueryType::Ptr => 12,
             QueryType::MX => 15,
             QueryType::Txt => 16,
             QueryType::Aaaa => 28,
+            QueryType::Srv => 33,
+            QueryType::Dname => 39,
+            QueryType::Opt => 41,
+            QueryType::Nsec => 47,
+            QueryType::Tsig => 250,
+            QueryType::Ixfr => 251,
+            QueryType::Axfr => 252,
+            QueryType::Any => 255,
         }
     }
 
@@ -30,10 +51,74 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::Cname,
             6 => QueryType::Soa,
+            12 => QueryType::Ptr,
             15 => QueryType::MX,
             16 => QueryType::Txt,
             28 => QueryType::Aaaa,
+            33 => QueryType::Srv,
+            39 => QueryType::Dname,
+            41 => QueryType::Opt,
+            47 => QueryType::Nsec,
+            250 => QueryType::Tsig,
+            251 => QueryType::Ixfr,
+            252 => QueryType::Axfr,
+            255 => QueryType::Any,
             _ => QueryType::Unknown(num),
         }
     }
-}
\ No newline at end of file
+}
+
+//Parses the usual names of the types (`A`, `AAAA`, ...) in any case, and the
+//`TYPE123` form of RFC 3597 for everything else
+impl FromStr for QueryType {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_uppercase();
+        match s.as_str() {
+            "A" => Ok(QueryType::A),
+            "NS" => Ok(QueryType::NS),
+            "CNAME" => Ok(QueryType::Cname),
+            "SOA" => Ok(QueryType::Soa),
+            "PTR" => Ok(QueryType::Ptr),
+            "MX" => Ok(QueryType::MX),
+            "TXT" => Ok(QueryType::Txt),
+            "AAAA" => Ok(QueryType::Aaaa),
+            "SRV" => Ok(QueryType::Srv),
+            "DNAME" => Ok(QueryType::Dname),
+            "NSEC" => Ok(QueryType::Nsec),
+            "TSIG" => Ok(QueryType::Tsig),
+            "IXFR" => Ok(QueryType::Ixfr),
+            "AXFR" => Ok(QueryType::Axfr),
+            "ANY" => Ok(QueryType::Any),
+            _ => match s.strip_prefix("TYPE").and_then(|num| num.parse::<u16>().ok()) {
+                Some(num) => Ok(QueryType::from_num(num)),
+                None => Err(format!("Unknown query type {:?}", s).into()),
+            },
+        }
+    }
+}
+
+//The other way round, the name `FromStr` reads back
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryType::A => write!(f, "A"),
+            QueryType::NS => write!(f, "NS"),
+            QueryType::Cname => write!(f, "CNAME"),
+            QueryType::Soa => write!(f, "SOA"),
+            QueryType::Ptr => write!(f, "PTR"),
+            QueryType::MX => write!(f, "MX"),
+            QueryType::Txt => write!(f, "TXT"),
+            QueryType::Aaaa => write!(f, "AAAA"),
+            QueryType::Srv => write!(f, "SRV"),
+            QueryType::Dname => write!(f, "DNAME"),
+            QueryType::Nsec => write!(f, "NSEC"),
+            QueryType::Tsig => write!(f, "TSIG"),
+            QueryType::Ixfr => write!(f, "IXFR"),
+            QueryType::Axfr => write!(f, "AXFR"),
+            QueryType::Any => write!(f, "ANY"),
+            QueryType::Opt | QueryType::Unknown(_) => write!(f, "TYPE{}", self.to_num()),
+        }
+    }
+}
@@ -6,18 +6,29 @@ pub enum ResultCode {
     NXDomain = 3,
     NotImp = 4,
     Refused = 5,
+    YXDomain = 6,
+    YXRRSet = 7,
+    NXRRSet = 8,
+    NotAuth = 9,
+    NotZone = 10,
 }
 
 impl ResultCode {
     pub fn from_num(num: u8) -> ResultCode {
         match num {
+            0 => ResultCode::NoError,
             1 => ResultCode::FormErr,
             2 => ResultCode::ServFail,
             3 => ResultCode::NXDomain,
             4 => ResultCode::NotImp,
             5 => ResultCode::Refused,
-            0 => ResultCode::NoError,
-            _ => ResultCode::NoError,
+            6 => ResultCode::YXDomain,
+            7 => ResultCode::YXRRSet,
+            8 => ResultCode::NXRRSet,
+            9 => ResultCode::NotAuth,
+            10 => ResultCode::NotZone,
+            //Codes we don't know can't be taken for success
+            _ => ResultCode::ServFail,
         }
     }
 }
\ No newline at end of file
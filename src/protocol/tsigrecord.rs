@@ -0,0 +1,126 @@
+use std::error::Error;
+
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+
+//TSIG records are always of class ANY and have a ttl of 0
+const CLASS_ANY: u16 = 255;
+
+/// A TSIG record (RFC 8945 section 4.2), which signs the message it comes
+/// last in. It belongs to that one message and is never stored or cached, so
+/// it's kept apart from `DnsRecord`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsigRecord {
+    pub key_name: DomainName,
+    pub algorithm: DomainName,
+    //Seconds since the epoch, in 48 bits
+    pub time_signed: u64,
+    //How far off the clocks of the two sides may be, in seconds
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    //The id of the message when it was signed
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl TsigRecord {
+    /// Reads the record at the buffer's position. None if it's not a TSIG record.
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Option<TsigRecord>, Box<dyn Error>> {
+        let key_name = buffer.read_name()?;
+        if buffer.read_u16()? != QueryType::Tsig.to_num() {
+            return Ok(None);
+        }
+        let _ = buffer.read_u16()?; // class
+        let _ = buffer.read_u32()?; // ttl
+        let data_len = buffer.read_u16()? as usize;
+        let end = buffer.pos() + data_len;
+
+        let algorithm = buffer.read_name()?;
+        let time_signed = (buffer.read_u16()? as u64) << 32 | buffer.read_u32()? as u64;
+        let fudge = buffer.read_u16()?;
+        let mac_len = buffer.read_u16()? as usize;
+        let mac = read_bytes(buffer, mac_len)?;
+        let original_id = buffer.read_u16()?;
+        let error = buffer.read_u16()?;
+        let other_len = buffer.read_u16()? as usize;
+        let other = read_bytes(buffer, other_len)?;
+
+        if buffer.pos() != end {
+            return Err("The length of the TSIG record doesn't match its data".into());
+        }
+
+        Ok(Some(TsigRecord {
+            key_name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        }))
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<(), Box<dyn Error>> {
+        buffer.write_qname(self.key_name.as_str())?;
+        buffer.write_u16(QueryType::Tsig.to_num())?;
+        buffer.write_u16(CLASS_ANY)?;
+        buffer.write_u32(0)?;
+
+        let len_pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        buffer.write_qname(self.algorithm.as_str())?;
+        self.write_timers(buffer)?;
+        buffer.write_u16(self.mac.len() as u16)?;
+        write_bytes(buffer, &self.mac)?;
+        buffer.write_u16(self.original_id)?;
+        buffer.write_u16(self.error)?;
+        buffer.write_u16(self.other.len() as u16)?;
+        write_bytes(buffer, &self.other)?;
+
+        let size = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, size as u16)?;
+        Ok(())
+    }
+
+    /// The fields that go into the MAC along with the message (section 4.3.3).
+    /// Messages after the first of a zone transfer only cover the timers.
+    pub fn variables(&self, timers_only: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = BytePacketBuffer::with_size(2 * 256 + 16 + self.other.len());
+        //Names go in lowercase and uncompressed, we never compress them anyway
+        if !timers_only {
+            buffer.write_qname(self.key_name.to_lowercase().as_str())?;
+            buffer.write_u16(CLASS_ANY)?;
+            buffer.write_u32(0)?;
+            buffer.write_qname(self.algorithm.to_lowercase().as_str())?;
+        }
+        self.write_timers(&mut buffer)?;
+        if !timers_only {
+            buffer.write_u16(self.error)?;
+            buffer.write_u16(self.other.len() as u16)?;
+            write_bytes(&mut buffer, &self.other)?;
+        }
+        Ok(buffer.get_range(0, buffer.pos())?.to_vec())
+    }
+
+    fn write_timers(&self, buffer: &mut BytePacketBuffer) -> Result<(), Box<dyn Error>> {
+        buffer.write_u16((self.time_signed >> 32) as u16)?;
+        buffer.write_u32(self.time_signed as u32)?;
+        buffer.write_u16(self.fudge)?;
+        Ok(())
+    }
+}
+
+fn read_bytes(buffer: &mut BytePacketBuffer, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    (0..len).map(|_| buffer.read()).collect()
+}
+
+fn write_bytes(buffer: &mut BytePacketBuffer, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    for &byte in bytes {
+        buffer.write(byte)?;
+    }
+    Ok(())
+}
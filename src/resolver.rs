@@ -0,0 +1,1150 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cache::{CacheConfig, CacheKey, DnsCache};
+use crate::dns64::Dns64Config;
+use crate::forwarder::{find_forward_zone, ForwardZone, Forwarder};
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::{DnsRecord, EDNS_DNSSEC_OK};
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::scrub::scrub_response;
+use crate::server_selection::ServerSelection;
+use crate::trace::TraceStep;
+use crate::udp;
+
+//The IPv4 addresses of a.root-servers.net through m.root-servers.net, where
+//every lookup starts when the cache can't help, unless configured otherwise
+const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+//How long we wait for an upstream server to answer before giving up on it,
+//unless configured otherwise
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+//RFC 9156 caps the number of minimised queries for a single name, after that
+//whatever is left of the name is sent in one go
+const MAX_MINIMISE_COUNT: usize = 10;
+
+//Clients waiting on the same lookup, unless configured otherwise
+const MAX_WAITERS_PER_QUERY: usize = 100;
+
+/// How much of the query name we reveal to the servers above the target zone (RFC 9156)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QnameMinimisation {
+    //Always send the full name, like resolvers traditionally did
+    Off,
+    //Minimise, but fall back to the full name when a server chokes on the
+    //minimised queries (errors, timeouts or a bogus NXDOMAIN for an empty non-terminal)
+    Relaxed,
+    //Minimise and never fall back, an error or NXDOMAIN for a parent name is final
+    Strict,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(QnameMinimisation::Off),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            "strict" => Ok(QnameMinimisation::Strict),
+            _ => Err(format!("Unknown QNAME minimisation mode {:?}, expected off, relaxed or strict", s).into()),
+        }
+    }
+}
+
+/// Caps on the amount of work a single client query may cause, so that a
+/// crafted delegation chain can't turn one query into unbounded work upstream
+#[derive(Clone, Copy, Debug)]
+pub struct ResolverLimits {
+    //Referrals followed while resolving a single name
+    pub max_referrals: usize,
+    //How deep lookups of glueless nameserver names may nest
+    pub max_depth: usize,
+    //Lookups of nameserver names without glue, in total
+    pub max_ns_fetches: usize,
+    //Queries sent upstream, in total
+    pub max_queries: usize,
+    //Time spent resolving before we give up
+    pub max_time: Duration,
+}
+
+impl Default for ResolverLimits {
+    fn default() -> Self {
+        ResolverLimits {
+            max_referrals: 20,
+            max_depth: 4,
+            max_ns_fetches: 8,
+            max_queries: 64,
+            max_time: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Where answers that aren't in the cache come from
+#[derive(Clone, Debug)]
+pub enum ResolutionMode {
+    //Walk the delegations down from the root ourselves
+    Recursive,
+    //Hand the query to other resolvers and pass their answer on
+    Forwarding(Forwarder),
+    //Don't resolve at all, only our own zones get answered
+    Authoritative,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolverConfig {
+    pub mode: ResolutionMode,
+    //Conditional forwarding rules, checked before the mode is
+    pub forward_zones: Vec<ForwardZone>,
+    pub cache: CacheConfig,
+    pub qname_minimisation: QnameMinimisation,
+    pub limits: ResolverLimits,
+    //Synthesise AAAA records for IPv6-only clients, off unless configured
+    pub dns64: Option<Dns64Config>,
+    //Where recursive lookups start
+    pub root_servers: Vec<Ipv4Addr>,
+    pub upstream_timeout: Duration,
+    //Clients that may wait on a single in-flight resolution, anyone beyond that
+    //gets SERVFAIL right away instead of adding to the pile
+    pub max_waiters_per_query: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            mode: ResolutionMode::Recursive,
+            forward_zones: Vec::new(),
+            cache: CacheConfig::default(),
+            qname_minimisation: QnameMinimisation::Relaxed,
+            limits: ResolverLimits::default(),
+            dns64: None,
+            root_servers: ROOT_SERVERS.to_vec(),
+            upstream_timeout: UPSTREAM_TIMEOUT,
+            max_waiters_per_query: MAX_WAITERS_PER_QUERY,
+        }
+    }
+}
+
+//The work done so far on behalf of one client query, checked against the
+//`ResolverLimits` every time more is about to be done
+struct Budget {
+    limits: ResolverLimits,
+    started: Instant,
+    queries: usize,
+    ns_fetches: usize,
+    depth: usize,
+    //Every query sent upstream, only collected when tracing
+    trace: Option<Vec<TraceStep>>,
+}
+
+impl Budget {
+    fn new(limits: ResolverLimits) -> Budget {
+        Budget {
+            limits,
+            started: Instant::now(),
+            queries: 0,
+            ns_fetches: 0,
+            depth: 0,
+            trace: None,
+        }
+    }
+
+    fn record(&mut self, step: impl FnOnce() -> TraceStep) {
+        if let Some(ref mut trace) = self.trace {
+            trace.push(step());
+        }
+    }
+
+    fn spend_query(&mut self) -> Result<(), Box<dyn Error>> {
+        self.queries += 1;
+        if self.queries > self.limits.max_queries {
+            return Err(format!("Gave up after {} upstream queries", self.limits.max_queries).into());
+        }
+        if self.started.elapsed() > self.limits.max_time {
+            return Err(format!("Gave up after {:?}", self.limits.max_time).into());
+        }
+        Ok(())
+    }
+
+    fn spend_ns_fetch(&mut self) -> Result<(), Box<dyn Error>> {
+        self.ns_fetches += 1;
+        if self.ns_fetches > self.limits.max_ns_fetches {
+            return Err(format!("Gave up after {} nameserver lookups", self.limits.max_ns_fetches).into());
+        }
+        if self.depth >= self.limits.max_depth {
+            return Err(format!("Nameserver lookups nested deeper than {}", self.limits.max_depth).into());
+        }
+        Ok(())
+    }
+}
+
+//A resolution that's under way. The clients waiting for it sleep on `done`
+//until the result shows up.
+#[derive(Default)]
+struct Pending {
+    state: Mutex<PendingState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct PendingState {
+    waiters: usize,
+    result: Option<Result<DnsPacket, String>>,
+}
+
+//The in-flight entry of the resolution we're running. Dropping it takes the
+//entry out and hands the result to everyone who showed up in the meantime,
+//which happens even when the resolution panics. Otherwise the entry would stay,
+//and every identical query after it would wait on it until it gives up.
+struct InFlightEntry<'a> {
+    in_flight: &'a Mutex<HashMap<CacheKey, Arc<Pending>>>,
+    key: CacheKey,
+    result: Option<Result<DnsPacket, String>>,
+}
+
+impl Drop for InFlightEntry<'_> {
+    fn drop(&mut self) {
+        //The locks may have been poisoned by the very panic we're cleaning up after
+        let pending = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+
+        if let Some(pending) = pending {
+            let mut state = pending.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.result = Some(
+                self.result
+                    .take()
+                    .unwrap_or_else(|| Err("The lookup failed".to_string())),
+            );
+            pending.done.notify_all();
+        }
+    }
+}
+
+/// A recursive resolver. It holds the cache, the nameserver round trip times
+/// and the settings shared by every lookup, including the ones running in the
+/// background.
+pub struct Resolver {
+    pub cache: Mutex<DnsCache>,
+    pub servers: Mutex<ServerSelection>,
+    pub config: ResolverConfig,
+    //Resolutions running right now, so that identical ones can wait for them
+    //instead of asking upstream again
+    in_flight: Mutex<HashMap<CacheKey, Arc<Pending>>>,
+}
+
+//Sends a single query to `server` and waits up to `timeout` for the reply.
+//With `dnssec_ok` the query carries an OPT record with the DO bit set, asking
+//for the DNSSEC records (NSEC and signatures) that go with the answer. Every
+//query gets a random id and port, and only an answer matching it is taken.
+pub fn lookup(
+    qname: &DomainName,
+    qtype: QueryType,
+    server: (Ipv4Addr, u16),
+    dnssec_ok: bool,
+    timeout: Duration,
+) -> Result<DnsPacket, Box<dyn Error>> {
+    let mut packet = DnsPacket::new();
+
+    packet.header.id = udp::random_id();
+    packet.header.questions = 1;
+    packet.header.recursion_desired = true;
+
+    packet.questions.push(DnsQuestion::new(qname.clone(), qtype));
+    if dnssec_ok {
+        //Our buffers are only 512 bytes, so that's all we can take
+        packet.resources.push(DnsRecord::Opt {
+            domain: DomainName::root(),
+            udp_size: 512,
+            flags: EDNS_DNSSEC_OK,
+        });
+    }
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    //Every lookup gets its own socket, lookups may run concurrently when
+    //refreshing the cache in the background
+    let (response, _) = udp::exchange(&packet, &req_buffer, SocketAddr::from(server), timeout)?;
+    Ok(response)
+}
+
+//The name to send next when minimising: `zone` with one more label of
+//`qname` in front of it. None once that would be the full name anyway.
+fn minimised_qname(qname: &DomainName, zone: &DomainName) -> Option<DomainName> {
+    let labels = zone.label_count() + 1;
+    if labels >= qname.label_count() {
+        return None;
+    }
+
+    Some(qname.suffix(labels))
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Resolver {
+        Resolver {
+            cache: Mutex::new(DnsCache::new(config.cache)),
+            servers: Mutex::new(ServerSelection::new()),
+            config,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Answers a client's question, from the cache if possible. When the answer
+    /// has to come from upstream and we still hold a stale copy of it, the client
+    /// gets the stale copy if resolution fails or takes too long (RFC 8767).
+    pub fn resolve(self: &Arc<Self>, qname: &DomainName, qtype: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        let (fresh, prefetches) = {
+            let mut cache = self.cache.lock().unwrap();
+            (cache.lookup(qname, qtype), cache.take_prefetches())
+        };
+
+        //Popular entries that are about to expire get refreshed in the background,
+        //so the next client doesn't have to wait for them. Those being looked up
+        //already are left to that lookup.
+        for key in prefetches {
+            if self.claim(&key).is_some() {
+                continue;
+            }
+            println!("Prefetching {:?} {}", key.qtype, key.name);
+            let resolver = Arc::clone(self);
+            thread::spawn(move || {
+                let name = key.name.clone();
+                if let Err(e) = resolver.resolve_claimed(key) {
+                    eprintln!("Prefetch of {} failed : {}", name, e);
+                }
+            });
+        }
+
+        //If we've seen the answer recently, there's no need to ask anyone
+        //This includes negative answers, a name that didn't exist a minute ago
+        //most likely still doesn't
+        if let Some(packet) = fresh {
+            println!("Cache hit for {:?} {} ({:?})", qtype, qname, packet.header.rescode);
+            return Ok(packet);
+        }
+
+        let stale = self.cache.lock().unwrap().lookup_stale(qname, qtype);
+        let stale = match stale {
+            Some(stale) => stale,
+            None => return self.resolve_shared(qname, qtype),
+        };
+
+        //We have something to fall back on, so only wait for a fresh answer for as
+        //long as the client response timer allows. A lookup we start keeps running
+        //in the background after the timer fires, and refreshes the cache once
+        //it's done. Every client after us waits on that same lookup.
+        let key = CacheKey::new(qname, qtype);
+        let timeout = self.config.cache.client_response_timeout;
+        let result = match self.claim(&key) {
+            Some(pending) => self.wait_for(&pending, qname, qtype, timeout),
+            None => {
+                let (tx, rx) = mpsc::channel();
+                let resolver = Arc::clone(self);
+                thread::spawn(move || {
+                    let result = resolver.resolve_claimed(key).map_err(|e| e.to_string());
+                    let _ = tx.send(result);
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(result) => result.map_err(Into::into),
+                    Err(_) => Err(format!("No fresh answer for {} in time", qname).into()),
+                }
+            }
+        };
+
+        match result {
+            Ok(packet) if packet.header.rescode != ResultCode::ServFail => Ok(packet),
+            _ => {
+                println!("Serving stale answer for {:?} {}", qtype, qname);
+                self.cache.lock().unwrap().served_stale();
+                Ok(stale)
+            }
+        }
+    }
+
+    /// Resolves a name from scratch, starting at the root servers (or at the
+    /// forwarders), and returns every query that was sent on the way along with
+    /// the result. Whatever is learned still goes into the cache.
+    pub fn trace(&self, qname: &DomainName, qtype: QueryType) -> (Result<DnsPacket, Box<dyn Error>>, Vec<TraceStep>) {
+        let mut budget = Budget::new(self.config.limits);
+        budget.trace = Some(Vec::new());
+
+        let result = self.resolve_upstream(qname, qtype, &mut budget);
+        (result, budget.trace.unwrap_or_default())
+    }
+
+    //Resolves a client query upstream, unless the very same query is already
+    //being resolved for someone else, in which case we wait for that to finish
+    //and share its answer. Only client queries and refreshes are coalesced,
+    //lookups of nameserver names inside a resolution aren't, so resolutions
+    //never end up waiting on each other.
+    fn resolve_shared(&self, qname: &DomainName, qtype: QueryType) -> Result<DnsPacket, Box<dyn Error>> {
+        let key = CacheKey::new(qname, qtype);
+        match self.claim(&key) {
+            Some(pending) => self.wait_for(&pending, qname, qtype, self.config.limits.max_time),
+            None => self.resolve_claimed(key),
+        }
+    }
+
+    //Marks the lookup of `key` as in flight and returns None, which makes it
+    //ours to run with `resolve_claimed`. If it's in flight already, that's the
+    //lookup to wait for instead.
+    fn claim(&self, key: &CacheKey) -> Option<Arc<Pending>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(pending) = in_flight.get(key) {
+            return Some(Arc::clone(pending));
+        }
+        in_flight.insert(key.clone(), Arc::new(Pending::default()));
+        None
+    }
+
+    //Waits up to `timeout` for a lookup someone else is running
+    fn wait_for(&self, pending: &Pending, qname: &DomainName, qtype: QueryType, timeout: Duration) -> Result<DnsPacket, Box<dyn Error>> {
+        let mut state = pending.state.lock().unwrap();
+        if state.waiters >= self.config.max_waiters_per_query {
+            return Err(format!("Too many clients waiting for {:?} {}", qtype, qname).into());
+        }
+        state.waiters += 1;
+
+        println!("Waiting for the in-flight lookup of {:?} {}", qtype, qname);
+        let (state, _) = pending
+            .done
+            .wait_timeout_while(state, timeout, |state| state.result.is_none())
+            .unwrap();
+
+        match state.result {
+            Some(Ok(ref packet)) => Ok(packet.clone()),
+            Some(Err(ref e)) => Err(e.clone().into()),
+            None => Err(format!("Gave up waiting for the in-flight lookup of {}", qname).into()),
+        }
+    }
+
+    //Runs the lookup of `key` after `claim` made it ours
+    fn resolve_claimed(&self, key: CacheKey) -> Result<DnsPacket, Box<dyn Error>> {
+        let (qname, qtype) = (key.name.clone(), key.qtype);
+        let mut entry = InFlightEntry {
+            in_flight: &self.in_flight,
+            key,
+            result: None,
+        };
+        let result = self.resolve_upstream(&qname, qtype, &mut Budget::new(self.config.limits));
+
+        //Dropping the entry hands the result to everyone who showed up in the
+        //meantime. The entry goes first, so that clients arriving from now on get
+        //the answer from the cache.
+        entry.result = Some(match result {
+            Ok(ref packet) => Ok(packet.clone()),
+            Err(ref e) => Err(e.to_string()),
+        });
+        drop(entry);
+
+        result
+    }
+
+    //Implementing recursive lookup
+    fn recursive_lookup(&self, qname: &DomainName, qtype: QueryType, budget: &mut Budget) -> Result<DnsPacket, Box<dyn Error>> {
+        //If we've seen the answer recently, there's no need to ask anyone
+        if let Some(packet) = self.cache.lock().unwrap().lookup(qname, qtype) {
+            return Ok(packet);
+        }
+
+        self.resolve_upstream(qname, qtype, budget)
+    }
+
+    //The part of the lookup that actually talks to other servers, either the
+    //authoritative ones or the forwarders, depending on the mode. This skips the
+    //cache check so it can also be used to refresh existing entries.
+    //Every query it sends is charged to `budget`, and it fails once that runs out.
+    fn resolve_upstream(&self, qname: &DomainName, qtype: QueryType, budget: &mut Budget) -> Result<DnsPacket, Box<dyn Error>> {
+        //Names with a conditional forwarding rule go to that rule's upstreams first
+        if let Some(rule) = find_forward_zone(&self.config.forward_zones, qname) {
+            match self.forward(&rule.forwarder, qname, qtype, budget) {
+                Ok(packet) => return Ok(packet),
+                Err(e) if rule.fallback => {
+                    println!("Forwarders for {} failed ({}), resolving {} recursively", rule.zone, e, qname);
+                    return self.iterate(qname, qtype, budget);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.config.mode {
+            ResolutionMode::Recursive => self.iterate(qname, qtype, budget),
+            ResolutionMode::Forwarding(ref forwarder) => self.forward(forwarder, qname, qtype, budget),
+            ResolutionMode::Authoritative => Err(format!("Not resolving {}, we only answer for our own zones", qname).into()),
+        }
+    }
+
+    //Sends the query to the upstreams of `forwarder`, moving on to the next one
+    //when an upstream doesn't answer or can't resolve the name either
+    fn forward(
+        &self,
+        forwarder: &Forwarder,
+        qname: &DomainName,
+        qtype: QueryType,
+        budget: &mut Budget,
+    ) -> Result<DnsPacket, Box<dyn Error>> {
+        let upstreams = forwarder.order(&self.servers.lock().unwrap());
+        let mut last_error: Box<dyn Error> = "No upstream servers configured".into();
+
+        for (addr, port) in upstreams {
+            budget.spend_query()?;
+            println!("Forwarding {:?} {} to {}:{}", qtype, qname, addr, port);
+
+            let started = Instant::now();
+            //We can't validate DNSSEC ourselves, but the upstreams we forward to
+            //are trusted to, and their AD bit tells us when they did
+            let dnssec_ok = self.config.cache.aggressive_nsec;
+            let step = TraceStep::new(budget.depth, (addr, port), &DomainName::root(), qname, qtype);
+            let mut response = match lookup(qname, qtype, (addr, port), dnssec_ok, self.config.upstream_timeout) {
+                Ok(response) => {
+                    self.servers.lock().unwrap().record_rtt(addr, started.elapsed());
+                    response
+                }
+                Err(e) => {
+                    println!("Upstream {}:{} failed ({})", addr, port, e);
+                    self.servers.lock().unwrap().record_timeout(addr, self.config.upstream_timeout);
+                    budget.record(|| step.failed(&e.to_string(), started.elapsed()));
+                    last_error = e;
+                    continue;
+                }
+            };
+            let rtt = started.elapsed();
+
+            if matches!(response.header.rescode, ResultCode::ServFail | ResultCode::Refused) {
+                println!("Upstream {}:{} returned {:?}", addr, port, response.header.rescode);
+                budget.record(|| step.answered(&response, rtt));
+                last_error = format!("Upstream {}:{} returned {:?}", addr, port, response.header.rescode).into();
+                continue;
+            }
+
+            //A forwarder may answer for any zone, but it still has no business
+            //telling us about names we didn't ask for
+            for rec in scrub_response(&mut response, qname, &DomainName::root()) {
+                println!("Dropped unrelated record from {}:{}: {:?}", addr, port, rec);
+            }
+            budget.record(|| step.answered(&response, rtt));
+
+            let mut cache = self.cache.lock().unwrap();
+            cache.store_packet(&response);
+            cache.store_negative(qname, qtype, &response);
+            //We can't validate, so the forwarder's word that it did is all we have
+            if response.header.authed_data {
+                cache.store_validated_denials(&response);
+            }
+
+            return Ok(response);
+        }
+
+        Err(last_error)
+    }
+
+    //Resolves a name by following the delegations from the closest known zone
+    //cut down to the servers that are authoritative for it
+    fn iterate(&self, qname: &DomainName, qtype: QueryType, budget: &mut Budget) -> Result<DnsPacket, Box<dyn Error>> {
+        //We start at the closest zone cut we know a nameserver for,
+        //falling back to the root when the cache has nothing. `zone` is always
+        //the zone the current nameserver is an authority for.
+        //A trace always starts at the root, to show the whole path
+        let closest = match budget.trace {
+            Some(_) => None,
+            None => self.cache.lock().unwrap().closest_nameservers(qname),
+        };
+        let (mut zone, mut candidates) = match closest {
+            Some((zone, addrs)) => {
+                println!("Starting lookup of {} at cached zone cut {:?}", qname, zone);
+                (zone, addrs)
+            }
+            None => (DomainName::root(), self.config.root_servers.clone()),
+        };
+
+        let mode = self.config.qname_minimisation;
+        let mut minimise = mode != QnameMinimisation::Off;
+        let mut minimise_count = 0;
+        //How much of the name has been revealed to the current nameserver
+        let mut revealed = zone.clone();
+        let mut referrals = 0;
+
+        //Since it might take an arbitrary number of queries to get to the final answer,
+        //We start the loop
+        loop {
+            //With minimisation on, the servers above the target zone only get to
+            //see the next label of the name, asked for as a plain A query
+            let minimised = match minimised_qname(qname, &revealed) {
+                Some(name) if minimise && minimise_count < MAX_MINIMISE_COUNT => Some(name),
+                _ => None,
+            };
+            let (query_name, query_type) = match minimised {
+                Some(ref name) => (name, QueryType::A),
+                None => (qname, qtype),
+            };
+
+            //Out of the nameservers for the current zone, we ask the one that has
+            //been the fastest to answer so far
+            let ns = match self.servers.lock().unwrap().select(&candidates) {
+                Some(ns) => ns,
+                None => return Err(format!("No nameserver left to ask for {}", qname).into()),
+            };
+
+            budget.spend_query()?;
+            println!("Attempting lookup of {:?} {} with ns {}", query_type, query_name, ns);
+
+            //The next step is to send the query to the active server
+            let server = (ns, 53);
+            let started = Instant::now();
+            let step = TraceStep::new(budget.depth, server, &zone, query_name, query_type);
+            let mut response = match lookup(query_name, query_type, server, false, self.config.upstream_timeout) {
+                Ok(response) => {
+                    self.servers.lock().unwrap().record_rtt(ns, started.elapsed());
+                    response
+                }
+                Err(e) => {
+                    //The server didn't answer, so it's off the list for this lookup
+                    //and we try the next best one
+                    self.servers.lock().unwrap().record_timeout(ns, self.config.upstream_timeout);
+                    budget.record(|| step.failed(&e.to_string(), started.elapsed()));
+                    candidates.retain(|addr| *addr != ns);
+
+                    if minimised.is_some() && mode == QnameMinimisation::Relaxed {
+                        println!("Minimised query to {} failed ({}), retrying with the full name", ns, e);
+                        minimise = false;
+                    } else {
+                        println!("Query to {} failed ({})", ns, e);
+                    }
+
+                    if candidates.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            //Whatever the server said about names outside its own zone, or about
+            //things we didn't ask for, is thrown away before we look at the response
+            for rec in scrub_response(&mut response, query_name, &zone) {
+                println!("Dropped out-of-bailiwick record from {} (zone {:?}): {:?}", ns, zone, rec);
+            }
+            budget.record(|| step.answered(&response, started.elapsed()));
+
+            if minimised.is_some() {
+                minimise_count += 1;
+
+                match response.header.rescode {
+                    ResultCode::NoError => {}
+
+                    //An NXDOMAIN for a parent means our name can't exist either (RFC 8020).
+                    //Broken servers send one for empty non-terminals though, which is
+                    //why relaxed mode asks again with the full name.
+                    ResultCode::NXDomain if mode == QnameMinimisation::Strict => {
+                        self.cache.lock().unwrap().store_negative(query_name, query_type, &response);
+                        return Ok(response);
+                    }
+
+                    _ if mode == QnameMinimisation::Relaxed => {
+                        println!(
+                            "Minimised query to {} returned {:?}, retrying with the full name",
+                            ns, response.header.rescode
+                        );
+                        minimise = false;
+                        continue;
+                    }
+
+                    _ => return Ok(response),
+                }
+            }
+
+            //Everything we learn on the way, answers as well as referrals and glue,
+            //goes into the cache
+            self.cache.lock().unwrap().store_packet(&response);
+
+            //A minimised query that didn't get a referral means the name we asked
+            //for is inside the current zone, so we reveal one more label to the
+            //same server
+            if let Some(ref name) = minimised {
+                match response.get_ns(query_name).map(|(domain, _)| domain).next() {
+                    Some(cut) if cut.label_count() > zone.label_count() => {
+                        zone = cut.clone();
+                        revealed = zone.clone();
+                    }
+                    _ => {
+                        revealed = name.clone();
+                        continue;
+                    }
+                }
+            } else {
+                //If there are entries in the answer section, we can return the packet
+                if !response.answers.is_empty() && response.header.rescode == ResultCode::NoError {
+                    return Ok(response);
+                }
+
+                // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
+                // way of telling us that the name doesn't exist.
+                if response.header.rescode == ResultCode::NXDomain {
+                    self.cache.lock().unwrap().store_negative(qname, qtype, &response);
+                    return Ok(response);
+                }
+
+                if let Some(cut) = response.get_ns(qname).map(|(domain, _)| domain).next() {
+                    zone = cut.clone();
+                    revealed = zone.clone();
+                }
+            }
+
+            //A referral sends us somewhere else, which only happens so often for a single name
+            if response.get_ns(query_name).next().is_some() {
+                referrals += 1;
+                if referrals > self.config.limits.max_referrals {
+                    return Err(format!("Gave up on {} after {} referrals", qname, referrals - 1).into());
+                }
+            }
+
+            // Otherwise, we'll try to find new nameservers based on NS and corresponding A
+            // records in the additional section. If this succeeds, we can switch name servers
+            // and retry the loop.
+            let resolved = response.get_resolved_ns(query_name);
+            if !resolved.is_empty() {
+                candidates = resolved;
+
+                continue;
+            }
+
+            // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
+            // we'll go with what the last server told us.
+            // An empty NOERROR at this point is a NODATA answer, which we remember as well.
+            let new_ns_name = match response.get_unresolved_ns(query_name) {
+                Some(x) => x,
+                None => {
+                    self.cache.lock().unwrap().store_negative(qname, qtype, &response);
+                    return Ok(response);
+                }
+            };
+
+            // Here we go down the rabbit hole by starting _another_ lookup sequence in the
+            // midst of our current one. Hopefully, this will give us the IP of an appropriate
+            // name server.
+            budget.spend_ns_fetch()?;
+            budget.depth += 1;
+            let recursive_response = self.recursive_lookup(new_ns_name, QueryType::A, budget);
+            budget.depth -= 1;
+            let recursive_response = recursive_response?;
+
+            // Finally, every ip from the result becomes a candidate, and we restart the loop.
+            // If no such record is available, we again return the last result we got.
+            let addrs = recursive_response.get_a_addrs();
+            if addrs.is_empty() {
+                return Ok(response);
+            }
+            candidates = addrs;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forwarder::ForwardStrategy;
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+    use std::panic::{self, AssertUnwindSafe};
+
+    //A resolver forwarding to `upstream`
+    fn forwarding_to(upstream: &UdpSocket, cache: CacheConfig) -> Arc<Resolver> {
+        let port = upstream.local_addr().unwrap().port();
+        Arc::new(Resolver::new(ResolverConfig {
+            mode: ResolutionMode::Forwarding(Forwarder::new(vec![(Ipv4Addr::LOCALHOST, port)], ForwardStrategy::Sequential)),
+            cache,
+            ..ResolverConfig::default()
+        }))
+    }
+
+    //Answers the next query to `upstream` with an A record
+    fn answer_once(upstream: UdpSocket) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, client) = upstream.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions.clone();
+            response.answers.push(DnsRecord::A {
+                domain: request.questions[0].name.clone(),
+                addr: Ipv4Addr::new(192, 0, 2, 2),
+                ttl: 300,
+            });
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            upstream.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+        })
+    }
+
+    //Puts an A record in the cache that expires right away
+    fn cache_expiring(resolver: &Resolver, name: &DomainName) {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: name.clone(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 1,
+        });
+        resolver.cache.lock().unwrap().store_packet(&packet);
+        thread::sleep(Duration::from_millis(1100));
+    }
+
+    //A nameserver on port 53 of `ip` that answers the full `qname` but says
+    //NXDOMAIN for every name above it, like servers that don't know about empty
+    //non-terminals. Hands out the names it's asked for. None when we aren't
+    //allowed to listen on port 53.
+    fn broken_server(ip: Ipv4Addr, qname: &DomainName) -> Option<mpsc::Receiver<DomainName>> {
+        let socket = match UdpSocket::bind((ip, 53)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("Skipping, can't listen on {}:53: {}", ip, e);
+                return None;
+            }
+        };
+
+        let qname = qname.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, client) = socket.recv_from(&mut buffer.buf).unwrap();
+            let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+            let name = request.questions[0].name.clone();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.authoritative_answer = true;
+            response.questions = request.questions;
+            if name == qname {
+                response.answers.push(DnsRecord::A {
+                    domain: name.clone(),
+                    addr: Ipv4Addr::new(192, 0, 2, 7),
+                    ttl: 300,
+                });
+            } else {
+                response.header.rescode = ResultCode::NXDomain;
+            }
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            //Handed out before answering, so it's there once the resolver is done
+            if sender.send(name).is_err() {
+                return;
+            }
+            socket.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+        });
+        Some(receiver)
+    }
+
+    //A nameserver on port 53 of `ip` that never answers `qname`, but refers
+    //every query one label further down towards it, to itself again. None when
+    //we aren't allowed to listen on port 53.
+    fn referring_server(ip: Ipv4Addr, qname: &DomainName) -> Option<()> {
+        let socket = match UdpSocket::bind((ip, 53)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("Skipping, can't listen on {}:53: {}", ip, e);
+                return None;
+            }
+        };
+
+        let qname = qname.clone();
+        thread::spawn(move || {
+            for labels in 1.. {
+                let mut buffer = BytePacketBuffer::new();
+                let (_, client) = socket.recv_from(&mut buffer.buf).unwrap();
+                let request = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+                let cut = qname.suffix(labels);
+                let host = DomainName::new(&format!("ns.{}", cut));
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions;
+                response.authorities.push(DnsRecord::NS {
+                    domain: cut,
+                    host: host.clone(),
+                    ttl: 300,
+                });
+                response.resources.push(DnsRecord::A { domain: host, addr: ip, ttl: 300 });
+                let mut buffer = BytePacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+            }
+        });
+        Some(())
+    }
+
+    fn recursing_from(root: Ipv4Addr, qname_minimisation: QnameMinimisation) -> Arc<Resolver> {
+        Arc::new(Resolver::new(ResolverConfig {
+            qname_minimisation,
+            root_servers: vec![root],
+            upstream_timeout: Duration::from_millis(500),
+            ..ResolverConfig::default()
+        }))
+    }
+
+    #[test]
+    fn budgets_run_out() {
+        let limits = ResolverLimits {
+            max_queries: 2,
+            max_ns_fetches: 1,
+            max_depth: 1,
+            ..ResolverLimits::default()
+        };
+
+        let mut budget = Budget::new(limits);
+        assert!(budget.spend_query().is_ok());
+        assert!(budget.spend_query().is_ok());
+        assert!(budget.spend_query().is_err());
+
+        let mut budget = Budget::new(limits);
+        assert!(budget.spend_ns_fetch().is_ok());
+        assert!(budget.spend_ns_fetch().is_err());
+
+        let mut budget = Budget::new(limits);
+        budget.depth = 1;
+        assert!(budget.spend_ns_fetch().is_err());
+
+        let mut budget = Budget::new(ResolverLimits {
+            max_time: Duration::ZERO,
+            ..limits
+        });
+        thread::sleep(Duration::from_millis(1));
+        assert!(budget.spend_query().is_err());
+    }
+
+    #[test]
+    fn endless_referrals_give_up() {
+        let qname = DomainName::new("a.b.c.d.e.f.example.org");
+        if referring_server(Ipv4Addr::new(127, 0, 0, 31), &qname).is_none() {
+            return;
+        }
+
+        let limits = ResolverLimits {
+            max_referrals: 3,
+            ..ResolverLimits::default()
+        };
+        let resolver = Resolver::new(ResolverConfig {
+            qname_minimisation: QnameMinimisation::Off,
+            root_servers: vec![Ipv4Addr::new(127, 0, 0, 31)],
+            limits,
+            ..ResolverConfig::default()
+        });
+        let mut budget = Budget::new(limits);
+        let error = resolver.iterate(&qname, QueryType::A, &mut budget).unwrap_err();
+        assert!(error.to_string().contains("after 3 referrals"), "{}", error);
+        assert_eq!(budget.queries, 4);
+
+        //Each referral is a query as well, the query budget runs out first here
+        let mut budget = Budget::new(ResolverLimits {
+            max_queries: 2,
+            ..limits
+        });
+        let error = resolver.iterate(&qname, QueryType::A, &mut budget).unwrap_err();
+        assert!(error.to_string().contains("2 upstream queries"), "{}", error);
+    }
+
+    #[test]
+    fn forward_zones_fall_back_to_recursion_only_when_asked_to() {
+        let qname = DomainName::new("www.corp.internal");
+        let _asked = match broken_server(Ipv4Addr::new(127, 0, 0, 33), &qname) {
+            Some(asked) => asked,
+            None => return,
+        };
+        //Nobody listens there, the upstream of the rule never answers
+        let gone = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let resolver = |fallback: bool| {
+            Arc::new(Resolver::new(ResolverConfig {
+                forward_zones: vec![ForwardZone {
+                    zone: DomainName::new("corp.internal"),
+                    forwarder: Forwarder::new(vec![(Ipv4Addr::LOCALHOST, gone)], ForwardStrategy::Sequential),
+                    fallback,
+                }],
+                qname_minimisation: QnameMinimisation::Off,
+                root_servers: vec![Ipv4Addr::new(127, 0, 0, 33)],
+                upstream_timeout: Duration::from_millis(200),
+                ..ResolverConfig::default()
+            }))
+        };
+
+        assert!(resolver(false).resolve(&qname, QueryType::A).is_err());
+        let packet = resolver(true).resolve(&qname, QueryType::A).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn minimised_names_reveal_one_label_at_a_time() {
+        let qname = DomainName::new("www.sub.example.org");
+        assert_eq!(minimised_qname(&qname, &DomainName::root()), Some(DomainName::new("org")));
+        assert_eq!(minimised_qname(&qname, &DomainName::new("org")), Some(DomainName::new("example.org")));
+        assert_eq!(minimised_qname(&qname, &DomainName::new("example.org")), Some(DomainName::new("sub.example.org")));
+        assert_eq!(minimised_qname(&qname, &DomainName::new("sub.example.org")), None);
+        assert_eq!(minimised_qname(&qname, &qname), None);
+        assert_eq!(minimised_qname(&DomainName::root(), &DomainName::root()), None);
+    }
+
+    #[test]
+    fn relaxed_minimisation_asks_again_after_a_bogus_nxdomain() {
+        let qname = DomainName::new("www.sub.example.org");
+        let asked = match broken_server(Ipv4Addr::new(127, 0, 0, 29), &qname) {
+            Some(asked) => asked,
+            None => return,
+        };
+        let resolver = recursing_from(Ipv4Addr::new(127, 0, 0, 29), QnameMinimisation::Relaxed);
+
+        let packet = resolver.resolve(&qname, QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NoError);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), [DomainName::new("org"), qname]);
+    }
+
+    #[test]
+    fn strict_minimisation_takes_nxdomain_for_an_answer() {
+        let qname = DomainName::new("www.sub.example.org");
+        let asked = match broken_server(Ipv4Addr::new(127, 0, 0, 30), &qname) {
+            Some(asked) => asked,
+            None => return,
+        };
+        let resolver = recursing_from(Ipv4Addr::new(127, 0, 0, 30), QnameMinimisation::Strict);
+
+        let packet = resolver.resolve(&qname, QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDomain);
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), [DomainName::new("org")]);
+    }
+
+    #[test]
+    fn fresh_answers_win_over_stale_ones() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = forwarding_to(&upstream, CacheConfig::default());
+        let name = DomainName::new("example.org");
+        cache_expiring(&resolver, &name);
+
+        let responder = answer_once(upstream);
+        let packet = resolver.resolve(&name, QueryType::A).unwrap();
+        responder.join().unwrap();
+        assert!(matches!(packet.answers[..], [DnsRecord::A { addr, .. }] if addr == Ipv4Addr::new(192, 0, 2, 2)));
+        assert_eq!(resolver.cache.lock().unwrap().stats().stale_hits, 0);
+    }
+
+    #[test]
+    fn stale_clients_share_a_single_refresh() {
+        //An upstream that never answers
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = forwarding_to(
+            &upstream,
+            CacheConfig {
+                client_response_timeout: Duration::from_millis(100),
+                ..CacheConfig::default()
+            },
+        );
+
+        let name = DomainName::new("example.org");
+        cache_expiring(&resolver, &name);
+
+        let clients: Vec<_> = (0..5)
+            .map(|_| {
+                let resolver = Arc::clone(&resolver);
+                let name = name.clone();
+                thread::spawn(move || resolver.resolve(&name, QueryType::A).unwrap())
+            })
+            .collect();
+        for client in clients {
+            let packet = client.join().unwrap();
+            assert_eq!(packet.answers.len(), 1);
+            assert_eq!(packet.answers[0].ttl(), CacheConfig::default().stale_ttl);
+        }
+        assert_eq!(resolver.cache.lock().unwrap().stats().stale_hits, 5);
+
+        //One refresh went upstream, and it's still waiting for its answer
+        assert_eq!(resolver.in_flight.lock().unwrap().len(), 1);
+        upstream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut buf = [0; 512];
+        assert!(upstream.recv_from(&mut buf).is_ok());
+        assert!(upstream.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn waiters_beyond_the_limit_give_up_right_away() {
+        let resolver = Resolver::new(ResolverConfig {
+            max_waiters_per_query: 2,
+            ..ResolverConfig::default()
+        });
+        let pending = Pending::default();
+        pending.state.lock().unwrap().waiters = 2;
+
+        let started = Instant::now();
+        let name = DomainName::new("example.org");
+        assert!(resolver.wait_for(&pending, &name, QueryType::A, Duration::from_secs(5)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(pending.state.lock().unwrap().waiters, 2);
+    }
+
+    #[test]
+    fn panicking_resolution_releases_its_waiters() {
+        let resolver = Resolver::new(ResolverConfig::default());
+        let key = CacheKey::new(&DomainName::new("example.org"), QueryType::A);
+        let pending = Arc::new(Pending::default());
+        resolver.in_flight.lock().unwrap().insert(key.clone(), Arc::clone(&pending));
+
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _entry = InFlightEntry {
+                in_flight: &resolver.in_flight,
+                key: key.clone(),
+                result: None,
+            };
+            panic!("the resolution blew up");
+        }));
+
+        assert!(outcome.is_err());
+        assert!(resolver.in_flight.lock().unwrap().is_empty());
+        assert!(matches!(pending.state.lock().unwrap().result, Some(Err(_))));
+    }
+
+    #[test]
+    fn finished_resolution_hands_its_result_to_waiters() {
+        let resolver = Resolver::new(ResolverConfig::default());
+        let key = CacheKey::new(&DomainName::new("example.org"), QueryType::A);
+        let pending = Arc::new(Pending::default());
+        resolver.in_flight.lock().unwrap().insert(key.clone(), Arc::clone(&pending));
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = 42;
+        drop(InFlightEntry {
+            in_flight: &resolver.in_flight,
+            key,
+            result: Some(Ok(packet)),
+        });
+
+        assert!(resolver.in_flight.lock().unwrap().is_empty());
+        assert!(matches!(pending.state.lock().unwrap().result, Some(Ok(ref packet)) if packet.header.id == 42));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+
+/// Drops every record from an upstream response that the server had no business
+/// telling us about. `zone` is the zone the server was asked as an authority for,
+/// anything outside of it is dropped, and so is anything unrelated to `qname`:
+///
+/// - answers have to belong to `qname` or to the CNAME chain starting at it
+/// - NS and SOA records in the authority section have to be for a parent of one
+///   of those names
+/// - additional records have to be addresses within the zone
+///
+/// The removed records are returned so that the caller can log them.
+pub fn scrub_response(response: &mut DnsPacket, qname: &DomainName, zone: &DomainName) -> Vec<DnsRecord> {
+    let mut removed = Vec::new();
+
+    //First work out the names the answer is allowed to talk about, by following
+    //the CNAME chain as far as it stays inside the zone
+    let mut names = vec![qname.clone()];
+    loop {
+        let next = response.answers.iter().find_map(|rec| match rec {
+            DnsRecord::Cname { domain, host, .. }
+                if names.contains(domain) && domain.is_subdomain_of(zone) && !names.contains(host) =>
+            {
+                Some(host.clone())
+            }
+            _ => None,
+        });
+
+        match next {
+            Some(name) => names.push(name),
+            None => break,
+        }
+    }
+
+    let answers = std::mem::take(&mut response.answers);
+    for rec in answers {
+        if names.contains(rec.domain()) && rec.domain().is_subdomain_of(zone) {
+            response.answers.push(rec);
+        } else {
+            removed.push(rec);
+        }
+    }
+
+    let authorities = std::mem::take(&mut response.authorities);
+    for rec in authorities {
+        let keep = rec.domain().is_subdomain_of(zone)
+            && match rec.qtype() {
+                QueryType::NS | QueryType::Soa => names.iter().any(|name| name.is_subdomain_of(rec.domain())),
+                _ => true,
+            };
+
+        if keep {
+            response.authorities.push(rec);
+        } else {
+            removed.push(rec);
+        }
+    }
+
+    let resources = std::mem::take(&mut response.resources);
+    for rec in resources {
+        let keep = matches!(rec, DnsRecord::A { .. } | DnsRecord::Aaaa { .. }) && rec.domain().is_subdomain_of(zone);
+
+        if keep {
+            response.resources.push(rec);
+        } else {
+            removed.push(rec);
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn name(s: &str) -> DomainName {
+        DomainName::new(s)
+    }
+
+    fn a(owner: &str, last: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: name(owner),
+            addr: Ipv4Addr::new(192, 0, 2, last),
+            ttl: 300,
+        }
+    }
+
+    fn cname(owner: &str, target: &str) -> DnsRecord {
+        DnsRecord::Cname {
+            domain: name(owner),
+            host: name(target),
+            ttl: 300,
+        }
+    }
+
+    fn ns(owner: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: name(owner),
+            host: name(host),
+            ttl: 300,
+        }
+    }
+
+    fn scrub(response: &mut DnsPacket, qname: &str, zone: &str) -> Vec<DnsRecord> {
+        scrub_response(response, &name(qname), &name(zone))
+    }
+
+    #[test]
+    fn answers_follow_the_cname_chain_inside_the_zone() {
+        let mut response = DnsPacket::new();
+        response.answers = vec![
+            cname("www.example.org", "web.example.org"),
+            a("web.example.org", 1),
+            //Not asked for, and not on the chain
+            a("mail.example.org", 2),
+            //The chain leaves the zone, the server can't speak for where it goes
+            cname("web.example.org", "www.example.com"),
+            a("www.example.com", 3),
+        ];
+
+        let removed = scrub(&mut response, "www.example.org", "example.org");
+        assert_eq!(
+            response.answers,
+            [cname("www.example.org", "web.example.org"), a("web.example.org", 1), cname("web.example.org", "www.example.com")]
+        );
+        assert_eq!(removed, [a("mail.example.org", 2), a("www.example.com", 3)]);
+    }
+
+    #[test]
+    fn authorities_only_for_parents_of_the_name() {
+        let mut response = DnsPacket::new();
+        response.authorities = vec![
+            ns("sub.example.org", "ns.sub.example.org"),
+            //A sibling of the name, and a zone the server isn't an authority for
+            ns("other.example.org", "ns.other.example.org"),
+            ns("com", "ns.attacker.net"),
+        ];
+
+        let removed = scrub(&mut response, "www.sub.example.org", "example.org");
+        assert_eq!(response.authorities, [ns("sub.example.org", "ns.sub.example.org")]);
+        assert_eq!(removed, [ns("other.example.org", "ns.other.example.org"), ns("com", "ns.attacker.net")]);
+    }
+
+    #[test]
+    fn glue_has_to_be_an_address_inside_the_zone() {
+        let mut response = DnsPacket::new();
+        response.authorities = vec![ns("sub.example.org", "ns.sub.example.org"), ns("sub.example.org", "ns.example.com")];
+        response.resources = vec![a("ns.sub.example.org", 1), a("ns.example.com", 2), cname("alias.example.org", "ns.sub.example.org")];
+
+        let removed = scrub(&mut response, "www.sub.example.org", "example.org");
+        assert_eq!(response.resources, [a("ns.sub.example.org", 1)]);
+        assert_eq!(removed, [a("ns.example.com", 2), cname("alias.example.org", "ns.sub.example.org")]);
+        assert_eq!(response.authorities.len(), 2);
+    }
+
+    #[test]
+    fn the_root_zone_may_talk_about_anything() {
+        let mut response = DnsPacket::new();
+        response.authorities = vec![ns("org", "a0.org-servers.net")];
+        response.resources = vec![a("a0.org-servers.net", 1)];
+
+        assert!(scrub(&mut response, "www.example.org", "").is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.resources.len(), 1);
+    }
+}
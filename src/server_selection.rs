@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+//Weight of the previous estimate when a new rtt sample comes in, the same
+//7/10 smoothing BIND uses
+const SRTT_WEIGHT: f64 = 0.7;
+
+//Every time a server is passed over in favour of another one, its estimate
+//shrinks a little. Sooner or later it looks fast enough to be picked again,
+//which is how slower servers still get probed now and then.
+const SRTT_DECAY: f64 = 0.98;
+
+//Servers that time out are left alone for a while, twice as long for every
+//timeout in a row, up to this limit
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+//We don't want to remember every server we ever talked to
+const MAX_SERVERS: usize = 10000;
+
+struct ServerInfo {
+    //Smoothed round trip time in milliseconds
+    srtt: f64,
+    samples: u32,
+    timeouts: u32,
+    backoff_until: Option<Instant>,
+    last_used: Instant,
+}
+
+/// Keeps a smoothed round trip time for every nameserver address we've queried
+/// and uses it to pick the fastest of a set of candidates, like BIND and Unbound do.
+#[derive(Default)]
+pub struct ServerSelection {
+    servers: HashMap<Ipv4Addr, ServerInfo>,
+}
+
+impl ServerSelection {
+    pub fn new() -> ServerSelection {
+        ServerSelection::default()
+    }
+
+    /// Picks the server to ask next out of `candidates`. Servers we've never
+    /// talked to count as having an rtt of zero so that they get tried once,
+    /// and servers that are backing off are only used if nothing else is left.
+    pub fn select(&mut self, candidates: &[Ipv4Addr]) -> Option<Ipv4Addr> {
+        let best = candidates
+            .iter()
+            .min_by(|a, b| self.rank(a).partial_cmp(&self.rank(b)).unwrap_or(std::cmp::Ordering::Equal))
+            .copied()?;
+
+        //Everyone who wasn't picked gets a little more attractive
+        for addr in candidates.iter().filter(|addr| **addr != best) {
+            if let Some(info) = self.servers.get_mut(addr) {
+                info.srtt *= SRTT_DECAY;
+            }
+        }
+
+        Some(best)
+    }
+
+    /// How good a choice `addr` is, lower is better. Servers that are backing
+    /// off always rank behind the others, after that it's down to the rtt.
+    pub fn rank(&self, addr: &Ipv4Addr) -> (bool, f64) {
+        match self.servers.get(addr) {
+            Some(info) => {
+                let backing_off = info.backoff_until.is_some_and(|until| until > Instant::now());
+                (backing_off, info.srtt)
+            }
+            None => (false, 0.0),
+        }
+    }
+
+    pub fn record_rtt(&mut self, addr: Ipv4Addr, rtt: Duration) {
+        let sample = rtt.as_secs_f64() * 1000.0;
+        let info = self.entry(addr);
+
+        //The first sample is taken as is, there's nothing to smooth it with
+        info.srtt = if info.samples == 0 {
+            sample
+        } else {
+            SRTT_WEIGHT * info.srtt + (1.0 - SRTT_WEIGHT) * sample
+        };
+        info.samples += 1;
+        info.timeouts = 0;
+        info.backoff_until = None;
+    }
+
+    /// Penalises a server that didn't answer within `timeout`, and backs it
+    /// off once it does so repeatedly
+    pub fn record_timeout(&mut self, addr: Ipv4Addr, timeout: Duration) {
+        let now = Instant::now();
+        let info = self.entry(addr);
+
+        info.srtt = info.srtt.max(timeout.as_secs_f64() * 1000.0);
+        info.timeouts += 1;
+
+        if info.timeouts > 1 {
+            let backoff = BASE_BACKOFF
+                .saturating_mul(1 << (info.timeouts - 2).min(16))
+                .min(MAX_BACKOFF);
+            info.backoff_until = Some(now + backoff);
+        }
+    }
+
+    fn entry(&mut self, addr: Ipv4Addr) -> &mut ServerInfo {
+        let now = Instant::now();
+
+        if !self.servers.contains_key(&addr) && self.servers.len() >= MAX_SERVERS {
+            let oldest = self
+                .servers
+                .iter()
+                .min_by_key(|(_, info)| info.last_used)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.servers.remove(&oldest);
+            }
+        }
+
+        let info = self.servers.entry(addr).or_insert(ServerInfo {
+            srtt: 0.0,
+            samples: 0,
+            timeouts: 0,
+            backoff_until: None,
+            last_used: now,
+        });
+        info.last_used = now;
+        info
+    }
+}
@@ -0,0 +1,37 @@
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+
+/// The largest message the two byte length in front of it allows
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Reads one DNS message from a TCP stream, where every message comes with its
+/// length in front of it (RFC 1035 section 4.2.2). Returns None once the peer
+/// has closed the connection, or has been idle for longer than the read timeout.
+pub fn read_message(stream: &mut impl Read) -> Result<Option<BytePacketBuffer>, Box<dyn Error>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u16::from_be_bytes(len) as usize;
+    let mut buffer = BytePacketBuffer::with_size(len);
+    stream.read_exact(&mut buffer.buf)?;
+    Ok(Some(buffer))
+}
+
+/// Writes the message in `buffer` with its length in front
+pub fn write_message(stream: &mut impl Write, buffer: &BytePacketBuffer) -> Result<(), Box<dyn Error>> {
+    let data = buffer.get_range(0, buffer.pos())?;
+
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::protocol::dnspacket::DnsPacket;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+
+/// One query sent upstream while resolving a name, the way `dig +trace` shows it
+#[derive(Clone, Debug)]
+pub struct TraceStep {
+    //How deep in lookups of nameserver names the query was made, 0 for the
+    //name that was asked for
+    pub depth: usize,
+    pub server: (Ipv4Addr, u16),
+    //The zone the server was asked as an authority for, the root when forwarding
+    pub zone: DomainName,
+    pub qname: DomainName,
+    pub qtype: QueryType,
+    //Until the answer came in, or until we gave up waiting
+    pub rtt: Duration,
+    //None when the server never answered, `error` says what went wrong then
+    pub rcode: Option<ResultCode>,
+    pub error: Option<String>,
+    //The zone we were sent on to and the names of its nameservers
+    pub referral: Option<DomainName>,
+    pub nameservers: Vec<DomainName>,
+    //Addresses of those nameservers that came along with the referral
+    pub glue: Vec<(DomainName, Ipv4Addr)>,
+    pub answers: Vec<DnsRecord>,
+}
+
+impl TraceStep {
+    pub fn new(depth: usize, server: (Ipv4Addr, u16), zone: &DomainName, qname: &DomainName, qtype: QueryType) -> TraceStep {
+        TraceStep {
+            depth,
+            server,
+            zone: zone.clone(),
+            qname: qname.clone(),
+            qtype,
+            rtt: Duration::ZERO,
+            rcode: None,
+            error: None,
+            referral: None,
+            nameservers: Vec::new(),
+            glue: Vec::new(),
+            answers: Vec::new(),
+        }
+    }
+
+    /// Fills in what the server answered, after the response has been scrubbed
+    pub fn answered(mut self, response: &DnsPacket, rtt: Duration) -> TraceStep {
+        self.rtt = rtt;
+        self.rcode = Some(response.header.rescode);
+        self.answers = response.answers.clone();
+
+        if response.answers.is_empty() {
+            for (zone, host) in response.get_ns(&self.qname) {
+                self.referral.get_or_insert_with(|| zone.clone());
+                self.nameservers.push(host.clone());
+            }
+        }
+
+        self.glue = response
+            .resources
+            .iter()
+            .filter_map(|rec| match rec {
+                DnsRecord::A { domain, addr, .. } if self.nameservers.contains(domain) => Some((domain.clone(), *addr)),
+                _ => None,
+            })
+            .collect();
+
+        self
+    }
+
+    pub fn failed(mut self, error: &str, rtt: Duration) -> TraceStep {
+        self.rtt = rtt;
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let indent = "  ".repeat(self.depth);
+        write!(
+            f,
+            "{}{:?} {} @{}:{} (zone {}) {}ms",
+            indent,
+            self.qtype,
+            self.qname,
+            self.server.0,
+            self.server.1,
+            self.zone,
+            self.rtt.as_millis()
+        )?;
+
+        match (self.rcode, &self.error) {
+            (Some(rcode), _) => write!(f, " {:?}", rcode)?,
+            (None, Some(error)) => return write!(f, " failed: {}", error),
+            (None, None) => return Ok(()),
+        }
+
+        if let Some(ref referral) = self.referral {
+            write!(f, "\n{}  referral to {}", indent, referral)?;
+            for host in &self.nameservers {
+                let addrs: Vec<String> = self
+                    .glue
+                    .iter()
+                    .filter(|(name, _)| name == host)
+                    .map(|(_, addr)| addr.to_string())
+                    .collect();
+                if addrs.is_empty() {
+                    write!(f, "\n{}    NS {} (no glue)", indent, host)?;
+                } else {
+                    write!(f, "\n{}    NS {} glue {}", indent, host, addrs.join(", "))?;
+                }
+            }
+        }
+
+        for rec in &self.answers {
+            write!(f, "\n{}  {:?}", indent, rec)?;
+        }
+
+        Ok(())
+    }
+}
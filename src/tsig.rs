@@ -0,0 +1,743 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnsheader::DnsHeader;
+use crate::protocol::dnsquestion::DnsQuestion;
+use crate::protocol::dnsrecord::DnsRecord;
+use crate::protocol::domainname::DomainName;
+use crate::protocol::querytype::QueryType;
+use crate::protocol::resultcode::ResultCode;
+use crate::protocol::tsigrecord::TsigRecord;
+
+//How far apart our clock and the other side's may be, in seconds
+const FUDGE: u16 = 300;
+
+//The errors a TSIG record can carry (RFC 8945 section 3)
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+/// The most a TSIG record adds to a message: two names of the longest kind,
+/// the fixed size fields, the longest MAC and our time for BADTIME
+pub const MAX_OVERHEAD: usize = 2 * 255 + 30 + 64 + 6;
+
+//How many messages of a zone transfer may go without a signature in a row
+//(RFC 8945 section 5.3.1)
+const MAX_UNSIGNED: usize = 99;
+
+/// The MAC algorithms we sign with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    fn name(&self) -> DomainName {
+        match self {
+            Algorithm::HmacSha256 => DomainName::new("hmac-sha256"),
+            Algorithm::HmacSha512 => DomainName::new("hmac-sha512"),
+        }
+    }
+
+    fn from_name(name: &DomainName) -> Option<Algorithm> {
+        [Algorithm::HmacSha256, Algorithm::HmacSha512]
+            .into_iter()
+            .find(|algorithm| algorithm.name() == *name)
+    }
+
+    fn mac_len(&self) -> usize {
+        match self {
+            Algorithm::HmacSha256 => 32,
+            Algorithm::HmacSha512 => 64,
+        }
+    }
+
+    fn mac(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC takes keys of any length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    //Compares in constant time, and takes MACs cut short as far as RFC 8945
+    //(section 5.2.2.1) allows
+    fn verify(&self, secret: &[u8], data: &[u8], mac: &[u8]) -> bool {
+        let full = self.mac_len();
+        if mac.len() > full || mac.len() < (full / 2).max(10) {
+            return false;
+        }
+
+        match self {
+            Algorithm::HmacSha256 => {
+                let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+                hmac.update(data);
+                hmac.verify_truncated_left(mac).is_ok()
+            }
+            Algorithm::HmacSha512 => {
+                let mut hmac = Hmac::<Sha512>::new_from_slice(secret).expect("HMAC takes keys of any length");
+                hmac.update(data);
+                hmac.verify_truncated_left(mac).is_ok()
+            }
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::from_name(&DomainName::new(s)).ok_or_else(|| format!("Unsupported TSIG algorithm {:?}", s).into())
+    }
+}
+
+/// A secret shared with another server to sign the messages between us, which
+/// both sides know by the same name
+#[derive(Clone)]
+pub struct Key {
+    pub name: DomainName,
+    algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl Key {
+    /// Parses a key given as `<name>=<algorithm>:<secret in base64>`
+    pub fn parse(value: &str) -> Result<Key, Box<dyn Error>> {
+        let (name, rest) = value.split_once('=').ok_or("A TSIG key needs a name=algorithm:secret value")?;
+        let (algorithm, secret) = rest.split_once(':').ok_or("A TSIG key needs a name=algorithm:secret value")?;
+        Key::new(name, algorithm, secret)
+    }
+
+    /// Makes the key called `name` from the name of its algorithm and its
+    /// secret in base64
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> Result<Key, Box<dyn Error>> {
+        let secret = BASE64
+            .decode(secret)
+            .map_err(|e| format!("The secret of TSIG key {} isn't valid base64: {}", name, e))?;
+
+        Ok(Key {
+            name: DomainName::new(name),
+            algorithm: algorithm.parse()?,
+            secret,
+        })
+    }
+
+    //How much the TSIG record signing a message with this key adds to it
+    fn overhead(&self) -> usize {
+        //Both names, the fixed size fields, the MAC and our time for BADTIME
+        self.name.as_str().len() + self.algorithm.name().as_str().len() + 30 + self.algorithm.mac_len() + 6
+    }
+}
+
+//Keeps the secret out of the logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key {{ name: {:?}, algorithm: {:?} }}", self.name, self.algorithm)
+    }
+}
+
+/// The keys we know, and which of them the messages to and from each server
+/// are signed with
+#[derive(Default)]
+pub struct Keyring {
+    keys: HashMap<DomainName, Key>,
+    servers: HashMap<IpAddr, DomainName>,
+}
+
+impl Keyring {
+    pub fn add(&mut self, key: Key) {
+        self.keys.insert(key.name.clone(), key);
+    }
+
+    pub fn contains(&self, name: &DomainName) -> bool {
+        self.keys.contains_key(name)
+    }
+
+    /// Signs everything we send to `server` with the key called `name`, and
+    /// expects everything from it to be signed with that key too
+    pub fn use_for(&mut self, server: IpAddr, name: &DomainName) -> Result<(), Box<dyn Error>> {
+        if !self.contains(name) {
+            return Err(format!("Unknown TSIG key {} for server {}", name, server).into());
+        }
+        self.servers.insert(server, name.clone());
+        Ok(())
+    }
+
+    pub fn for_server(&self, server: IpAddr) -> Option<&Key> {
+        self.servers.get(&server).and_then(|name| self.keys.get(name))
+    }
+}
+
+/// Why the signature of a request was turned down
+#[derive(Debug)]
+pub enum TsigError {
+    //The TSIG record can't be read, or isn't where it belongs
+    Malformed(String),
+    BadKey(Box<TsigRecord>),
+    BadSig(Box<TsigRecord>),
+    //The signature checks out, but was made too long ago or too far ahead
+    BadTime(Box<TsigRecord>, Key),
+}
+
+impl TsigError {
+    pub fn rescode(&self) -> ResultCode {
+        match self {
+            TsigError::Malformed(_) => ResultCode::FormErr,
+            _ => ResultCode::NotAuth,
+        }
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsigError::Malformed(e) => write!(f, "malformed TSIG record: {}", e),
+            TsigError::BadKey(tsig) => write!(f, "unknown TSIG key {} ({})", tsig.key_name, tsig.algorithm),
+            TsigError::BadSig(tsig) => write!(f, "bad signature with TSIG key {}", tsig.key_name),
+            TsigError::BadTime(tsig, _) => {
+                write!(f, "signed with TSIG key {} at {}, too far from our clock", tsig.key_name, tsig.time_signed)
+            }
+        }
+    }
+}
+
+impl Error for TsigError {}
+
+fn error_name(error: u16) -> String {
+    match error {
+        BADSIG => "BADSIG".to_string(),
+        BADKEY => "BADKEY".to_string(),
+        BADTIME => "BADTIME".to_string(),
+        _ => format!("error {}", error),
+    }
+}
+
+/// What a signed request was signed with, which its answer gets signed with too
+pub struct Signature {
+    pub key: Key,
+    mac: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+//Finds the TSIG record, which has to be the last one of the message. Returns
+//where the message ends without it, along with the record.
+fn find(buffer: &mut BytePacketBuffer) -> Result<(usize, Option<TsigRecord>), Box<dyn Error>> {
+    buffer.seek(0)?;
+    let mut header = DnsHeader::new();
+    header.read(buffer)?;
+
+    for _ in 0..header.questions {
+        DnsQuestion::new(DomainName::root(), QueryType::Unknown(0)).read(buffer)?;
+    }
+
+    let records = header.answers as usize + header.authoritative_entries as usize + header.resource_entries as usize;
+    for _ in 1..records {
+        DnsRecord::read(buffer)?;
+    }
+
+    let end = buffer.pos();
+    if header.resource_entries == 0 {
+        if records > 0 {
+            DnsRecord::read(buffer)?;
+        }
+        return Ok((buffer.pos(), None));
+    }
+
+    match TsigRecord::read(buffer)? {
+        Some(tsig) => Ok((end, Some(tsig))),
+        None => {
+            buffer.seek(end)?;
+            DnsRecord::read(buffer)?;
+            Ok((buffer.pos(), None))
+        }
+    }
+}
+
+//The message the way it was signed: with its original id, and without the
+//TSIG record
+fn unsigned_message(buffer: &BytePacketBuffer, end: usize, tsig: &TsigRecord) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut message = buffer.get_range(0, end)?.to_vec();
+    message[0..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let additional = u16::from_be_bytes([message[10], message[11]]).saturating_sub(1);
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(message)
+}
+
+//What the MAC goes over: the MAC it follows up on if any, the messages, and
+//the variables of the TSIG record
+fn signed_data(prior: Option<&[u8]>, messages: &[u8], tsig: &TsigRecord, timers_only: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    if let Some(prior) = prior {
+        data.extend_from_slice(&(prior.len() as u16).to_be_bytes());
+        data.extend_from_slice(prior);
+    }
+    data.extend_from_slice(messages);
+    data.extend(tsig.variables(timers_only)?);
+    Ok(data)
+}
+
+//Adds the TSIG record to the end of the message and counts it in the header
+fn append(buffer: &mut BytePacketBuffer, tsig: &TsigRecord) -> Result<(), Box<dyn Error>> {
+    let mut record = BytePacketBuffer::with_size(2 * 256 + 32 + tsig.mac.len() + tsig.other.len());
+    tsig.write(&mut record)?;
+    let record = record.get_range(0, record.pos())?;
+
+    let end = buffer.pos() + record.len();
+    if buffer.buf.len() < end {
+        buffer.buf.resize(end, 0);
+    }
+    for &byte in record {
+        buffer.write(byte)?;
+    }
+
+    let additional = u16::from_be_bytes([buffer.get(10)?, buffer.get(11)?]);
+    buffer.set_u16(10, additional + 1)?;
+    Ok(())
+}
+
+/// Checks the signature of a request, if it has one. It has to be made with a
+/// key we know, and within the fudge of our clock.
+pub fn verify(keyring: &Keyring, buffer: &mut BytePacketBuffer) -> Result<Option<Signature>, TsigError> {
+    let malformed = |e: Box<dyn Error>| TsigError::Malformed(e.to_string());
+    let (end, tsig) = find(buffer).map_err(malformed)?;
+    let tsig = match tsig {
+        Some(tsig) => tsig,
+        None => return Ok(None),
+    };
+
+    let key = match keyring.keys.get(&tsig.key_name) {
+        Some(key) if Algorithm::from_name(&tsig.algorithm) == Some(key.algorithm) => key,
+        _ => return Err(TsigError::BadKey(Box::new(tsig))),
+    };
+
+    let message = unsigned_message(buffer, end, &tsig).map_err(malformed)?;
+    let data = signed_data(None, &message, &tsig, false).map_err(malformed)?;
+    if !key.algorithm.verify(&key.secret, &data, &tsig.mac) {
+        return Err(TsigError::BadSig(Box::new(tsig)));
+    }
+    if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(TsigError::BadTime(Box::new(tsig), key.clone()));
+    }
+
+    Ok(Some(Signature {
+        key: key.clone(),
+        mac: tsig.mac,
+    }))
+}
+
+/// Adds the TSIG record that tells the sender of a request why its signature
+/// was turned down (RFC 8945 section 5.3.2). With an unknown key or a bad
+/// signature there's nothing we can sign it with. A signature made at the
+/// wrong time still gets a signed answer, with our clock in it.
+pub fn write_error(buffer: &mut BytePacketBuffer, error: &TsigError) -> Result<(), Box<dyn Error>> {
+    let (tsig, code) = match error {
+        TsigError::Malformed(_) => return Ok(()),
+        TsigError::BadKey(tsig) => (tsig, BADKEY),
+        TsigError::BadSig(tsig) => (tsig, BADSIG),
+        TsigError::BadTime(tsig, key) => {
+            let mut signer = Signer::answer(Signature {
+                key: key.clone(),
+                mac: tsig.mac.clone(),
+            });
+            let time = now().to_be_bytes()[2..].to_vec();
+            return signer.sign_with(buffer, BADTIME, time);
+        }
+    };
+
+    let reply = TsigRecord {
+        time_signed: now(),
+        mac: Vec::new(),
+        error: code,
+        other: Vec::new(),
+        ..(**tsig).clone()
+    };
+    append(buffer, &reply)
+}
+
+/// Signs what we send in one exchange: a request of ours, or the answer to a
+/// signed request, in as many messages as it takes. Every message after the
+/// first is chained to the one before it (RFC 8945 section 5.3.1).
+pub struct Signer {
+    key: Key,
+    mac: Option<Vec<u8>>,
+    first: bool,
+}
+
+impl Signer {
+    /// For a request we send
+    pub fn new(key: Key) -> Signer {
+        Signer {
+            key,
+            mac: None,
+            first: true,
+        }
+    }
+
+    /// For the answer to a signed request
+    pub fn answer(signature: Signature) -> Signer {
+        Signer {
+            key: signature.key,
+            mac: Some(signature.mac),
+            first: true,
+        }
+    }
+
+    /// Adds the TSIG record to the message in `buffer`, whose position is at
+    /// the end of the message
+    pub fn sign(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Box<dyn Error>> {
+        self.sign_with(buffer, 0, Vec::new())
+    }
+
+    fn sign_with(&mut self, buffer: &mut BytePacketBuffer, error: u16, other: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut tsig = TsigRecord {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.name(),
+            time_signed: now(),
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: u16::from_be_bytes([buffer.get(0)?, buffer.get(1)?]),
+            error,
+            other,
+        };
+
+        let message = buffer.get_range(0, buffer.pos())?;
+        let data = signed_data(self.mac.as_deref(), message, &tsig, !self.first)?;
+        tsig.mac = self.key.algorithm.mac(&self.key.secret, &data);
+        append(buffer, &tsig)?;
+
+        self.mac = Some(tsig.mac);
+        self.first = false;
+        Ok(())
+    }
+
+    /// How much signing adds to a message
+    pub fn overhead(&self) -> usize {
+        self.key.overhead()
+    }
+
+    /// Checks the answers to the request this signed
+    pub fn verifier(&self) -> Verifier {
+        Verifier {
+            key: self.key.clone(),
+            mac: self.mac.clone().unwrap_or_default(),
+            first: true,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        }
+    }
+}
+
+/// Checks the signatures on the answers to a request we signed. A zone
+/// transfer may leave out the signature on some of its messages, but not on
+/// the first and last ones.
+pub struct Verifier {
+    key: Key,
+    mac: Vec<u8>,
+    first: bool,
+    //The messages since the last signed one, which the next signature covers
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+impl Verifier {
+    pub fn check(&mut self, buffer: &mut BytePacketBuffer) -> Result<(), Box<dyn Error>> {
+        let (end, tsig) = find(buffer)?;
+        let tsig = match tsig {
+            Some(tsig) => tsig,
+            None if self.first => return Err(format!("The answer isn't signed with TSIG key {}", self.key.name).into()),
+            None if self.unsigned_count >= MAX_UNSIGNED => return Err("Too many unsigned messages in a row".into()),
+            None => {
+                self.unsigned.extend_from_slice(buffer.get_range(0, end)?);
+                self.unsigned_count += 1;
+                return Ok(());
+            }
+        };
+
+        if tsig.error != 0 {
+            return Err(format!("Our signature was turned down with {}", error_name(tsig.error)).into());
+        }
+        if tsig.key_name != self.key.name || tsig.algorithm != self.key.algorithm.name() {
+            return Err(format!("The answer is signed with TSIG key {} instead of {}", tsig.key_name, self.key.name).into());
+        }
+
+        let mut messages = std::mem::take(&mut self.unsigned);
+        messages.extend(unsigned_message(buffer, end, &tsig)?);
+        let data = signed_data(Some(&self.mac), &messages, &tsig, !self.first)?;
+        if !self.key.algorithm.verify(&self.key.secret, &data, &tsig.mac) {
+            return Err("The signature of the answer doesn't check out".into());
+        }
+        if now().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+            return Err("The answer was signed too far from our clock".into());
+        }
+
+        self.mac = tsig.mac;
+        self.first = false;
+        self.unsigned_count = 0;
+        Ok(())
+    }
+
+    /// Whether the answers so far end with a signed message, which the last
+    /// message of a transfer has to be
+    pub fn is_complete(&self) -> bool {
+        !self.first && self.unsigned_count == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::dnspacket::DnsPacket;
+
+    //The known answers below were signed outside this code base, by a separate
+    //implementation of RFC 8945 on top of Python's hmac module, with the key
+    //k1 (hmac-sha256, "secretsecretsecretsecretsecretse") at this time
+    const SIGNED_AT: u64 = 1_700_000_000;
+
+    //A query for example.org/SOA with id 0x1234, and its answer
+    const REQUEST: &str = "123400000001000000000000076578616d706c65036f72670000060001";
+    const ANSWER: &str = "123484000001000000000000076578616d706c65036f72670000060001";
+    //The request with its TSIG record
+    const SIGNED_REQUEST: &str = "123400000001000000000001076578616d706c65036f72670000060001026b310000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c0020c6636f38ca5ba34528315230440cf4700dea0adf249ae886f08cf03d0b8d0a9f123400000000";
+    //The MAC of the request, then of the first message of the answer following up
+    //on it, and of a second message signed a second later with the timers only
+    const REQUEST_MAC: &str = "c6636f38ca5ba34528315230440cf4700dea0adf249ae886f08cf03d0b8d0a9f";
+    const FIRST_MAC: &str = "1250484a650fcf4c7d1f87553f599cf63a462ea5ba2d1cab8b9d3924bb9f2c7a";
+    const SECOND_MAC: &str = "b5da04b3079e565b4803d0ff81565c4c74d16c5e1dfb8441c1e12dbdf4c7b444";
+    //The MAC of the request with the same secret under hmac-sha512
+    const REQUEST_MAC_512: &str = "ec55b7941b34b775593e52a50679ea8ec6249e1c3b30f770ceb310928d01791200fd23b8ce7dd3cd52c4e9df62b447ddc352f17e5f12ab7beb566bddbc7df4e6";
+
+    const SECRET: &str = "c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(algorithm: &str) -> Key {
+        Key::new("k1", algorithm, SECRET).unwrap()
+    }
+
+    fn keyring() -> Keyring {
+        let mut keyring = Keyring::default();
+        keyring.add(key("hmac-sha256"));
+        keyring
+    }
+
+    fn tsig(key: &Key, time_signed: u64) -> TsigRecord {
+        TsigRecord {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm.name(),
+            time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: 0x1234,
+            error: 0,
+            other: Vec::new(),
+        }
+    }
+
+    fn buffer(bytes: &[u8]) -> BytePacketBuffer {
+        let mut buffer = BytePacketBuffer::with_size(bytes.len());
+        buffer.buf.copy_from_slice(bytes);
+        buffer
+    }
+
+    //An answer to `request`, with the buffer's position at its end, ready to sign
+    fn answer(request: &[u8]) -> BytePacketBuffer {
+        let mut packet = DnsPacket::from_buffer(&mut buffer(request)).unwrap();
+        packet.header.response = true;
+        packet.resources.clear();
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer
+    }
+
+    //The TSIG record at the end of a message, and the message the way it was signed
+    fn split(buffer: &mut BytePacketBuffer) -> (TsigRecord, Vec<u8>) {
+        let (end, tsig) = find(buffer).unwrap();
+        let tsig = tsig.unwrap();
+        let message = unsigned_message(buffer, end, &tsig).unwrap();
+        (tsig, message)
+    }
+
+    #[test]
+    fn hmacs_match_rfc_4231() {
+        //Test case 2
+        let data = b"what do ya want for nothing?";
+        assert_eq!(
+            Algorithm::HmacSha256.mac(b"Jefe", data),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+        assert_eq!(
+            Algorithm::HmacSha512.mac(b"Jefe", data),
+            hex("164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737")
+        );
+    }
+
+    #[test]
+    fn request_macs_match_the_known_answers() {
+        for (algorithm, expected) in [("hmac-sha256", REQUEST_MAC), ("hmac-sha512", REQUEST_MAC_512)] {
+            let key = key(algorithm);
+            let data = signed_data(None, &hex(REQUEST), &tsig(&key, SIGNED_AT), false).unwrap();
+            assert_eq!(key.algorithm.mac(&key.secret, &data), hex(expected), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn the_tsig_record_reads_back_the_way_it_was_signed() {
+        let (tsig, message) = split(&mut buffer(&hex(SIGNED_REQUEST)));
+        assert_eq!(message, hex(REQUEST));
+        assert_eq!(tsig.mac, hex(REQUEST_MAC));
+        assert_eq!(tsig.time_signed, SIGNED_AT);
+        assert_eq!(TsigRecord { mac: Vec::new(), ..tsig }, self::tsig(&key("hmac-sha256"), SIGNED_AT));
+    }
+
+    #[test]
+    fn chained_macs_match_the_known_answers() {
+        let key = key("hmac-sha256");
+        let first = signed_data(Some(&hex(REQUEST_MAC)), &hex(ANSWER), &tsig(&key, SIGNED_AT), false).unwrap();
+        assert_eq!(key.algorithm.mac(&key.secret, &first), hex(FIRST_MAC));
+
+        let second = signed_data(Some(&hex(FIRST_MAC)), &hex(ANSWER), &tsig(&key, SIGNED_AT + 1), true).unwrap();
+        assert_eq!(key.algorithm.mac(&key.secret, &second), hex(SECOND_MAC));
+    }
+
+    #[test]
+    fn old_signatures_are_badtime_and_broken_ones_badsig() {
+        //The known request checks out, but was signed long ago
+        let result = verify(&keyring(), &mut buffer(&hex(SIGNED_REQUEST)));
+        assert!(matches!(result, Err(TsigError::BadTime(..))));
+
+        //Anything changed in the message breaks the signature
+        let mut tampered = hex(SIGNED_REQUEST);
+        tampered[14] ^= 0x20;
+        let result = verify(&keyring(), &mut buffer(&tampered));
+        assert!(matches!(result, Err(TsigError::BadSig(_))));
+
+        let result = verify(&Keyring::default(), &mut buffer(&hex(SIGNED_REQUEST)));
+        assert!(matches!(result, Err(TsigError::BadKey(_))));
+
+        assert!(verify(&keyring(), &mut buffer(&hex(REQUEST))).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_macs_go_no_shorter_than_half() {
+        let key = key("hmac-sha256");
+        let data = b"message";
+        let mac = key.algorithm.mac(&key.secret, data);
+
+        assert!(key.algorithm.verify(&key.secret, data, &mac));
+        assert!(key.algorithm.verify(&key.secret, data, &mac[..16]));
+        assert!(!key.algorithm.verify(&key.secret, data, &mac[..15]));
+
+        let mut longer = mac.clone();
+        longer.push(0);
+        assert!(!key.algorithm.verify(&key.secret, data, &longer));
+
+        let mut wrong = mac[..16].to_vec();
+        wrong[15] ^= 1;
+        assert!(!key.algorithm.verify(&key.secret, data, &wrong));
+    }
+
+    #[test]
+    fn answers_in_several_messages_are_chained() {
+        let mut request = buffer(&hex(REQUEST));
+        request.seek(request.buf.len()).unwrap();
+        let mut client = Signer::new(key("hmac-sha256"));
+        client.sign(&mut request).unwrap();
+        let mut verifier = client.verifier();
+
+        let signature = verify(&keyring(), &mut request).unwrap().unwrap();
+        let mut server = Signer::answer(signature);
+        let mut messages: Vec<BytePacketBuffer> = (0..3).map(|_| answer(&hex(REQUEST))).collect();
+        for message in messages.iter_mut() {
+            server.sign(message).unwrap();
+        }
+
+        //Taken out of order, the chain breaks
+        assert!(client.verifier().check(&mut messages[1]).is_err());
+
+        for message in messages.iter_mut() {
+            verifier.check(message).unwrap();
+        }
+        assert!(verifier.is_complete());
+
+        //The first and last messages need a signature, the ones in between don't
+        let mut verifier = client.verifier();
+        assert!(verifier.check(&mut answer(&hex(REQUEST))).is_err());
+        let mut verifier = client.verifier();
+        let mut messages: Vec<BytePacketBuffer> = (0..2).map(|_| answer(&hex(REQUEST))).collect();
+        let mut server = Signer::answer(verify(&keyring(), &mut request).unwrap().unwrap());
+        server.sign(&mut messages[0]).unwrap();
+        verifier.check(&mut messages[0]).unwrap();
+        verifier.check(&mut messages[1]).unwrap();
+        assert!(!verifier.is_complete());
+    }
+
+    #[test]
+    fn badtime_answers_are_signed_with_our_clock() {
+        let mut request = buffer(&hex(REQUEST));
+        request.seek(request.buf.len()).unwrap();
+        let mut client = Signer::new(key("hmac-sha256"));
+        client.sign(&mut request).unwrap();
+        let (request_tsig, _) = split(&mut request);
+
+        let error = TsigError::BadTime(Box::new(request_tsig.clone()), key("hmac-sha256"));
+        assert_eq!(error.rescode(), ResultCode::NotAuth);
+        let mut response = answer(&hex(REQUEST));
+        write_error(&mut response, &error).unwrap();
+
+        let (tsig, message) = split(&mut response);
+        assert_eq!(tsig.error, BADTIME);
+        assert_eq!(tsig.other.len(), 6);
+        let mut time = [0; 8];
+        time[2..].copy_from_slice(&tsig.other);
+        assert!(now().abs_diff(u64::from_be_bytes(time)) <= 1);
+
+        let key = key("hmac-sha256");
+        let data = signed_data(Some(&request_tsig.mac), &message, &tsig, false).unwrap();
+        assert!(key.algorithm.verify(&key.secret, &data, &tsig.mac));
+
+        let error = client.verifier().check(&mut response).unwrap_err();
+        assert!(error.to_string().contains("BADTIME"), "{}", error);
+    }
+
+    #[test]
+    fn badsig_answers_are_unsigned() {
+        let (request_tsig, _) = split(&mut buffer(&hex(SIGNED_REQUEST)));
+        let mut response = answer(&hex(REQUEST));
+        write_error(&mut response, &TsigError::BadSig(Box::new(request_tsig))).unwrap();
+
+        let (tsig, _) = split(&mut response);
+        assert_eq!(tsig.error, BADSIG);
+        assert!(tsig.mac.is_empty());
+
+        let error = Signer::new(key("hmac-sha256")).verifier().check(&mut response).unwrap_err();
+        assert!(error.to_string().contains("BADSIG"), "{}", error);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+use crate::protocol::dnspacket::DnsPacket;
+
+/// A fresh id for a query we send. Every `RandomState` hashes with keys of its
+/// own that start out random, so what it hashes to can't be guessed by
+/// someone trying to spoof the answer.
+pub fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Sends `request`, already written to `req_buffer`, to `server` from a random
+/// port and waits up to `timeout` for its answer. Datagrams from anyone else,
+/// and answers whose id or question don't match the request, get dropped. That's
+/// what a spoofed answer looks like, and taking it would poison the cache.
+/// The buffer is returned along with the answer for checking its signature.
+pub fn exchange(
+    request: &DnsPacket,
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<(DnsPacket, BytePacketBuffer), Box<dyn Error>> {
+    let local: SocketAddr = if server.is_ipv6() { ([0u16; 8], 0).into() } else { ([0u8; 4], 0).into() };
+    let socket = UdpSocket::bind(local)?;
+    socket.send_to(req_buffer.get_range(0, req_buffer.pos())?, server)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(format!("No answer from {}", server).into());
+        }
+        socket.set_read_timeout(Some(left))?;
+
+        let mut res_buffer = BytePacketBuffer::new();
+        let src = match socket.recv_from(&mut res_buffer.buf) {
+            Ok((_, src)) => src,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(format!("No answer from {}", server).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if src != server {
+            println!("Dropped a datagram from {}, we asked {}", src, server);
+            continue;
+        }
+
+        let response = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Dropped a malformed answer from {}: {}", server, e);
+                continue;
+            }
+        };
+        if !response.header.response
+            || response.header.id != request.header.id
+            || response.questions != request.questions
+        {
+            println!("Dropped an answer from {} that doesn't match our query", server);
+            continue;
+        }
+
+        return Ok((response, res_buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::dnsquestion::DnsQuestion;
+    use crate::protocol::domainname::DomainName;
+    use crate::protocol::querytype::QueryType;
+    use std::thread;
+
+    fn reply(request: &DnsPacket, id: u16, name: &str) -> BytePacketBuffer {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.header.response = true;
+        packet.questions.push(DnsQuestion::new(DomainName::new(name), request.questions[0].qtype));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn exchange_drops_answers_that_dont_match() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let mut request = DnsPacket::new();
+        request.header.id = random_id();
+        request.questions.push(DnsQuestion::new(DomainName::new("example.org"), QueryType::A));
+        let mut req_buffer = BytePacketBuffer::new();
+        request.write(&mut req_buffer).unwrap();
+
+        let expected = request.clone();
+        let handle = thread::spawn(move || {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, client) = server.recv_from(&mut buffer.buf).unwrap();
+            let id = expected.header.id;
+            let send = |socket: &UdpSocket, buffer: BytePacketBuffer| {
+                socket.send_to(buffer.get_range(0, buffer.pos()).unwrap(), client).unwrap();
+            };
+
+            //Someone else guessing the port, then the wrong id and the wrong question
+            let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            send(&spoofer, reply(&expected, id, "example.org"));
+            send(&server, reply(&expected, id.wrapping_add(1), "example.org"));
+            send(&server, reply(&expected, id, "evil.example"));
+            //The real answer, with the name in another case
+            send(&server, reply(&expected, id, "EXAMPLE.org"));
+        });
+
+        let (response, _) = exchange(&request, &req_buffer, server_addr, Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(response.header.id, request.header.id);
+        assert_eq!(response.questions, request.questions);
+    }
+
+    #[test]
+    fn exchange_times_out_without_a_matching_answer() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut request = DnsPacket::new();
+        request.header.id = 1;
+        request.questions.push(DnsQuestion::new(DomainName::new("example.org"), QueryType::A));
+        let mut req_buffer = BytePacketBuffer::new();
+        request.write(&mut req_buffer).unwrap();
+
+        let result = exchange(&request, &req_buffer, server.local_addr().unwrap(), Duration::from_millis(200));
+        assert!(result.is_err());
+    }
+}